@@ -0,0 +1,36 @@
+//! RGB to 4:2:0 at 720p, the throughput is frames per second.
+
+use criterion::{criterion_group, criterion_main, Criterion, Throughput};
+use image::{Rgb, RgbImage};
+
+use camera_service::colorspace::{ColorMatrix, ColorRange, Conversion, I420};
+
+const WIDTH: u32 = 1280;
+const HEIGHT: u32 = 720;
+
+/// Gradients, so every block has different chroma like a camera frame would.
+fn frame() -> RgbImage {
+    RgbImage::from_fn(WIDTH, HEIGHT, |x, y| {
+        Rgb([(x % 256) as u8, (y % 256) as u8, ((x + y) % 256) as u8])
+    })
+}
+
+fn convert_rgb(c: &mut Criterion) {
+    let frame = frame();
+    let mut group = c.benchmark_group("convert_rgb_720p");
+    group.throughput(Throughput::Elements(1));
+    for (name, matrix, range) in [
+        ("bt601_limited", ColorMatrix::Bt601, ColorRange::Limited),
+        ("bt709_full", ColorMatrix::Bt709, ColorRange::Full),
+    ] {
+        let conversion = Conversion::new(matrix, range);
+        let mut yuv = I420::default();
+        group.bench_function(format!("i420_{name}"), |b| {
+            b.iter(|| yuv.convert_rgb(&frame, &conversion))
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, convert_rgb);
+criterion_main!(benches);
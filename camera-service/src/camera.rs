@@ -1,10 +1,8 @@
-use crate::{connection::ConnState, encoding::Encoder, prelude::*};
-
-use nokhwa::{
-    pixel_format::RgbFormat,
-    utils::{RequestedFormat, RequestedFormatType},
-    Camera,
+use crate::{
+    connection::ConnState, encoding::Encoder, metrics::Metrics, pipeline::LatestSender, prelude::*,
+    source::SourceKind,
 };
+
 use serde::{Deserialize, Serialize};
 
 #[allow(non_snake_case)]
@@ -12,69 +10,63 @@ use serde::{Deserialize, Serialize};
 pub struct VideoPacket {
     pub data: Vec<u8>,
     pub frameType: Option<String>,
+    /// When the camera captured the frame, since the epoch.
     pub epochTime: Duration,
     pub encoding: Encoder,
 }
 
+/// What the camera thread shares with the rest of the pipeline.
+pub struct CameraHandles {
+    /// Whether anyone is watching, the camera only runs while someone is.
+    pub client_counter: ConnectionState,
+    pub cam_tx: LatestSender<CameraPacket>,
+    pub metrics: Arc<Metrics>,
+}
+
 pub fn camera_thread(
-    client_counter: ConnectionState,
-    video_device_index: u32,
-    cam_tx: Sender<CameraPacket>,
+    handles: CameraHandles,
+    source: SourceKind,
+    width: u32,
+    height: u32,
+    framerate: u32,
 ) -> JoinHandle<()> {
-    thread::spawn(move || {
+    let CameraHandles {
+        client_counter,
+        cam_tx,
+        metrics,
+    } = handles;
+    thread::spawn(move || loop {
+        {
+            info!("waiting for connection...");
+            thread::sleep(Duration::from_millis(1200));
+            let counter = client_counter.lock().unwrap();
+            if *counter != ConnState::Connected {
+                continue;
+            }
+        }
+        let mut video_source = match source.open(width, height, framerate) {
+            Ok(video_source) => video_source,
+            Err(e) => {
+                error!("failed to open video source {:?}: {e}", source);
+                continue;
+            }
+        };
         loop {
             {
-                info!("waiting for connection...");
-                thread::sleep(Duration::from_millis(1200));
                 let counter = client_counter.lock().unwrap();
                 if *counter != ConnState::Connected {
-                    continue;
-                }
-            }
-            let requested =
-                RequestedFormat::new::<RgbFormat>(RequestedFormatType::AbsoluteHighestFrameRate);
-            let mut camera = Camera::new(
-                nokhwa::utils::CameraIndex::Index(video_device_index), // index
-                requested,
-            )
-            .unwrap();
-            camera.open_stream().unwrap();
-            loop {
-                {
-                    let counter = client_counter.lock().unwrap();
-                    if *counter != ConnState::Connected {
-                        break;
-                    }
+                    break;
                 }
-                let frame = camera.frame().unwrap();
-                let decoded = frame.decode_image::<RgbFormat>().unwrap();
-                cam_tx.send((decoded, since_the_epoch().as_millis()));
             }
-        }
-    })
-}
-
-pub fn fps_thread(fps_rx: mpsc::Receiver<u128>) -> JoinHandle<()> {
-    thread::spawn(move || {
-        let mut num_frames = 0;
-        let mut now_plus_1 = since_the_epoch().as_millis() + 1000;
-        warn!("Starting fps loop");
-        loop {
-            match fps_rx.recv() {
-                Ok(dur) => {
-                    if now_plus_1 < dur {
-                        warn!("FPS: {:?}", num_frames);
-                        num_frames = 0;
-                        now_plus_1 = since_the_epoch().as_millis() + 1000;
-                    } else {
-                        num_frames += 1;
-                    }
-                }
+            let frame = match video_source.frame() {
+                Ok(frame) => frame,
                 Err(e) => {
-                    error!("Receive error: {:?}", e);
-                    panic!("I'm done yo");
+                    error!("video source {:?} failed: {e}", source);
+                    break;
                 }
-            }
+            };
+            metrics.frames_captured.inc();
+            let _ = cam_tx.send((frame, since_the_epoch().as_millis()));
         }
     })
 }
@@ -0,0 +1,466 @@
+use image::{imageops, ImageBuffer, Luma, RgbImage};
+use rav1e::color::{
+    ColorDescription, ColorPrimaries, MatrixCoefficients, PixelRange, TransferCharacteristics,
+};
+use serde::{Deserialize, Serialize};
+
+use anyhow::Result;
+
+/// Fractional bits of the fixed point coefficients.
+const SHIFT: u32 = 16;
+const HALF: i32 = 1 << (SHIFT - 1);
+
+/// Which RGB to YCbCr matrix frames are converted with and signalled as.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum ColorMatrix {
+    /// SD cameras and what decoders assume without a color description.
+    #[default]
+    Bt601,
+    Bt709,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum ColorRange {
+    /// Y in 16..=235, Cb and Cr in 16..=240.
+    #[default]
+    Limited,
+    /// Every sample uses 0..=255.
+    Full,
+}
+
+impl ColorMatrix {
+    /// Luma weights of red and blue.
+    fn kr_kb(self) -> (f32, f32) {
+        match self {
+            ColorMatrix::Bt601 => (0.299, 0.114),
+            ColorMatrix::Bt709 => (0.2126, 0.0722),
+        }
+    }
+
+    /// How the AV1 sequence header describes frames converted with this matrix.
+    pub fn color_description(self) -> ColorDescription {
+        match self {
+            ColorMatrix::Bt601 => ColorDescription {
+                color_primaries: ColorPrimaries::BT601,
+                transfer_characteristics: TransferCharacteristics::BT601,
+                matrix_coefficients: MatrixCoefficients::BT601,
+            },
+            ColorMatrix::Bt709 => ColorDescription {
+                color_primaries: ColorPrimaries::BT709,
+                transfer_characteristics: TransferCharacteristics::BT709,
+                matrix_coefficients: MatrixCoefficients::BT709,
+            },
+        }
+    }
+}
+
+impl ColorRange {
+    pub fn pixel_range(self) -> PixelRange {
+        match self {
+            ColorRange::Limited => PixelRange::Limited,
+            ColorRange::Full => PixelRange::Full,
+        }
+    }
+}
+
+/// RGB to YCbCr in fixed point, worked out once per matrix and range rather than per pixel.
+#[derive(Debug, Clone, Copy)]
+pub struct Conversion {
+    y: [i32; 3],
+    cb: [i32; 3],
+    cr: [i32; 3],
+    y_offset: i32,
+}
+
+impl Conversion {
+    pub fn new(matrix: ColorMatrix, range: ColorRange) -> Self {
+        let (kr, kb) = matrix.kr_kb();
+        let kg = 1.0 - kr - kb;
+        let (y_scale, c_scale, y_offset) = match range {
+            ColorRange::Limited => (219.0 / 255.0, 224.0 / 255.0, 16),
+            ColorRange::Full => (1.0, 1.0, 0),
+        };
+        let fixed = |weights: [f32; 3], scale: f32| {
+            weights.map(|w| (w * scale * (1 << SHIFT) as f32).round() as i32)
+        };
+        // Cb = (B - Y) / (2 (1 - Kb)), Cr = (R - Y) / (2 (1 - Kr))
+        let cb_div = 2.0 * (1.0 - kb);
+        let cr_div = 2.0 * (1.0 - kr);
+        Self {
+            y: fixed([kr, kg, kb], y_scale),
+            cb: fixed([-kr / cb_div, -kg / cb_div, (1.0 - kb) / cb_div], c_scale),
+            cr: fixed([(1.0 - kr) / cr_div, -kg / cr_div, -kb / cr_div], c_scale),
+            y_offset,
+        }
+    }
+
+    fn luma(&self, [r, g, b]: [u8; 3]) -> u8 {
+        let [cr, cg, cb] = self.y;
+        let y = (cr * i32::from(r) + cg * i32::from(g) + cb * i32::from(b) + HALF) >> SHIFT;
+        (y + self.y_offset).clamp(0, 255) as u8
+    }
+
+    /// Cb and Cr of a 2x2 block from the sums of its red, green and blue, which averages
+    /// the block in the same step.
+    fn chroma(&self, [r, g, b]: [i32; 3]) -> (u8, u8) {
+        let apply = |[cr, cg, cb]: [i32; 3]| {
+            let c = (cr * r + cg * g + cb * b + (HALF << 2)) >> (SHIFT + 2);
+            (c + 128).clamp(0, 255) as u8
+        };
+        (apply(self.cb), apply(self.cr))
+    }
+}
+
+/// How raw YCbCr from a camera or file is laid out.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum YuvLayout {
+    /// Packed 4:2:2, Y0 Cb Y1 Cr.
+    Yuyv,
+    /// A luma plane and a plane of interleaved Cb and Cr.
+    Nv12,
+    /// The luma, Cb and Cr planes back to back.
+    I420,
+}
+
+/// Bytes of the luma plane and of each chroma plane of a 4:2:0 frame.
+fn plane_sizes(width: usize, height: usize) -> (usize, usize) {
+    (width * height, width.div_ceil(2) * height.div_ceil(2))
+}
+
+/// A planar 4:2:0 frame, one Cb and Cr sample per 2x2 block of pixels. The planes are
+/// kept between frames so converting doesn't allocate once the size settled.
+#[derive(Debug, Default)]
+pub struct I420 {
+    pub width: usize,
+    pub height: usize,
+    pub y: Vec<u8>,
+    pub u: Vec<u8>,
+    pub v: Vec<u8>,
+}
+
+impl I420 {
+    /// Converts raw YCbCr as a camera or file lays it out into this frame's planes.
+    pub fn convert_yuv(
+        &mut self,
+        layout: YuvLayout,
+        data: &[u8],
+        width: usize,
+        height: usize,
+    ) -> Result<()> {
+        match layout {
+            YuvLayout::Yuyv => self.convert_yuyv(data, width, height),
+            YuvLayout::Nv12 => self.convert_nv12(data, width, height),
+            YuvLayout::I420 => self.copy_i420(data, width, height),
+        }
+    }
+
+    /// Repacks a camera's YUYV (4:2:2, Y0 Cb Y1 Cr), averaging the chroma of row pairs.
+    fn convert_yuyv(&mut self, data: &[u8], width: usize, height: usize) -> Result<()> {
+        let row_bytes = width.div_ceil(2) * 4;
+        anyhow::ensure!(
+            data.len() >= row_bytes * height,
+            "YUYV frame of {} bytes is too short for {width}x{height}",
+            data.len()
+        );
+        self.resize(width, height);
+        let rows = data.chunks_exact(row_bytes).take(height);
+        for (luma, line) in self.y.chunks_exact_mut(width).zip(rows) {
+            for (dst, src) in luma.iter_mut().zip(line.iter().step_by(2)) {
+                *dst = *src;
+            }
+        }
+        let chroma_width = self.chroma_width();
+        let chroma_rows = self
+            .u
+            .chunks_exact_mut(chroma_width)
+            .zip(self.v.chunks_exact_mut(chroma_width));
+        for (cy, (u_row, v_row)) in chroma_rows.enumerate() {
+            let top = &data[cy * 2 * row_bytes..][..row_bytes];
+            let bottom = &data[(cy * 2 + 1).min(height - 1) * row_bytes..][..row_bytes];
+            for (cx, (u, v)) in u_row.iter_mut().zip(v_row.iter_mut()).enumerate() {
+                let average =
+                    |i: usize| (u16::from(top[i]) + u16::from(bottom[i])).div_ceil(2) as u8;
+                *u = average(cx * 4 + 1);
+                *v = average(cx * 4 + 3);
+            }
+        }
+        Ok(())
+    }
+
+    /// Splits the interleaved chroma plane of a camera's NV12.
+    fn convert_nv12(&mut self, data: &[u8], width: usize, height: usize) -> Result<()> {
+        let (luma_len, chroma_len) = plane_sizes(width, height);
+        anyhow::ensure!(
+            data.len() >= luma_len + chroma_len * 2,
+            "NV12 frame of {} bytes is too short for {width}x{height}",
+            data.len()
+        );
+        self.resize(width, height);
+        self.y.copy_from_slice(&data[..luma_len]);
+        let chroma = data[luma_len..].chunks_exact(2);
+        for ((u, v), pair) in self.u.iter_mut().zip(self.v.iter_mut()).zip(chroma) {
+            *u = pair[0];
+            *v = pair[1];
+        }
+        Ok(())
+    }
+
+    /// Copies the three planes stored back to back.
+    fn copy_i420(&mut self, data: &[u8], width: usize, height: usize) -> Result<()> {
+        let (luma_len, chroma_len) = plane_sizes(width, height);
+        anyhow::ensure!(
+            data.len() >= luma_len + chroma_len * 2,
+            "I420 frame of {} bytes is too short for {width}x{height}",
+            data.len()
+        );
+        self.resize(width, height);
+        let (luma, chroma) = data.split_at(luma_len);
+        self.y.copy_from_slice(luma);
+        self.u.copy_from_slice(&chroma[..chroma_len]);
+        self.v.copy_from_slice(&chroma[chroma_len..][..chroma_len]);
+        Ok(())
+    }
+
+    pub fn dimensions(&self) -> (usize, usize) {
+        (self.width, self.height)
+    }
+
+    /// Width of the chroma planes, odd frame sizes round up.
+    pub fn chroma_width(&self) -> usize {
+        self.width.div_ceil(2)
+    }
+
+    fn resize(&mut self, width: usize, height: usize) {
+        self.width = width;
+        self.height = height;
+        let (luma, chroma) = plane_sizes(width, height);
+        self.y.resize(luma, 0);
+        self.u.resize(chroma, 0);
+        self.v.resize(chroma, 0);
+    }
+
+    pub fn convert_rgb(&mut self, frame: &RgbImage, conversion: &Conversion) {
+        self.resize(frame.width() as usize, frame.height() as usize);
+        let (u, v) = (&mut self.u, &mut self.v);
+        convert_rgb(frame, conversion, &mut self.y, |i, (cb, cr)| {
+            u[i] = cb;
+            v[i] = cr;
+        });
+    }
+
+    /// Scales every plane of `src` into this frame.
+    pub fn scale(&mut self, src: &I420, width: usize, height: usize) {
+        self.resize(width, height);
+        let (src_chroma, dst_chroma) = (
+            (src.chroma_width(), src.height.div_ceil(2)),
+            (self.chroma_width(), height.div_ceil(2)),
+        );
+        let planes = [
+            (&mut self.y, &src.y, src.dimensions(), (width, height)),
+            (&mut self.u, &src.u, src_chroma, dst_chroma),
+            (&mut self.v, &src.v, src_chroma, dst_chroma),
+        ];
+        for (dst, plane, (src_width, src_height), (dst_width, dst_height)) in planes {
+            let view = ImageBuffer::<Luma<u8>, &[u8]>::from_raw(
+                src_width as u32,
+                src_height as u32,
+                plane,
+            )
+            .expect("plane matches its frame size");
+            let scaled = imageops::resize(
+                &view,
+                dst_width as u32,
+                dst_height as u32,
+                imageops::FilterType::Triangle,
+            );
+            dst.copy_from_slice(scaled.as_raw());
+        }
+    }
+}
+
+/// Fills `luma` from the frame and hands the Cb and Cr of every 2x2 block to `chroma`
+/// along with the block's index, row by row.
+fn convert_rgb(
+    frame: &RgbImage,
+    conversion: &Conversion,
+    luma: &mut [u8],
+    mut chroma: impl FnMut(usize, (u8, u8)),
+) {
+    let (width, height) = (frame.width() as usize, frame.height() as usize);
+    let rgb = frame.as_raw();
+    let row_bytes = width * 3;
+
+    for (luma, line) in luma
+        .chunks_exact_mut(width)
+        .zip(rgb.chunks_exact(row_bytes))
+    {
+        for (dst, src) in luma.iter_mut().zip(line.chunks_exact(3)) {
+            *dst = conversion.luma([src[0], src[1], src[2]]);
+        }
+    }
+
+    let chroma_width = width.div_ceil(2);
+    for cy in 0..height.div_ceil(2) {
+        // an odd last row or column pairs up with itself
+        let top = &rgb[cy * 2 * row_bytes..][..row_bytes];
+        let bottom = &rgb[(cy * 2 + 1).min(height - 1) * row_bytes..][..row_bytes];
+        for cx in 0..chroma_width {
+            let (left, right) = (cx * 6, (cx * 2 + 1).min(width - 1) * 3);
+            let sum = std::array::from_fn(|i| {
+                [
+                    top[left + i],
+                    top[right + i],
+                    bottom[left + i],
+                    bottom[right + i],
+                ]
+                .into_iter()
+                .map(i32::from)
+                .sum()
+            });
+            chroma(cy * chroma_width + cx, conversion.chroma(sum));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use image::Rgb;
+
+    use super::*;
+
+    const RED: [u8; 3] = [255, 0, 0];
+    const GREEN: [u8; 3] = [0, 255, 0];
+    const BLUE: [u8; 3] = [0, 0, 255];
+    const WHITE: [u8; 3] = [255, 255, 255];
+    const BLACK: [u8; 3] = [0, 0, 0];
+
+    /// Y, Cb and Cr of a frame filled with one color.
+    fn ycbcr(matrix: ColorMatrix, range: ColorRange, rgb: [u8; 3]) -> [u8; 3] {
+        let frame = RgbImage::from_pixel(2, 2, Rgb(rgb));
+        let mut yuv = I420::default();
+        yuv.convert_rgb(&frame, &Conversion::new(matrix, range));
+        assert!(yuv.y.iter().all(|y| *y == yuv.y[0]));
+        [yuv.y[0], yuv.u[0], yuv.v[0]]
+    }
+
+    #[test]
+    fn bt601_limited() {
+        let ycbcr = |rgb| ycbcr(ColorMatrix::Bt601, ColorRange::Limited, rgb);
+        assert_eq!(ycbcr(WHITE), [235, 128, 128]);
+        assert_eq!(ycbcr(BLACK), [16, 128, 128]);
+        assert_eq!(ycbcr(RED), [81, 90, 240]);
+        assert_eq!(ycbcr(GREEN), [145, 54, 34]);
+        assert_eq!(ycbcr(BLUE), [41, 240, 110]);
+    }
+
+    #[test]
+    fn bt601_full() {
+        let ycbcr = |rgb| ycbcr(ColorMatrix::Bt601, ColorRange::Full, rgb);
+        assert_eq!(ycbcr(WHITE), [255, 128, 128]);
+        assert_eq!(ycbcr(BLACK), [0, 128, 128]);
+        assert_eq!(ycbcr(RED), [76, 85, 255]);
+        assert_eq!(ycbcr(GREEN), [150, 44, 21]);
+        assert_eq!(ycbcr(BLUE), [29, 255, 107]);
+    }
+
+    #[test]
+    fn bt709_limited() {
+        let ycbcr = |rgb| ycbcr(ColorMatrix::Bt709, ColorRange::Limited, rgb);
+        assert_eq!(ycbcr(WHITE), [235, 128, 128]);
+        assert_eq!(ycbcr(BLACK), [16, 128, 128]);
+        assert_eq!(ycbcr(RED), [63, 102, 240]);
+        assert_eq!(ycbcr(GREEN), [173, 42, 26]);
+        assert_eq!(ycbcr(BLUE), [32, 240, 118]);
+    }
+
+    #[test]
+    fn bt709_full() {
+        let ycbcr = |rgb| ycbcr(ColorMatrix::Bt709, ColorRange::Full, rgb);
+        assert_eq!(ycbcr(WHITE), [255, 128, 128]);
+        assert_eq!(ycbcr(BLACK), [0, 128, 128]);
+        assert_eq!(ycbcr(RED), [54, 99, 255]);
+        assert_eq!(ycbcr(GREEN), [182, 30, 12]);
+        assert_eq!(ycbcr(BLUE), [18, 255, 116]);
+    }
+
+    #[test]
+    fn chroma_averages_blocks() {
+        let frame = RgbImage::from_fn(2, 2, |x, _| Rgb(if x == 0 { RED } else { BLUE }));
+        let mut yuv = I420::default();
+        yuv.convert_rgb(
+            &frame,
+            &Conversion::new(ColorMatrix::Bt601, ColorRange::Full),
+        );
+        let [_, red_cb, red_cr] = ycbcr(ColorMatrix::Bt601, ColorRange::Full, RED);
+        let [_, blue_cb, blue_cr] = ycbcr(ColorMatrix::Bt601, ColorRange::Full, BLUE);
+        let average = |a: u8, b: u8| (u16::from(a) + u16::from(b)).div_ceil(2) as u8;
+        assert!(yuv.u[0].abs_diff(average(red_cb, blue_cb)) <= 1);
+        assert!(yuv.v[0].abs_diff(average(red_cr, blue_cr)) <= 1);
+    }
+
+    #[test]
+    fn odd_edges() {
+        // the last row and column are blue, the rest red
+        let frame = RgbImage::from_fn(3, 3, |x, y| Rgb(if x == 2 || y == 2 { BLUE } else { RED }));
+        let conversion = Conversion::new(ColorMatrix::Bt601, ColorRange::Limited);
+        let mut yuv = I420::default();
+        yuv.convert_rgb(&frame, &conversion);
+        assert_eq!(yuv.dimensions(), (3, 3));
+        assert_eq!((yuv.chroma_width(), yuv.u.len(), yuv.v.len()), (2, 4, 4));
+
+        let [_, red_cb, red_cr] = ycbcr(ColorMatrix::Bt601, ColorRange::Limited, RED);
+        let [_, blue_cb, blue_cr] = ycbcr(ColorMatrix::Bt601, ColorRange::Limited, BLUE);
+        // the edge blocks only cover the edge pixels, which pair up with themselves
+        assert_eq!((yuv.u[0], yuv.v[0]), (red_cb, red_cr));
+        assert_eq!((yuv.u[1], yuv.v[1]), (blue_cb, blue_cr));
+        assert_eq!((yuv.u[2], yuv.v[2]), (blue_cb, blue_cr));
+        assert_eq!((yuv.u[3], yuv.v[3]), (blue_cb, blue_cr));
+        assert_eq!(yuv.y[8], 41);
+    }
+
+    #[test]
+    fn yuv_layouts_agree() {
+        // 3x3, two chroma samples per row, two chroma rows
+        let y: Vec<u8> = (10..19).collect();
+        let (u, v) = ([100, 101, 102, 103], [200, 201, 202, 203]);
+        let i420: Vec<u8> = [&y[..], &u, &v].concat();
+        let nv12: Vec<u8> = [
+            y.clone(),
+            u.iter().zip(&v).flat_map(|(u, v)| [*u, *v]).collect(),
+        ]
+        .concat();
+        // YUYV has chroma on every row, rows of a pair carry the same so the average is exact
+        let yuyv: Vec<u8> = (0..3)
+            .flat_map(|row| {
+                let (y, c) = (&y[row * 3..][..3], (row / 2) * 2);
+                [y[0], u[c], y[1], v[c], y[2], u[c + 1], 0, v[c + 1]]
+            })
+            .collect();
+
+        let mut frame = I420::default();
+        for (layout, data) in [
+            (YuvLayout::I420, &i420),
+            (YuvLayout::Nv12, &nv12),
+            (YuvLayout::Yuyv, &yuyv),
+        ] {
+            frame.convert_yuv(layout, data, 3, 3).unwrap();
+            assert_eq!(frame.y, y, "{layout:?}");
+            assert_eq!((&frame.u[..], &frame.v[..]), (&u[..], &v[..]), "{layout:?}");
+            assert!(frame
+                .convert_yuv(layout, &data[..data.len() - 1], 3, 3)
+                .is_err());
+        }
+    }
+
+    #[test]
+    fn buffers_follow_the_frame_size() {
+        let conversion = Conversion::new(ColorMatrix::Bt709, ColorRange::Limited);
+        let mut yuv = I420::default();
+        yuv.convert_rgb(&RgbImage::new(5, 1), &conversion);
+        assert_eq!((yuv.y.len(), yuv.u.len()), (5, 3));
+        yuv.convert_rgb(&RgbImage::new(2, 2), &conversion);
+        assert_eq!((yuv.y.len(), yuv.u.len(), yuv.v.len()), (4, 1, 1));
+    }
+}
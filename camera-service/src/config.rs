@@ -0,0 +1,517 @@
+use std::{
+    fs,
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+
+use anyhow::Context as _;
+use clap::Parser;
+use protocol::SERVER_PORT;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    colorspace::{ColorMatrix, ColorRange},
+    encoding::Encoder,
+    prelude::*,
+    source::SourceKind,
+};
+
+const DEFAULT_CONFIG_FILE: &str = "camera-service.toml";
+
+/// Everything camera-service can be configured with, see `camera-service.example.toml`.
+/// Values come from the defaults, then the TOML file, then the command line and environment.
+#[derive(Debug, Clone, Serialize, Deserialize, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServiceConfig {
+    pub identity: IdentityConfig,
+    pub signaling: SignalingConfig,
+    pub capture: CaptureConfig,
+    pub encoding: EncodingConfig,
+    pub rate: RateConfig,
+    pub ice: IceConfig,
+    pub control: ControlConfig,
+    pub metrics: MetricsConfig,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct IdentityConfig {
+    /// Unique id the tank logs in with.
+    pub id: String,
+    /// Name shown to operators, the id if empty.
+    pub name: Option<String>,
+    /// Pre-shared token the signaling server knows this tank by.
+    pub token: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SignalingConfig {
+    pub url: String,
+    /// PEM certificates a `wss://` server is verified against instead of the webpki roots.
+    pub ca_bundle: Option<PathBuf>,
+}
+
+impl Default for SignalingConfig {
+    fn default() -> Self {
+        Self {
+            url: format!("ws://127.0.0.1:{SERVER_PORT}"),
+            ca_bundle: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CaptureConfig {
+    /// `camera[:index]`, `test-pattern`, `dir:<path>` or `y4m:<path>`.
+    pub source: String,
+    pub width: u32,
+    pub height: u32,
+    pub framerate: u32,
+    /// Latency budget from capture to sending, older frames are dropped before encoding
+    /// and again before they are sent.
+    pub max_frame_age_ms: u64,
+}
+
+impl Default for CaptureConfig {
+    fn default() -> Self {
+        Self {
+            source: "camera:0".to_owned(),
+            width: 720,
+            height: 480,
+            framerate: 10,
+            max_frame_age_ms: 500,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct EncodingConfig {
+    pub encoder: Encoder,
+    /// rav1e speed preset, 0 (slowest) to 10 (fastest).
+    pub speed_preset: u8,
+    pub min_quantizer: u8,
+    pub quantizer: usize,
+    pub tiles: usize,
+    pub threads: usize,
+    pub min_key_frame_interval: u64,
+    pub max_key_frame_interval: u64,
+    /// Keyframes viewers ask for with PLI/FIR are forced at most this often.
+    pub keyframe_request_interval_ms: u64,
+    /// How frames are converted to YCbCr for the encoder and signalled to the decoder.
+    pub color_matrix: ColorMatrix,
+    pub color_range: ColorRange,
+}
+
+impl Default for EncodingConfig {
+    fn default() -> Self {
+        Self {
+            encoder: Encoder::AV1,
+            speed_preset: 1,
+            min_quantizer: 50,
+            quantizer: 100,
+            tiles: 4,
+            threads: 4,
+            min_key_frame_interval: 20,
+            max_key_frame_interval: 50,
+            keyframe_request_interval_ms: 500,
+            color_matrix: ColorMatrix::default(),
+            color_range: ColorRange::default(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RateConfig {
+    /// Follow the viewers' RTCP feedback, off encodes at the fixed quantizer.
+    pub enabled: bool,
+    pub min_bitrate_kbps: u32,
+    pub start_bitrate_kbps: u32,
+    /// Resolution and frame rate drop once the links can't carry half of this.
+    pub max_bitrate_kbps: u32,
+}
+
+impl Default for RateConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            min_bitrate_kbps: 50,
+            start_bitrate_kbps: 1000,
+            max_bitrate_kbps: 1500,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct IceConfig {
+    pub servers: Vec<IceServerConfig>,
+}
+
+impl Default for IceConfig {
+    fn default() -> Self {
+        Self {
+            servers: vec![IceServerConfig {
+                urls: vec!["stun:stun.l.google.com:19302".to_owned()],
+                username: String::new(),
+                credential: String::new(),
+            }],
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct IceServerConfig {
+    pub urls: Vec<String>,
+    pub username: String,
+    pub credential: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ControlConfig {
+    /// The tank stops when the drive state of the operator in control hasn't been repeated
+    /// for this long.
+    pub deadman_timeout_ms: u64,
+}
+
+impl Default for ControlConfig {
+    fn default() -> Self {
+        Self {
+            deadman_timeout_ms: 1000,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct MetricsConfig {
+    /// Serve Prometheus metrics on `http://<listen>/metrics`.
+    pub enabled: bool,
+    /// Loopback by default, the endpoint has no authentication.
+    pub listen: SocketAddr,
+}
+
+impl Default for MetricsConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            listen: SocketAddr::from(([127, 0, 0, 1], 9090)),
+        }
+    }
+}
+
+/// Command line, every option can also be given through the environment.
+#[derive(Debug, Parser)]
+#[command(
+    version,
+    about = "Streams a tank's camera to its operators over WebRTC"
+)]
+pub struct Cli {
+    /// TOML config file, `camera-service.toml` is used if it exists.
+    #[arg(short, long, env = "CAMERA_SERVICE_CONFIG")]
+    pub config: Option<PathBuf>,
+    /// Print the effective configuration and exit.
+    #[arg(long)]
+    pub print_config: bool,
+    #[arg(long, env = "TANK_ID")]
+    pub tank_id: Option<String>,
+    #[arg(long, env = "TANK_NAME")]
+    pub tank_name: Option<String>,
+    #[arg(long, env = "TANK_TOKEN", hide_env_values = true)]
+    pub tank_token: Option<String>,
+    #[arg(long, env = "SIGNALING_URL")]
+    pub signaling_url: Option<String>,
+    /// PEM certificates to verify a `wss://` signaling server against.
+    #[arg(long, env = "SIGNALING_CA_BUNDLE")]
+    pub ca_bundle: Option<PathBuf>,
+    /// `camera[:index]`, `test-pattern`, `dir:<path>` or `y4m:<path>`.
+    #[arg(long, env = "VIDEO_SOURCE")]
+    pub source: Option<String>,
+    /// Shorthand for `--source camera:<index>`, ignored when a source is given.
+    #[arg(long, env = "VIDEO_DEVICE_INDEX")]
+    pub video_device_index: Option<u32>,
+    #[arg(long)]
+    pub width: Option<u32>,
+    #[arg(long)]
+    pub height: Option<u32>,
+    #[arg(long, env = "FRAMERATE")]
+    pub framerate: Option<u32>,
+    /// `AV1`, `VP8` or `H264`.
+    #[arg(long, env = "ENCODER")]
+    pub encoder: Option<String>,
+    #[arg(long, env = "DEADMAN_TIMEOUT_MS")]
+    pub deadman_timeout_ms: Option<u64>,
+    /// Address the Prometheus `/metrics` endpoint listens on.
+    #[arg(long, env = "METRICS_LISTEN")]
+    pub metrics_listen: Option<SocketAddr>,
+}
+
+impl ServiceConfig {
+    /// Reads the config file the command line points at and applies the overrides on top.
+    pub fn load(cli: &Cli) -> Result<Self> {
+        let mut config = match &cli.config {
+            Some(path) => Self::from_file(path)?,
+            None if Path::new(DEFAULT_CONFIG_FILE).exists() => {
+                Self::from_file(Path::new(DEFAULT_CONFIG_FILE))?
+            }
+            None => Self::default(),
+        };
+        config.apply(cli)?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    fn from_file(path: &Path) -> Result<Self> {
+        let text = fs::read_to_string(path)
+            .with_context(|| format!("can't read config {}", path.display()))?;
+        toml::from_str(&text).with_context(|| format!("invalid config {}", path.display()))
+    }
+
+    fn apply(&mut self, cli: &Cli) -> Result<()> {
+        if let Some(id) = &cli.tank_id {
+            self.identity.id = id.clone();
+        }
+        if let Some(name) = &cli.tank_name {
+            self.identity.name = Some(name.clone());
+        }
+        if let Some(token) = &cli.tank_token {
+            self.identity.token = token.clone();
+        }
+        if let Some(url) = &cli.signaling_url {
+            self.signaling.url = url.clone();
+        }
+        if let Some(ca_bundle) = &cli.ca_bundle {
+            self.signaling.ca_bundle = Some(ca_bundle.clone());
+        }
+        match (&cli.source, cli.video_device_index) {
+            (Some(source), _) => self.capture.source = source.clone(),
+            (None, Some(index)) => self.capture.source = format!("camera:{index}"),
+            (None, None) => {}
+        }
+        if let Some(width) = cli.width {
+            self.capture.width = width;
+        }
+        if let Some(height) = cli.height {
+            self.capture.height = height;
+        }
+        if let Some(framerate) = cli.framerate {
+            self.capture.framerate = framerate;
+        }
+        if let Some(encoder) = &cli.encoder {
+            self.encoding.encoder = Encoder::from_str(encoder)
+                .map_err(|_| anyhow::anyhow!("unknown encoder {encoder}, use AV1, VP8 or H264"))?;
+        }
+        if let Some(timeout) = cli.deadman_timeout_ms {
+            self.control.deadman_timeout_ms = timeout;
+        }
+        if let Some(listen) = cli.metrics_listen {
+            self.metrics.listen = listen;
+        }
+        Ok(())
+    }
+
+    /// Checks everything that would otherwise only blow up once a frame or operator shows up.
+    pub fn validate(&self) -> Result<()> {
+        if self.identity.id.trim().is_empty() {
+            anyhow::bail!("identity.id (TANK_ID) is not set, every tank needs a unique id");
+        }
+        if self.identity.token.is_empty() {
+            anyhow::bail!("identity.token (TANK_TOKEN) is not set, the signaling server needs it");
+        }
+        if !(self.signaling.url.starts_with("ws://") || self.signaling.url.starts_with("wss://")) {
+            anyhow::bail!(
+                "signaling.url must start with ws:// or wss://, got {}",
+                self.signaling.url
+            );
+        }
+        self.source()?;
+        let capture = &self.capture;
+        if capture.width == 0 || capture.height == 0 {
+            anyhow::bail!(
+                "capture resolution {}x{} is empty",
+                capture.width,
+                capture.height
+            );
+        }
+        if !(1..=120).contains(&capture.framerate) {
+            anyhow::bail!(
+                "capture.framerate must be 1 to 120, got {}",
+                capture.framerate
+            );
+        }
+        let encoding = &self.encoding;
+        if encoding.speed_preset > 10 {
+            anyhow::bail!(
+                "encoding.speed_preset must be 0 to 10, got {}",
+                encoding.speed_preset
+            );
+        }
+        if encoding.quantizer > 255 || usize::from(encoding.min_quantizer) > encoding.quantizer {
+            anyhow::bail!(
+                "encoding quantizers must satisfy min_quantizer <= quantizer <= 255, got {} and {}",
+                encoding.min_quantizer,
+                encoding.quantizer
+            );
+        }
+        if encoding.tiles == 0 || encoding.threads == 0 {
+            anyhow::bail!("encoding.tiles and encoding.threads must be at least 1");
+        }
+        if encoding.min_key_frame_interval > encoding.max_key_frame_interval {
+            anyhow::bail!("encoding.min_key_frame_interval is above max_key_frame_interval");
+        }
+        if encoding.keyframe_request_interval_ms == 0 {
+            anyhow::bail!("encoding.keyframe_request_interval_ms must be above 0");
+        }
+        let rate = &self.rate;
+        if rate.min_bitrate_kbps == 0
+            || rate.min_bitrate_kbps > rate.start_bitrate_kbps
+            || rate.start_bitrate_kbps > rate.max_bitrate_kbps
+        {
+            anyhow::bail!(
+                "rate bitrates must satisfy 0 < min <= start <= max, got {}, {} and {} kbps",
+                rate.min_bitrate_kbps,
+                rate.start_bitrate_kbps,
+                rate.max_bitrate_kbps
+            );
+        }
+        for server in &self.ice.servers {
+            for url in &server.urls {
+                if !["stun:", "turn:", "turns:"]
+                    .iter()
+                    .any(|scheme| url.starts_with(scheme))
+                {
+                    anyhow::bail!("ice server url {url} must start with stun:, turn: or turns:");
+                }
+            }
+        }
+        if self.control.deadman_timeout_ms == 0 {
+            anyhow::bail!("control.deadman_timeout_ms must be above 0");
+        }
+        Ok(())
+    }
+
+    pub fn source(&self) -> Result<SourceKind> {
+        SourceKind::from_str(&self.capture.source)
+    }
+
+    pub fn tank_name(&self) -> String {
+        self.identity
+            .name
+            .clone()
+            .unwrap_or_else(|| self.identity.id.clone())
+    }
+
+    pub fn max_frame_age(&self) -> Duration {
+        Duration::from_millis(self.capture.max_frame_age_ms)
+    }
+
+    pub fn deadman_timeout(&self) -> Duration {
+        Duration::from_millis(self.control.deadman_timeout_ms)
+    }
+
+    /// The effective configuration as TOML, with secrets blanked out.
+    pub fn to_toml(&self) -> Result<String> {
+        let mut redacted = self.clone();
+        if !redacted.identity.token.is_empty() {
+            redacted.identity.token = "<redacted>".to_owned();
+        }
+        for server in &mut redacted.ice.servers {
+            if !server.credential.is_empty() {
+                server.credential = "<redacted>".to_owned();
+            }
+        }
+        Ok(toml::to_string_pretty(&redacted)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn valid() -> ServiceConfig {
+        let mut config = ServiceConfig::default();
+        config.identity.id = "tank-1".to_owned();
+        config.identity.token = "secret".to_owned();
+        config
+    }
+
+    #[test]
+    fn defaults_with_identity_are_valid() {
+        valid().validate().unwrap();
+        let mut config = valid();
+        config.signaling.url = "wss://signaling.example.com".to_owned();
+        config.capture.source = "y4m:clip.y4m".to_owned();
+        config.ice.servers[0]
+            .urls
+            .push("turns:turn.example.com".to_owned());
+        config.validate().unwrap();
+    }
+
+    #[test]
+    fn rejections() {
+        type Change = fn(&mut ServiceConfig);
+        let cases: [(Change, &str); 17] = [
+            (|c| c.identity.id = " ".to_owned(), "identity.id"),
+            (|c| c.identity.token.clear(), "identity.token"),
+            (
+                |c| c.signaling.url = "http://127.0.0.1".to_owned(),
+                "signaling.url",
+            ),
+            (
+                |c| c.capture.source = "webcam".to_owned(),
+                "unknown video source",
+            ),
+            (
+                |c| c.capture.source = "camera:first".to_owned(),
+                "invalid digit",
+            ),
+            (|c| c.capture.height = 0, "is empty"),
+            (|c| c.capture.framerate = 0, "capture.framerate"),
+            (|c| c.capture.framerate = 121, "capture.framerate"),
+            (|c| c.encoding.speed_preset = 11, "speed_preset"),
+            (|c| c.encoding.quantizer = 256, "quantizers"),
+            (|c| c.encoding.min_quantizer = 101, "quantizers"),
+            (|c| c.encoding.threads = 0, "encoding.threads"),
+            (
+                |c| c.encoding.min_key_frame_interval = 1000,
+                "min_key_frame_interval",
+            ),
+            (
+                |c| c.encoding.keyframe_request_interval_ms = 0,
+                "keyframe_request",
+            ),
+            (|c| c.rate.min_bitrate_kbps = 0, "0 < min <= start <= max"),
+            (
+                |c| c.rate.start_bitrate_kbps = 2000,
+                "0 < min <= start <= max",
+            ),
+            (|c| c.control.deadman_timeout_ms = 0, "deadman_timeout_ms"),
+        ];
+        for (change, expected) in cases {
+            let mut config = valid();
+            change(&mut config);
+            let err = config.validate().unwrap_err().to_string();
+            assert!(
+                err.contains(expected),
+                "{err:?} doesn't mention {expected:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn ice_server_urls_need_a_scheme() {
+        let mut config = valid();
+        config.ice.servers.push(IceServerConfig {
+            urls: vec!["turn.example.com:3478".to_owned()],
+            ..Default::default()
+        });
+        let err = config.validate().unwrap_err().to_string();
+        assert!(err.contains("turn.example.com:3478"), "{err}");
+    }
+}
@@ -1,12 +1,22 @@
+use std::{
+    collections::HashMap,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
 use bytes::Bytes;
-use protocol::{SignalEnum, TankCommand, UserId};
+use protocol::{
+    ControlCommand, IceCandidate, IceServer, SignalEnum, TankCommand, UserId, CONTROL_CHANNEL,
+};
 use webrtc::{
     api::{
-        interceptor_registry::register_default_interceptors,
-        media_engine::{MediaEngine, MIME_TYPE_H264},
-        APIBuilder,
+        interceptor_registry::{configure_twcc_sender_only, register_default_interceptors},
+        media_engine::MediaEngine,
+        APIBuilder, API,
+    },
+    ice_transport::{
+        ice_candidate::{RTCIceCandidate, RTCIceCandidateInit},
+        ice_server::RTCIceServer,
     },
-    ice_transport::{ice_connection_state::RTCIceConnectionState, ice_server::RTCIceServer},
     interceptor::registry::Registry,
     media::Sample,
     peer_connection::{
@@ -16,141 +26,461 @@ use webrtc::{
         sdp::{sdp_type::RTCSdpType, session_description::RTCSessionDescription},
         RTCPeerConnection,
     },
-    rtp_transceiver::rtp_codec::RTCRtpCodecCapability,
+    rtp_transceiver::rtp_codec::{RTCRtpCodecCapability, RTCRtpCodecParameters, RTPCodecType},
     track::track_local::{track_local_static_sample::TrackLocalStaticSample, TrackLocal},
 };
 
-use crate::{camera::VideoPacket, prelude::*, signaling::WebSocketCommand};
+use crate::{
+    camera::{since_the_epoch, VideoPacket},
+    control::ControlEvent,
+    encoding::is_keyframe_request,
+    metrics::Metrics,
+    pipeline::LatestReceiver,
+    prelude::*,
+    rate::{self, RateFeedback},
+    signaling::WebSocketCommand,
+};
 
 #[derive(PartialEq, Eq)]
 pub enum ConnState {
     NotConnected,
     Connected,
-    Failed,
 }
-/// initializes webrtc
-pub async fn init_connection(
+
+type Rtc = Arc<RTCPeerConnection>;
+
+struct Viewer {
+    peer_connection: Rtc,
+    /// Distinguishes a peer from the one it replaced when the same operator reconnects.
+    generation: u64,
+    connected: bool,
+}
+
+/// Every operator currently watching, keyed by their signaling id.
+#[derive(Clone, Default)]
+struct Viewers {
+    inner: Arc<Mutex<HashMap<UserId, Viewer>>>,
+}
+
+impl Viewers {
+    fn insert(&self, user_id: UserId, peer_connection: Rtc, generation: u64) -> Option<Rtc> {
+        let viewer = Viewer {
+            peer_connection,
+            generation,
+            connected: false,
+        };
+        let mut viewers = self.inner.lock().unwrap();
+        viewers
+            .insert(user_id, viewer)
+            .map(|old| old.peer_connection)
+    }
+
+    fn get(&self, user_id: &UserId) -> Option<Rtc> {
+        let viewers = self.inner.lock().unwrap();
+        viewers.get(user_id).map(|v| v.peer_connection.clone())
+    }
+
+    /// Removes the viewer, unless it has since been replaced by a newer peer.
+    fn remove(&self, user_id: &UserId, generation: Option<u64>) -> Option<Rtc> {
+        let mut viewers = self.inner.lock().unwrap();
+        match viewers.get(user_id) {
+            Some(viewer) if generation.is_none_or(|g| g == viewer.generation) => {
+                viewers.remove(user_id).map(|v| v.peer_connection)
+            }
+            _ => None,
+        }
+    }
+
+    fn set_connected(&self, user_id: &UserId, generation: u64, connected: bool) {
+        let mut viewers = self.inner.lock().unwrap();
+        if let Some(viewer) = viewers.get_mut(user_id) {
+            if viewer.generation == generation {
+                viewer.connected = connected;
+            }
+        }
+    }
+
+    /// Capture runs as long as at least one viewer is connected.
+    fn update_counter(&self, counter: &ConnectionState) {
+        let any_connected = self.inner.lock().unwrap().values().any(|v| v.connected);
+        let mut state = counter.lock().unwrap();
+        *state = if any_connected {
+            ConnState::Connected
+        } else {
+            ConnState::NotConnected
+        };
+    }
+}
+
+/// Everything needed to create a peer connection for a new viewer.
+#[derive(Clone)]
+struct PeerFactory {
+    api: Arc<API>,
+    config: RTCConfiguration,
+    /// ICE servers from our own config, kept when the signaling server hands out more.
+    configured_ice_servers: Vec<RTCIceServer>,
+    video_track: Arc<TrackLocalStaticSample>,
+    viewers: Viewers,
     counter: ConnectionState,
-    frame_receiver: Receiver<VideoPacket>,
-    webrtc_cmd_receiver: Receiver<WebRtcEnumCommand>,
     ws_sender: Sender<WebSocketCommand>,
+    control_sender: Sender<ControlEvent>,
+    rate_sender: Sender<RateFeedback>,
+    /// Peers asking for a keyframe, by generation.
+    keyframe_sender: Sender<u64>,
+    next_generation: Arc<AtomicU64>,
+    metrics: Arc<Metrics>,
+}
+
+impl PeerFactory {
+    /// Creates a peer connection for `user_id`, closing any previous one they had.
+    /// All peers share the same track, so every frame is encoded once and fanned out.
+    async fn create(&self, user_id: UserId) -> Result<Rtc> {
+        let generation = self.next_generation.fetch_add(1, Ordering::Relaxed);
+        let peer_connection = Arc::new(self.api.new_peer_connection(self.config.clone()).await?);
+
+        let (viewers, counter, id) = (self.viewers.clone(), self.counter.clone(), user_id.clone());
+        let metrics = self.metrics.clone();
+        let mut last_state = None;
+        // Set the handler for Peer connection state
+        // This will notify you when the peer has connected/disconnected
+        peer_connection.on_peer_connection_state_change(Box::new(
+            move |s: RTCPeerConnectionState| {
+                info!("Peer Connection State of {:?} has changed: {s}", id);
+                metrics.peer_state(last_state.replace(s), s);
+                let mut closed = None;
+                match s {
+                    RTCPeerConnectionState::Connected => {
+                        viewers.set_connected(&id, generation, true);
+                    }
+                    // may still recover on its own
+                    RTCPeerConnectionState::Disconnected => {
+                        viewers.set_connected(&id, generation, false);
+                    }
+                    // Failed only happens after ~30 seconds without network activity,
+                    // at that point the viewer is gone for good.
+                    RTCPeerConnectionState::Failed | RTCPeerConnectionState::Closed => {
+                        closed = viewers.remove(&id, Some(generation));
+                    }
+                    _ => {}
+                }
+                viewers.update_counter(&counter);
+                Box::pin(async move {
+                    if let Some(peer_connection) = closed {
+                        let _ = peer_connection.close().await;
+                    }
+                })
+            },
+        ));
+
+        // Trickle our candidates to the operator as they are gathered
+        let (ws_sender, id) = (self.ws_sender.clone(), user_id.clone());
+        peer_connection.on_ice_candidate(Box::new(move |c: Option<RTCIceCandidate>| {
+            match c.map(|c| c.to_json()).transpose() {
+                Ok(candidate) => {
+                    let candidate = candidate.map(|c| IceCandidate {
+                        candidate: c.candidate,
+                        sdp_mid: c.sdp_mid,
+                        sdp_mline_index: c.sdp_mline_index,
+                        username_fragment: c.username_fragment,
+                    });
+                    let _ = ws_sender.send(WebSocketCommand::SendSignal(SignalEnum::TankCommand(
+                        TankCommand::IceCandidate(id.clone(), candidate),
+                    )));
+                }
+                Err(e) => error!("failed to serialize local candidate: {e}"),
+            }
+            Box::pin(async {})
+        }));
+
+        // Drive commands arrive on the operator's control data channel
+        let (control_sender, id) = (self.control_sender.clone(), user_id.clone());
+        peer_connection.on_data_channel(Box::new(move |dc: Arc<RTCDataChannel>| {
+            if dc.label() == CONTROL_CHANNEL {
+                info!("control channel of {:?} opened", id);
+                let (control_sender, id) = (control_sender.clone(), id.clone());
+                dc.on_message(Box::new(move |msg: DataChannelMessage| {
+                    match serde_json::from_slice::<ControlCommand>(&msg.data) {
+                        Ok(command) => {
+                            let _ = control_sender.send(ControlEvent::Command(id.clone(), command));
+                        }
+                        Err(e) => warn!("invalid control message from {:?}: {e}", id),
+                    }
+                    Box::pin(async {})
+                }));
+            } else {
+                warn!("ignoring data channel {} of {:?}", dc.label(), id);
+            }
+            Box::pin(async {})
+        }));
+
+        let rtp_sender = peer_connection
+            .add_track(Arc::clone(&self.video_track) as Arc<dyn TrackLocal + Send + Sync>)
+            .await?;
+
+        // Read incoming RTCP packets
+        // Before these packets are returned they are processed by interceptors. For things
+        // like NACK this needs to be called. What they say about loss and bandwidth
+        // goes to the rate controller, keyframe requests to the encoder.
+        let (rate_sender, keyframe_sender, metrics) = (
+            self.rate_sender.clone(),
+            self.keyframe_sender.clone(),
+            self.metrics.clone(),
+        );
+        tokio::spawn(async move {
+            while let Ok((packets, _)) = rtp_sender.read_rtcp().await {
+                for packet in &packets {
+                    metrics.observe_rtcp(generation, packet.as_ref());
+                    if let Some(feedback) = rate::feedback(generation, packet.as_ref()) {
+                        let _ = rate_sender.send(feedback);
+                    }
+                    if is_keyframe_request(packet.as_ref()) {
+                        let _ = keyframe_sender.send(generation);
+                    }
+                }
+            }
+            let _ = rate_sender.send(RateFeedback::PeerClosed { peer: generation });
+            metrics.peer_closed(generation);
+            Result::<()>::Ok(())
+        });
+
+        if let Some(old) = self
+            .viewers
+            .insert(user_id.clone(), peer_connection.clone(), generation)
+        {
+            info!("replacing previous connection of {:?}", user_id);
+            let _ = old.close().await;
+        }
+        Ok(peer_connection)
+    }
+
+    /// New peers use the configured ICE servers plus the ones the signaling server sent,
+    /// which replace whatever it sent before since TURN credentials expire.
+    fn set_ice_servers(&mut self, servers: Vec<IceServer>) {
+        self.config.ice_servers = self
+            .configured_ice_servers
+            .iter()
+            .cloned()
+            .chain(servers.into_iter().map(|server| RTCIceServer {
+                urls: server.urls,
+                username: server.username.unwrap_or_default(),
+                credential: server.credential.unwrap_or_default(),
+                ..Default::default()
+            }))
+            .collect();
+    }
+
+    async fn close(&self, user_id: &UserId) {
+        if let Some(peer_connection) = self.viewers.remove(user_id, None) {
+            info!("closing connection of {:?}", user_id);
+            let _ = peer_connection.close().await;
+        }
+        self.viewers.update_counter(&self.counter);
+    }
+}
+
+/// Stands in for a peer generation when the writer itself asks for a keyframe.
+const WRITER_KEYFRAME_REQUEST: u64 = u64::MAX;
+
+/// Sample durations from the capture times, which is what the track advances the RTP
+/// timestamp by. A frame's duration is the time since the frame before it was captured,
+/// so frames the encoder skipped still count and the timestamps follow the camera's clock.
+struct SampleClock {
+    frame_interval: Duration,
+    last_capture: Option<Duration>,
+}
+
+impl SampleClock {
+    fn new(framerate: u32) -> Self {
+        Self {
+            frame_interval: Duration::from_secs(1) / framerate.max(1),
+            last_capture: None,
+        }
+    }
+
+    fn duration(&mut self, captured: Duration) -> Duration {
+        let duration = match self.last_capture {
+            // the system clock went backwards or two frames share a millisecond, a
+            // nominal frame keeps the RTP timestamps increasing
+            Some(last) if captured > last => captured - last,
+            _ => self.frame_interval,
+        };
+        self.last_capture = Some(
+            self.last_capture
+                .map_or(captured, |last| last.max(captured)),
+        );
+        duration
+    }
+}
+
+/// The connection's ends of the pipeline and of the other threads' channels.
+pub struct ConnectionHandles {
+    /// Whether any viewer is connected, which keeps the camera running.
+    pub counter: ConnectionState,
+    pub frame_receiver: LatestReceiver<VideoPacket>,
+    pub webrtc_cmd_receiver: Receiver<WebRtcEnumCommand>,
+    pub ws_sender: Sender<WebSocketCommand>,
+    pub control_sender: Sender<ControlEvent>,
+    pub rate_sender: Sender<RateFeedback>,
+    /// Peers asking for a keyframe, by generation.
+    pub keyframe_sender: Sender<u64>,
+    pub metrics: Arc<Metrics>,
+}
+
+/// What the writer does with an encoded frame.
+#[derive(Debug, PartialEq)]
+enum Admission {
+    Write,
+    /// Dropped and counted under `reason`, asking the encoder for a keyframe if it says so.
+    Drop {
+        reason: &'static str,
+        request_keyframe: bool,
+    },
+}
+
+/// Drops frames older than the latency budget, and once a frame is lost the deltas after it
+/// until a keyframe comes. That keyframe goes out however late it is: dropping it would only
+/// have the writer wait for the next one, which can be just as late.
+#[derive(Default)]
+struct KeyframeGate {
+    waiting: bool,
+}
+
+impl KeyframeGate {
+    /// `skipped` frames were lost right before this one.
+    fn admit(&mut self, stale: bool, skipped: u64, is_delta: bool) -> Admission {
+        let awaited = self.waiting && !is_delta;
+        let lost = stale || (skipped > 0 && is_delta);
+        if awaited || !(lost || self.waiting) {
+            self.waiting = false;
+            return Admission::Write;
+        }
+        let request_keyframe = !self.waiting;
+        self.waiting = true;
+        Admission::Drop {
+            reason: if stale { "stale" } else { "send" },
+            request_keyframe,
+        }
+    }
+}
+
+/// initializes webrtc, negotiating `codec` as the only video codec
+pub async fn init_connection(
+    codec: RTCRtpCodecCapability,
+    ice_servers: &[IceServerConfig],
+    handles: ConnectionHandles,
+    framerate: u32,
+    latency_budget: Duration,
 ) -> anyhow::Result<()> {
+    let ConnectionHandles {
+        counter,
+        frame_receiver,
+        webrtc_cmd_receiver,
+        ws_sender,
+        control_sender,
+        rate_sender,
+        keyframe_sender,
+        metrics,
+    } = handles;
+    // Only offer what the encoder actually produces, so the browser
+    // can't pick a codec we never send.
     let mut m = MediaEngine::default();
-    m.register_default_codecs()?;
+    m.register_codec(
+        RTCRtpCodecParameters {
+            capability: codec.clone(),
+            payload_type: 41,
+            ..Default::default()
+        },
+        RTPCodecType::Video,
+    )?;
 
     let mut registry = Registry::new();
     registry = register_default_interceptors(registry, &mut m)?;
+    // have browsers send transport-cc feedback about our packets
+    registry = configure_twcc_sender_only(registry, &mut m)?;
 
     let api = APIBuilder::new()
         .with_media_engine(m)
         .with_interceptor_registry(registry)
         .build();
 
-    let config = RTCConfiguration {
-        ice_servers: vec![RTCIceServer {
-            urls: vec!["stun:stun.l.google.com:19302".to_owned()],
+    let configured_ice_servers: Vec<RTCIceServer> = ice_servers
+        .iter()
+        .map(|server| RTCIceServer {
+            urls: server.urls.clone(),
+            username: server.username.clone(),
+            credential: server.credential.clone(),
             ..Default::default()
-        }],
+        })
+        .collect();
+    let config = RTCConfiguration {
+        ice_servers: configured_ice_servers.clone(),
         ..Default::default()
     };
 
-    let ccounter = counter.clone();
-    let peer_connection = Arc::new(api.new_peer_connection(config).await?);
-    // Set the handler for ICE connection state
-    // This will notify you when the peer has connected/disconnected
-    peer_connection.on_ice_connection_state_change(Box::new(
-        move |connection_state: RTCIceConnectionState| {
-            println!("Connection State has changed {connection_state}");
-            if connection_state == RTCIceConnectionState::Connected {
-                let mut a = ccounter.lock().unwrap();
-                *a = ConnState::Connected;
-            }
-            Box::pin(async {})
-        },
-    ));
-
-    // Set the handler for Peer connection state
-    // This will notify you when the peer has connected/disconnected
-    peer_connection.on_peer_connection_state_change(Box::new(move |s: RTCPeerConnectionState| {
-        println!("Peer Connection State has changed: {s}");
-
-        if s == RTCPeerConnectionState::Failed {
-            // Wait until PeerConnection has had no network activity for 30 seconds or another failure. It may be reconnected using an ICE Restart.
-            // Use webrtc.PeerConnectionStateDisconnected if you are interested in detecting faster timeout.
-            // Note that the PeerConnection may come back from PeerConnectionStateDisconnected.
-            println!("Peer Connection has gone to failed exiting");
-            let mut a = counter.lock().unwrap();
-            *a = ConnState::Failed;
-        }
-
-        Box::pin(async {})
-    }));
-
     let video_track = Arc::new(TrackLocalStaticSample::new(
-        RTCRtpCodecCapability {
-            mime_type: MIME_TYPE_H264.to_owned(),
-            ..Default::default()
-        },
+        codec,
         "video".to_owned(),
         "webrtc-rs".to_owned(),
     ));
 
-    let rtp_sender = peer_connection
-        .add_track(Arc::clone(&video_track) as Arc<dyn TrackLocal + Send + Sync>)
-        .await?;
-
-    // Read incoming RTCP packets
-    // Before these packets are returned they are processed by interceptors. For things
-    // like NACK this needs to be called.
-    tokio::spawn(async move {
-        let mut rtcp_buf = vec![0u8; 1500];
-        while let Ok((_, _)) = rtp_sender.read(&mut rtcp_buf).await {}
-        Result::<()>::Ok(())
-    });
-
-    tokio::spawn(async move {
-        let mut ticker = tokio::time::interval(Duration::from_millis(33));
-        loop {
-            if let Ok(frame) = frame_receiver.recv() {
-                let _ = video_track
-                    .write_sample(&Sample {
-                        data: Bytes::from(frame.data),
-                        duration: Duration::from_secs(1),
-                        ..Default::default()
-                    })
-                    .await;
-            }
+    let (writer_keyframe_sender, writer_metrics) = (keyframe_sender.clone(), metrics.clone());
+    let mut peers = PeerFactory {
+        api: Arc::new(api),
+        config,
+        configured_ice_servers,
+        video_track: video_track.clone(),
+        viewers: Viewers::default(),
+        counter,
+        ws_sender: ws_sender.clone(),
+        control_sender,
+        rate_sender,
+        keyframe_sender,
+        next_generation: Arc::new(AtomicU64::new(0)),
+        metrics,
+    };
 
-            let _ = ticker.tick().await;
-        }
-    });
+    tokio::spawn(write_frames(
+        frame_receiver,
+        video_track,
+        framerate,
+        latency_budget,
+        writer_keyframe_sender,
+        writer_metrics,
+    ));
     tokio::spawn(async move {
         loop {
             if let Ok(cmd) = webrtc_cmd_receiver.recv() {
                 match cmd {
-                    WebRtcEnumCommand::ReceiveIceHandshake(id, data) => {
-                        let result = handle_ice(&data, peer_connection.clone()).await;
-                        dbg!(&result);
-                        if let Ok(ice) = result {
-                            info!("sending ice answer");
-                            let _ = ws_sender.send(WebSocketCommand::SendSignal(
-                                SignalEnum::TankCommand(TankCommand::IceAnswer(id, ice)),
-                            ));
-                        } else if let Err(er) = result {
-                            error!("{0}", er)
+                    WebRtcEnumCommand::ReceiveIceCandidate(id, candidate) => {
+                        if let Err(e) = add_ice_candidate(&peers, &id, candidate).await {
+                            error!("failed to add ice candidate of {:?}: {e}", id);
                         }
                     }
-                    WebRtcEnumCommand::CloseConn => {
-                        let _ = peer_connection.close().await;
+                    WebRtcEnumCommand::CloseConn(id) => {
+                        peers.close(&id).await;
+                    }
+                    WebRtcEnumCommand::SetIceServers(servers) => {
+                        peers.set_ice_servers(servers);
                     }
                     WebRtcEnumCommand::ReceiveSdpOffer(id, data) => {
-                        let result =
-                            receive_sdp_offer_send_answer(peer_connection.clone(), data).await;
-                        if let Ok(answer) = result {
-                            info!("sending sdp answer");
-                            let _ = ws_sender.send(WebSocketCommand::SendSignal(
-                                SignalEnum::TankCommand(TankCommand::SdpAnswer(id, answer)),
-                            ));
+                        // a new offer always means a fresh connection from that operator
+                        let result = match peers.create(id.clone()).await {
+                            Ok(peer_connection) => {
+                                receive_sdp_offer_send_answer(peer_connection, data).await
+                            }
+                            Err(e) => Err(e),
+                        };
+                        match result {
+                            Ok(answer) => {
+                                info!("sending sdp answer");
+                                let _ = ws_sender.send(WebSocketCommand::SendSignal(
+                                    SignalEnum::TankCommand(TankCommand::SdpAnswer(id, answer)),
+                                ));
+                            }
+                            Err(e) => {
+                                error!("failed to answer offer of {:?}: {e}", id);
+                                peers.close(&id).await;
+                            }
                         }
                     }
                 }
@@ -161,38 +491,70 @@ pub async fn init_connection(
     Ok(())
 }
 
-type Rtc = Arc<RTCPeerConnection>;
-async fn handle_ice(data: &str, conn: Rtc) -> Result<String> {
-    info!("ender handle ice");
-    let offer = serde_json::from_str::<RTCSessionDescription>(data)?;
-
-    info!("parsed offer ");
-    // Set the remote SessionDescription
-    conn.set_remote_description(offer).await?;
-
-    info!("set remote desc ");
-    // Create an answer
-    let answer = conn.create_answer(None).await?;
-
-    // Create channel that is blocked until ICE Gathering is complete
-    let mut gather_complete = conn.gathering_complete_promise().await;
-
-    // Sets the LocalDescription, and starts our UDP listeners
-    conn.set_local_description(answer).await?;
-
-    // Block until ICE Gathering is complete, disabling trickle ICE
-    // we do this because we only can exchange one signaling message
-    // in a production application you should exchange ICE Candidates via OnICECandidate
-    let _ = gather_complete.recv().await;
+/// Writes encoded frames to the track every viewer shares, skipping frames that waited too
+/// long and the deltas after a lost frame until the next keyframe.
+async fn write_frames(
+    frame_receiver: LatestReceiver<VideoPacket>,
+    video_track: Arc<TrackLocalStaticSample>,
+    framerate: u32,
+    latency_budget: Duration,
+    keyframe_sender: Sender<u64>,
+    metrics: Arc<Metrics>,
+) {
+    let mut clock = SampleClock::new(framerate);
+    let mut gate = KeyframeGate::default();
+    while let Ok(frame) = frame_receiver.recv_async().await {
+        let skipped = frame_receiver.skipped();
+        metrics.dropped("send", skipped);
+        let stale = since_the_epoch().saturating_sub(frame.epochTime) > latency_budget;
+        let is_delta = frame.frameType.as_deref() == Some("delta");
+        if let Admission::Drop {
+            reason,
+            request_keyframe,
+        } = gate.admit(stale, skipped, is_delta)
+        {
+            if request_keyframe {
+                debug!("lost encoded frames, waiting for a keyframe");
+                let _ = keyframe_sender.send(WRITER_KEYFRAME_REQUEST);
+            }
+            metrics.dropped(reason, 1);
+            continue;
+        }
+        let _ = video_track
+            .write_sample(&Sample {
+                data: Bytes::from(frame.data),
+                timestamp: UNIX_EPOCH + frame.epochTime,
+                duration: clock.duration(frame.epochTime),
+                ..Default::default()
+            })
+            .await;
+    }
+    error!("encoder is gone, no more video");
+}
 
-    if let Some(local_desc) = conn.local_description().await {
-        let json_str = serde_json::to_string(&local_desc)?;
-        Ok(json_str)
-        // let b64 = signal::encode(&json_str);
-        // println!("{b64}");
-    } else {
-        Err(anyhow::Error::msg("generate local_description failed!"))
+async fn add_ice_candidate(
+    peers: &PeerFactory,
+    user_id: &UserId,
+    candidate: Option<IceCandidate>,
+) -> Result<()> {
+    let Some(peer_connection) = peers.viewers.get(user_id) else {
+        anyhow::bail!("no connection to add the candidate to");
+    };
+    match candidate {
+        Some(candidate) => {
+            debug!("adding remote candidate {}", candidate.candidate);
+            peer_connection
+                .add_ice_candidate(RTCIceCandidateInit {
+                    candidate: candidate.candidate,
+                    sdp_mid: candidate.sdp_mid,
+                    sdp_mline_index: candidate.sdp_mline_index,
+                    username_fragment: candidate.username_fragment,
+                })
+                .await?;
+        }
+        None => debug!("end of remote candidates from {:?}", user_id),
     }
+    Ok(())
 }
 
 pub async fn receive_sdp_answer(peer_a: Rtc, answer_sdp: String) -> Result<()> {
@@ -233,6 +595,253 @@ pub async fn create_sdp_offer(peer_a: Rtc) -> Result<String> {
 }
 pub enum WebRtcEnumCommand {
     ReceiveSdpOffer(UserId, String),
-    ReceiveIceHandshake(UserId, String),
-    CloseConn,
+    ReceiveIceCandidate(UserId, Option<IceCandidate>),
+    CloseConn(UserId),
+    /// ICE servers from the signaling server, used for peers created from now on.
+    SetIceServers(Vec<IceServer>),
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::Context as _;
+    use webrtc::{
+        api::{media_engine::MIME_TYPE_VP8, setting_engine::SettingEngine},
+        track::track_remote::TrackRemote,
+    };
+
+    use super::*;
+    use crate::{encoding::Encoder, pipeline};
+
+    const FRAME: Duration = Duration::from_millis(40);
+
+    fn ms(ms: u64) -> Duration {
+        Duration::from_millis(ms)
+    }
+
+    #[test]
+    fn first_frame_is_nominal() {
+        let mut clock = SampleClock::new(25);
+        assert_eq!(clock.duration(ms(1_000)), FRAME);
+        assert_eq!(clock.duration(ms(1_030)), ms(30));
+    }
+
+    #[test]
+    fn equal_capture_times() {
+        let mut clock = SampleClock::new(25);
+        clock.duration(ms(1_000));
+        assert_eq!(clock.duration(ms(1_000)), FRAME);
+        assert_eq!(clock.duration(ms(1_000)), FRAME);
+        assert_eq!(clock.duration(ms(1_040)), ms(40));
+    }
+
+    #[test]
+    fn clock_going_backwards() {
+        let mut clock = SampleClock::new(25);
+        clock.duration(ms(1_000));
+        assert_eq!(clock.duration(ms(500)), FRAME);
+        // measured from the latest capture, not the one that went back
+        assert_eq!(clock.duration(ms(1_040)), ms(40));
+        assert_eq!(clock.duration(ms(1_039)), FRAME);
+    }
+
+    #[test]
+    fn skipped_frames_count() {
+        let mut clock = SampleClock::new(25);
+        clock.duration(ms(1_000));
+        // the encoder skipped two frames in between
+        assert_eq!(clock.duration(ms(1_120)), ms(120));
+        assert_eq!(clock.duration(ms(1_160)), ms(40));
+    }
+
+    fn dropped(reason: &'static str, request_keyframe: bool) -> Admission {
+        Admission::Drop {
+            reason,
+            request_keyframe,
+        }
+    }
+
+    #[test]
+    fn fresh_frames_go_out() {
+        let mut gate = KeyframeGate::default();
+        assert_eq!(gate.admit(false, 0, true), Admission::Write);
+        // a keyframe doesn't need what was lost before it
+        assert_eq!(gate.admit(false, 3, false), Admission::Write);
+        assert_eq!(gate.admit(false, 0, true), Admission::Write);
+    }
+
+    #[test]
+    fn lost_deltas_wait_for_a_keyframe() {
+        let mut gate = KeyframeGate::default();
+        assert_eq!(gate.admit(false, 2, true), dropped("send", true));
+        assert_eq!(gate.admit(false, 0, true), dropped("send", false));
+        assert_eq!(gate.admit(true, 0, true), dropped("stale", false));
+        assert_eq!(gate.admit(false, 0, false), Admission::Write);
+        assert_eq!(gate.admit(false, 0, true), Admission::Write);
+    }
+
+    #[test]
+    fn awaited_keyframe_goes_out_however_late() {
+        let mut gate = KeyframeGate::default();
+        assert_eq!(gate.admit(true, 0, true), dropped("stale", true));
+        assert_eq!(gate.admit(true, 0, false), Admission::Write);
+        assert_eq!(gate.admit(false, 0, true), Admission::Write);
+        // a late keyframe nobody waits for is dropped like any other frame
+        assert_eq!(gate.admit(true, 0, false), dropped("stale", true));
+        assert_eq!(gate.admit(true, 0, false), Admission::Write);
+    }
+
+    async fn peer_connection(codec: &RTCRtpCodecCapability) -> Result<Arc<RTCPeerConnection>> {
+        let mut m = MediaEngine::default();
+        m.register_codec(
+            RTCRtpCodecParameters {
+                capability: codec.clone(),
+                payload_type: 96,
+                ..Default::default()
+            },
+            RTPCodecType::Video,
+        )?;
+        let registry = register_default_interceptors(Registry::new(), &mut m)?;
+        let mut settings = SettingEngine::default();
+        settings.set_include_loopback_candidate(true);
+        let api = APIBuilder::new()
+            .with_media_engine(m)
+            .with_interceptor_registry(registry)
+            .with_setting_engine(settings)
+            .build();
+        Ok(Arc::new(
+            api.new_peer_connection(RTCConfiguration::default()).await?,
+        ))
+    }
+
+    /// Offers from `sender` to `receiver` with all candidates in the descriptions.
+    async fn negotiate(sender: &RTCPeerConnection, receiver: &RTCPeerConnection) -> Result<()> {
+        let offer = sender.create_offer(None).await?;
+        let mut gathered = sender.gathering_complete_promise().await;
+        sender.set_local_description(offer).await?;
+        let _ = gathered.recv().await;
+        let offer = sender.local_description().await.context("no offer")?;
+        receiver.set_remote_description(offer).await?;
+
+        let answer = receiver.create_answer(None).await?;
+        let mut gathered = receiver.gathering_complete_promise().await;
+        receiver.set_local_description(answer).await?;
+        let _ = gathered.recv().await;
+        let answer = receiver.local_description().await.context("no answer")?;
+        sender.set_remote_description(answer).await?;
+        Ok(())
+    }
+
+    fn key_frame(epoch_time: Duration) -> VideoPacket {
+        VideoPacket {
+            data: vec![0x10; 300],
+            frameType: Some("key".to_owned()),
+            epochTime: epoch_time,
+            encoding: Encoder::VP8,
+        }
+    }
+
+    #[tokio::test]
+    async fn rtp_timestamps_keep_increasing() -> Result<()> {
+        let codec = RTCRtpCodecCapability {
+            mime_type: MIME_TYPE_VP8.to_owned(),
+            clock_rate: 90000,
+            ..Default::default()
+        };
+        let sender = peer_connection(&codec).await?;
+        let receiver = peer_connection(&codec).await?;
+        let track = Arc::new(TrackLocalStaticSample::new(
+            codec.clone(),
+            "video".to_owned(),
+            "test".to_owned(),
+        ));
+        sender
+            .add_track(track.clone() as Arc<dyn TrackLocal + Send + Sync>)
+            .await?;
+        receiver
+            .add_transceiver_from_kind(RTPCodecType::Video, None)
+            .await?;
+
+        let (timestamp_tx, mut timestamp_rx) = tokio::sync::mpsc::unbounded_channel();
+        receiver.on_track(Box::new(move |track: Arc<TrackRemote>, _, _| {
+            let timestamp_tx = timestamp_tx.clone();
+            Box::pin(async move {
+                while let Ok((packet, _)) = track.read_rtp().await {
+                    // the last packet of every frame
+                    if packet.header.marker {
+                        let _ = timestamp_tx.send(packet.header.timestamp);
+                    }
+                }
+            })
+        }));
+        let connected = Arc::new(tokio::sync::Notify::new());
+        let on_connected = connected.clone();
+        sender.on_peer_connection_state_change(Box::new(move |state| {
+            if state == RTCPeerConnectionState::Connected {
+                on_connected.notify_one();
+            }
+            Box::pin(async {})
+        }));
+        negotiate(&sender, &receiver).await?;
+        tokio::time::timeout(Duration::from_secs(10), connected.notified())
+            .await
+            .context("peers didn't connect")?;
+
+        let metrics = Arc::new(Metrics::new()?);
+        let (frame_sender, frame_receiver) = pipeline::latest(metrics.queue("send"));
+        let (keyframe_sender, _keyframe_receiver) = mpsc::channel();
+        let writer = tokio::spawn(write_frames(
+            frame_receiver,
+            track,
+            25,
+            Duration::from_secs(60),
+            keyframe_sender,
+            metrics,
+        ));
+
+        // until the receiver sees frames, so that none of the ones below can get lost
+        let now = since_the_epoch();
+        let warmed_up = async {
+            loop {
+                let _ = frame_sender.send(key_frame(now - FRAME));
+                if let Ok(Some(_)) = tokio::time::timeout(ms(100), timestamp_rx.recv()).await {
+                    break;
+                }
+            }
+        };
+        tokio::time::timeout(Duration::from_secs(10), warmed_up)
+            .await
+            .context("no frames arrived")?;
+        tokio::time::sleep(ms(200)).await;
+        while timestamp_rx.try_recv().is_ok() {}
+
+        // regular frames, a repeated capture time, the clock stepping back and skipped frames
+        let captures = [0, 40, 80, 80, 80, 60, 20, 120, 160, 400, 440, 440, 480]
+            .map(|offset| now + ms(offset));
+        for captured in captures {
+            let _ = frame_sender.send(key_frame(captured));
+            tokio::time::sleep(ms(20)).await;
+        }
+        let mut timestamps: Vec<u32> = vec![];
+        while timestamps.len() < captures.len() {
+            let timestamp = tokio::time::timeout(Duration::from_secs(2), timestamp_rx.recv())
+                .await
+                .ok()
+                .flatten()
+                .with_context(|| format!("only {timestamps:?} arrived"))?;
+            timestamps.push(timestamp);
+        }
+        // each frame advances the clock by the time to the next capture, a nominal frame where
+        // that doesn't go forward, in 90 kHz ticks give or take rounding
+        let expected = [40, 40, 40, 40, 40, 40, 40, 40, 40, 240, 40, 40].map(|ms| ms * 90);
+        for (pair, expected) in timestamps.windows(2).zip(expected) {
+            let step = pair[1].wrapping_sub(pair[0]);
+            assert!(step.abs_diff(expected) <= 1, "{timestamps:?}");
+        }
+
+        drop(frame_sender);
+        writer.await?;
+        sender.close().await?;
+        receiver.close().await?;
+        Ok(())
+    }
 }
@@ -0,0 +1,409 @@
+use std::{sync::mpsc::RecvTimeoutError, time::Instant};
+
+use protocol::{ControlCommand, SignalEnum, TankCommand, UserId};
+
+use crate::{prelude::*, signaling::WebSocketCommand};
+
+/// Whatever moves the tank. Axes are already clamped to -1.0..=1.0.
+pub trait Actuator: Send {
+    /// Track speed forward/backward and turn rate left/right.
+    fn drive(&mut self, throttle: f32, steering: f32) -> Result<()>;
+    /// Turret rotation and gun elevation speed.
+    fn turret(&mut self, rotation: f32, elevation: f32) -> Result<()>;
+    /// Brings every motor to a halt.
+    fn stop(&mut self) -> Result<()>;
+    /// Called by the watchdog when the operator is gone, should stop as fast as the hardware can.
+    fn emergency_stop(&mut self) -> Result<()> {
+        self.stop()
+    }
+}
+
+/// Actuator without hardware that only logs what it was told to do.
+#[derive(Debug, Default)]
+pub struct SimulatedActuator {
+    throttle: f32,
+    steering: f32,
+    rotation: f32,
+    elevation: f32,
+}
+
+impl Actuator for SimulatedActuator {
+    fn drive(&mut self, throttle: f32, steering: f32) -> Result<()> {
+        self.throttle = throttle;
+        self.steering = steering;
+        info!("simulated drive: throttle {throttle:.2} steering {steering:.2}");
+        Ok(())
+    }
+
+    fn turret(&mut self, rotation: f32, elevation: f32) -> Result<()> {
+        self.rotation = rotation;
+        self.elevation = elevation;
+        info!("simulated turret: rotation {rotation:.2} elevation {elevation:.2}");
+        Ok(())
+    }
+
+    fn stop(&mut self) -> Result<()> {
+        *self = Self::default();
+        info!("simulated stop");
+        Ok(())
+    }
+
+    fn emergency_stop(&mut self) -> Result<()> {
+        *self = Self::default();
+        warn!("simulated emergency stop");
+        Ok(())
+    }
+}
+
+/// Anything outside -1.0..=1.0 (or NaN) from the wire is clamped before it reaches a motor.
+fn axis(value: f32) -> f32 {
+    if value.is_nan() {
+        0.0
+    } else {
+        value.clamp(-1.0, 1.0)
+    }
+}
+
+fn dispatch(actuator: &mut dyn Actuator, command: ControlCommand) -> Result<()> {
+    match command {
+        ControlCommand::Drive { throttle, steering } => {
+            actuator.drive(axis(throttle), axis(steering))
+        }
+        ControlCommand::Turret {
+            rotation,
+            elevation,
+        } => actuator.turret(axis(rotation), axis(elevation)),
+        ControlCommand::Stop => actuator.stop(),
+    }
+}
+
+/// Whether the command sets anything in motion.
+fn moves(command: &ControlCommand) -> bool {
+    match *command {
+        ControlCommand::Drive { throttle, steering } => {
+            axis(throttle) != 0.0 || axis(steering) != 0.0
+        }
+        ControlCommand::Turret {
+            rotation,
+            elevation,
+        } => axis(rotation) != 0.0 || axis(elevation) != 0.0,
+        ControlCommand::Stop => false,
+    }
+}
+
+/// What the control thread reacts to.
+#[derive(Debug)]
+pub enum ControlEvent {
+    /// A command from an operator's control data channel.
+    Command(UserId, ControlCommand),
+    /// The signaling server moved the control lease, `None` when nobody holds it.
+    ControllerChanged(Option<UserId>),
+}
+
+/// Operator whose commands are currently moving the tank.
+struct ActiveOperator {
+    user_id: UserId,
+    deadline: Instant,
+}
+
+/// Applies control commands received over the operators' data channels.
+/// Only the operator holding the control lease is obeyed, everyone else is a spectator.
+///
+/// Doubles as a dead-man switch: once an operator has moved the tank, the drive
+/// state their page keeps repeating has to arrive within `deadman_timeout` or the
+/// tank is stopped and the stop is reported to them through the signaling server.
+/// Only state counts, so a page that is alive but lost its key releases can't keep
+/// an old command going.
+pub fn control_thread(
+    control_rx: Receiver<ControlEvent>,
+    mut actuator: Box<dyn Actuator>,
+    deadman_timeout: Duration,
+    ws_sender: Sender<WebSocketCommand>,
+) -> JoinHandle<()> {
+    thread::spawn(move || {
+        let mut controller: Option<UserId> = None;
+        let mut active: Option<ActiveOperator> = None;
+        loop {
+            let received = match &active {
+                Some(operator) => control_rx
+                    .recv_timeout(operator.deadline.saturating_duration_since(Instant::now())),
+                None => control_rx
+                    .recv()
+                    .map_err(|_| RecvTimeoutError::Disconnected),
+            };
+            match received {
+                Ok(ControlEvent::ControllerChanged(user_id)) => {
+                    info!("control lease moved to {:?}", user_id);
+                    if active.as_ref().map(|op| &op.user_id) != user_id.as_ref() {
+                        // whoever was driving lost the lease mid-command
+                        if active.take().is_some() {
+                            if let Err(e) = actuator.stop() {
+                                error!("actuator failed to stop: {e}");
+                            }
+                        }
+                    }
+                    controller = user_id;
+                }
+                Ok(ControlEvent::Command(user_id, command)) => {
+                    debug!("control from {:?}: {:?}", user_id, command);
+                    if controller.as_ref() != Some(&user_id) {
+                        debug!(
+                            "ignoring {:?}, {:?} doesn't hold the lease",
+                            command, user_id
+                        );
+                        continue;
+                    }
+                    let is_active = active.as_ref().map(|op| &op.user_id) == Some(&user_id);
+                    // an operator who isn't driving repeating that nothing moves
+                    // leaves nothing to watch
+                    if is_active || moves(&command) {
+                        active = Some(ActiveOperator {
+                            user_id,
+                            deadline: Instant::now() + deadman_timeout,
+                        });
+                    }
+                    if let Err(e) = dispatch(actuator.as_mut(), command) {
+                        error!("actuator failed on {:?}: {e}", command);
+                    }
+                }
+                Err(RecvTimeoutError::Timeout) => {
+                    let Some(operator) = active.take() else {
+                        continue;
+                    };
+                    let reason = format!("no drive state for {} ms", deadman_timeout.as_millis());
+                    error!("emergency stop, {reason}");
+                    if let Err(e) = actuator.emergency_stop() {
+                        error!("actuator failed to emergency stop: {e}");
+                    }
+                    let _ = ws_sender.send(WebSocketCommand::SendSignal(SignalEnum::TankCommand(
+                        TankCommand::EmergencyStop(operator.user_id, reason),
+                    )));
+                }
+                Err(RecvTimeoutError::Disconnected) => break,
+            }
+        }
+        warn!("control channel closed, stopping");
+        let _ = actuator.emergency_stop();
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::mpsc::channel;
+
+    use super::*;
+
+    const DEADMAN: Duration = Duration::from_millis(100);
+    /// How often the tests repeat the drive state, well within `DEADMAN`.
+    const REPEAT: Duration = Duration::from_millis(10);
+
+    #[derive(Debug, PartialEq)]
+    enum Call {
+        Drive(f32, f32),
+        Stop,
+        EmergencyStop,
+    }
+
+    /// The simulated actuator, telling the test about every call it gets.
+    struct Recorder {
+        simulated: SimulatedActuator,
+        calls: Sender<Call>,
+    }
+
+    impl Actuator for Recorder {
+        fn drive(&mut self, throttle: f32, steering: f32) -> Result<()> {
+            let _ = self.calls.send(Call::Drive(throttle, steering));
+            self.simulated.drive(throttle, steering)
+        }
+
+        fn turret(&mut self, rotation: f32, elevation: f32) -> Result<()> {
+            self.simulated.turret(rotation, elevation)
+        }
+
+        fn stop(&mut self) -> Result<()> {
+            let _ = self.calls.send(Call::Stop);
+            self.simulated.stop()
+        }
+
+        fn emergency_stop(&mut self) -> Result<()> {
+            let _ = self.calls.send(Call::EmergencyStop);
+            self.simulated.emergency_stop()
+        }
+    }
+
+    struct Tank {
+        control: Sender<ControlEvent>,
+        calls: Receiver<Call>,
+        signals: Receiver<WebSocketCommand>,
+        thread: JoinHandle<()>,
+    }
+
+    impl Tank {
+        fn start(controller: &UserId) -> Self {
+            let (control, control_rx) = channel();
+            let (calls_tx, calls) = channel();
+            let (ws_sender, signals) = channel();
+            let actuator = Recorder {
+                simulated: SimulatedActuator::default(),
+                calls: calls_tx,
+            };
+            let thread = control_thread(control_rx, Box::new(actuator), DEADMAN, ws_sender);
+            control
+                .send(ControlEvent::ControllerChanged(Some(controller.clone())))
+                .unwrap();
+            Self {
+                control,
+                calls,
+                signals,
+                thread,
+            }
+        }
+
+        fn send(&self, user_id: &UserId, command: ControlCommand) {
+            self.control
+                .send(ControlEvent::Command(user_id.clone(), command))
+                .unwrap();
+        }
+
+        /// Sends the command every `REPEAT` for `duration`.
+        fn repeat(&self, user_id: &UserId, command: ControlCommand, duration: Duration) {
+            let until = Instant::now() + duration;
+            while Instant::now() < until {
+                self.send(user_id, command);
+                thread::sleep(REPEAT);
+            }
+        }
+
+        fn calls(&self) -> Vec<Call> {
+            self.calls.try_iter().collect()
+        }
+
+        /// The emergency stop reported to the signaling server, waiting up to `within` for it.
+        fn reported_stop(&self, within: Duration) -> Option<(UserId, String)> {
+            match self.signals.recv_timeout(within) {
+                Ok(WebSocketCommand::SendSignal(SignalEnum::TankCommand(
+                    TankCommand::EmergencyStop(user_id, reason),
+                ))) => Some((user_id, reason)),
+                Ok(_) => panic!("only emergency stops are reported"),
+                Err(_) => None,
+            }
+        }
+
+        fn shut_down(self) -> Vec<Call> {
+            drop(self.control);
+            self.thread.join().unwrap();
+            self.calls.try_iter().collect()
+        }
+    }
+
+    fn user(name: &str) -> UserId {
+        UserId::new(name.to_owned())
+    }
+
+    const FORWARD: ControlCommand = ControlCommand::Drive {
+        throttle: 1.0,
+        steering: 0.0,
+    };
+    const STILL: ControlCommand = ControlCommand::Drive {
+        throttle: 0.0,
+        steering: 0.0,
+    };
+
+    #[test]
+    fn stops_when_the_drive_state_stops() {
+        let alice = user("alice");
+        let tank = Tank::start(&alice);
+        tank.repeat(&alice, FORWARD, DEADMAN * 3);
+        assert_eq!(tank.reported_stop(Duration::ZERO), None);
+
+        let silent = Instant::now();
+        let (user_id, reason) = tank.reported_stop(Duration::from_secs(5)).unwrap();
+        assert!(silent.elapsed() >= DEADMAN / 2);
+        assert_eq!(user_id, alice);
+        assert_eq!(reason, "no drive state for 100 ms");
+        let calls = tank.calls();
+        assert!(calls[..calls.len() - 1]
+            .iter()
+            .all(|call| *call == Call::Drive(1.0, 0.0)));
+        assert_eq!(calls.last(), Some(&Call::EmergencyStop));
+
+        // a stopped tank has nothing left to watch
+        assert_eq!(tank.reported_stop(DEADMAN * 2), None);
+        assert_eq!(tank.shut_down(), vec![Call::EmergencyStop]);
+    }
+
+    #[test]
+    fn spectators_dont_keep_the_tank_going() {
+        let (alice, bob) = (user("alice"), user("bob"));
+        let tank = Tank::start(&alice);
+        tank.send(&alice, FORWARD);
+        let started = Instant::now();
+        // whatever bob sends is ignored and doesn't count as the drive state
+        tank.repeat(&bob, STILL, DEADMAN * 3);
+        tank.send(&bob, FORWARD);
+        let (user_id, _) = tank.reported_stop(Duration::from_secs(5)).unwrap();
+        assert_eq!(user_id, alice);
+        assert!(started.elapsed() < DEADMAN * 4);
+        assert_eq!(
+            tank.calls(),
+            vec![Call::Drive(1.0, 0.0), Call::EmergencyStop]
+        );
+        tank.shut_down();
+    }
+
+    #[test]
+    fn standing_still_needs_no_drive_state() {
+        let alice = user("alice");
+        let tank = Tank::start(&alice);
+        tank.send(&alice, STILL);
+        tank.send(&alice, ControlCommand::Stop);
+        assert_eq!(tank.reported_stop(DEADMAN * 3), None);
+        assert_eq!(
+            tank.shut_down(),
+            vec![Call::Drive(0.0, 0.0), Call::Stop, Call::EmergencyStop]
+        );
+    }
+
+    #[test]
+    fn losing_the_lease_stops_the_tank() {
+        let (alice, bob) = (user("alice"), user("bob"));
+        let tank = Tank::start(&alice);
+        tank.send(&alice, FORWARD);
+        tank.control
+            .send(ControlEvent::ControllerChanged(Some(bob)))
+            .unwrap();
+        assert_eq!(tank.reported_stop(DEADMAN * 3), None);
+        assert_eq!(
+            tank.shut_down(),
+            vec![Call::Drive(1.0, 0.0), Call::Stop, Call::EmergencyStop]
+        );
+    }
+
+    #[test]
+    fn axes_are_clamped() {
+        let mut actuator = SimulatedActuator::default();
+        let drive = |throttle, steering| ControlCommand::Drive { throttle, steering };
+        dispatch(&mut actuator, drive(3.0, -7.5)).unwrap();
+        assert_eq!((actuator.throttle, actuator.steering), (1.0, -1.0));
+        dispatch(&mut actuator, drive(f32::NEG_INFINITY, 0.25)).unwrap();
+        assert_eq!((actuator.throttle, actuator.steering), (-1.0, 0.25));
+
+        let turret = ControlCommand::Turret {
+            rotation: f32::NAN,
+            elevation: f32::INFINITY,
+        };
+        dispatch(&mut actuator, turret).unwrap();
+        assert_eq!((actuator.rotation, actuator.elevation), (0.0, 1.0));
+
+        dispatch(&mut actuator, ControlCommand::Stop).unwrap();
+        assert_eq!(
+            (
+                actuator.throttle,
+                actuator.steering,
+                actuator.rotation,
+                actuator.elevation
+            ),
+            (0.0, 0.0, 0.0, 0.0)
+        );
+    }
+}
@@ -1,21 +1,40 @@
 use std::{str::FromStr, time::Instant};
 
-use image::{codecs, ImageBuffer, Rgb};
-use rav1e::{
-    color::ChromaSampling, config::SpeedSettings, data::FrameType, Config, Context, EncoderConfig,
-};
+use image::imageops;
 use serde::{Deserialize, Serialize};
+use webrtc::{
+    api::media_engine::{MIME_TYPE_AV1, MIME_TYPE_H264, MIME_TYPE_VP8},
+    rtcp::{
+        packet::Packet,
+        payload_feedbacks::{
+            full_intra_request::FullIntraRequest, picture_loss_indication::PictureLossIndication,
+        },
+    },
+    rtp_transceiver::{rtp_codec::RTCRtpCodecCapability, RTCPFeedback},
+};
 
 use crate::{
     camera::{since_the_epoch, VideoPacket},
+    colorspace::{Conversion, I420},
+    config::EncodingConfig,
+    metrics::Metrics,
+    pipeline::{LatestReceiver, LatestSender},
     prelude::*,
-    THRESHOLD_MILLIS,
+    rate::{EncodeTarget, RateController, RateFeedback},
+    source::{Frame, RgbFrame},
 };
 
+mod av1;
+#[cfg(feature = "h264")]
+mod h264;
+#[cfg(feature = "vp8")]
+mod vp8;
+
 #[derive(Debug, PartialEq, Serialize, Deserialize, Clone)]
 pub enum Encoder {
-    MJPEG,
     AV1,
+    VP8,
+    H264,
 }
 
 impl FromStr for Encoder {
@@ -23,139 +42,369 @@ impl FromStr for Encoder {
 
     fn from_str(input: &str) -> Result<Encoder, Self::Err> {
         match input {
-            "MJPEG" => Ok(Encoder::MJPEG),
             "AV1" => Ok(Encoder::AV1),
+            "VP8" => Ok(Encoder::VP8),
+            "H264" => Ok(Encoder::H264),
             _ => Err(()),
         }
     }
 }
 
-pub fn encoder_config(width: usize, height: usize) -> Config {
-    let mut speed_settings = SpeedSettings::from_preset(1);
-    speed_settings.rdo_lookahead_frames = 1;
-
-    let enc = EncoderConfig {
-        width,
-        height,
-        bit_depth: 8,
-        error_resilient: true,
-        min_key_frame_interval: 20,
-        max_key_frame_interval: 50,
-        low_latency: true,
-        min_quantizer: 50,
-        quantizer: 100,
-        still_picture: false,
-        tiles: 4,
-        chroma_sampling: ChromaSampling::Cs444,
-        speed_settings,
-        ..Default::default()
-    };
-    Config::new().with_encoder_config(enc).with_threads(4)
+impl Encoder {
+    /// Codec the encoded stream is negotiated as over WebRTC.
+    /// Fails for encoders that this build leaves out.
+    pub fn codec_capability(&self) -> Result<RTCRtpCodecCapability> {
+        let (mime_type, sdp_fmtp_line) = match self {
+            Encoder::AV1 => (
+                MIME_TYPE_AV1,
+                format!(
+                    "level-idx=5;profile={};tier=0",
+                    av1::av1_profile(av1::CHROMA_SAMPLING)
+                ),
+            ),
+            Encoder::VP8 if cfg!(feature = "vp8") => (MIME_TYPE_VP8, String::new()),
+            Encoder::H264 if cfg!(feature = "h264") => (
+                MIME_TYPE_H264,
+                "level-asymmetry-allowed=1;packetization-mode=1;profile-level-id=42e01f".to_owned(),
+            ),
+            Encoder::VP8 | Encoder::H264 => return Err(self.not_built()),
+        };
+        Ok(RTCRtpCodecCapability {
+            mime_type: mime_type.to_owned(),
+            clock_rate: 90000,
+            channels: 0,
+            sdp_fmtp_line,
+            rtcp_feedback: video_rtcp_feedback(),
+        })
+    }
+
+    /// Starts the encoder for the target, on the thread that's going to use it.
+    pub fn open(
+        &self,
+        settings: &EncodingConfig,
+        target: &EncodeTarget,
+    ) -> Result<Box<dyn VideoEncoder>> {
+        Ok(match self {
+            Encoder::AV1 => Box::new(av1::Av1Encoder::new(settings, target)?),
+            #[cfg(feature = "vp8")]
+            Encoder::VP8 => Box::new(vp8::Vp8Encoder::new(settings, target)?),
+            #[cfg(feature = "h264")]
+            Encoder::H264 => Box::new(h264::H264Encoder::new(settings, target)?),
+            #[allow(unreachable_patterns)]
+            _ => return Err(self.not_built()),
+        })
+    }
+
+    fn not_built(&self) -> anyhow::Error {
+        anyhow::anyhow!(
+            "camera-service was built without the {} feature",
+            format!("{self:?}").to_lowercase()
+        )
+    }
+}
+
+/// Bitrate for codecs that need one while rate control is off.
+#[cfg(any(feature = "vp8", feature = "h264"))]
+const DEFAULT_BITRATE_BPS: u32 = 1_000_000;
+
+#[cfg(any(feature = "vp8", feature = "h264"))]
+fn bitrate_or_default(bitrate_bps: u32) -> u32 {
+    if bitrate_bps == 0 {
+        DEFAULT_BITRATE_BPS
+    } else {
+        bitrate_bps
+    }
+}
+
+/// A codec the encoder thread drives. Each takes frames in whatever format the source
+/// delivers and converts them itself, so adding one doesn't touch the threading code.
+pub trait VideoEncoder {
+    /// Switches to a new resolution, frame rate and bitrate. The next frame is a keyframe.
+    fn configure(&mut self, target: &EncodeTarget) -> Result<()>;
+    /// Encodes a frame, returning whatever packets the codec has ready.
+    fn encode(&mut self, frame: Frame, captured: Duration) -> Result<Vec<VideoPacket>>;
+    /// Makes the next frame a keyframe.
+    fn force_keyframe(&mut self);
+    /// Changes the bitrate. None of the codecs can do that mid-stream, so like `configure`
+    /// this starts the encoder over and the next frame is a keyframe.
+    fn set_bitrate(&mut self, bitrate_bps: u32) -> Result<()>;
+    /// Packets for frames the codec still holds back.
+    fn flush(&mut self) -> Result<Vec<VideoPacket>>;
+}
+
+fn video_rtcp_feedback() -> Vec<RTCPFeedback> {
+    [
+        ("goog-remb", ""),
+        ("ccm", "fir"),
+        ("nack", ""),
+        ("nack", "pli"),
+        ("transport-cc", ""),
+    ]
+    .into_iter()
+    .map(|(typ, parameter)| RTCPFeedback {
+        typ: typ.to_owned(),
+        parameter: parameter.to_owned(),
+    })
+    .collect()
+}
+
+/// Whether a viewer's RTCP packet asks for a keyframe, PLI and FIR both do.
+pub fn is_keyframe_request(packet: &(dyn Packet + Send + Sync)) -> bool {
+    let packet = packet.as_any();
+    packet.is::<PictureLossIndication>() || packet.is::<FullIntraRequest>()
+}
+
+/// Keyframes viewers asked for, forced at most once per interval however often they ask.
+/// A request that comes in too early is kept and honoured once the interval is over.
+struct KeyframeRequests {
+    min_interval: Duration,
+    pending: bool,
+    last_keyframe: Option<Instant>,
+}
+
+impl KeyframeRequests {
+    fn new(min_interval: Duration) -> Self {
+        Self {
+            min_interval,
+            pending: false,
+            last_keyframe: None,
+        }
+    }
+
+    fn request(&mut self) {
+        self.pending = true;
+    }
+
+    /// A keyframe went out anyway, which answers every request so far.
+    fn keyframe_sent(&mut self) {
+        self.keyframe_sent_at(Instant::now());
+    }
+
+    fn keyframe_sent_at(&mut self, now: Instant) {
+        self.pending = false;
+        self.last_keyframe = Some(now);
+    }
+
+    /// Whether the next frame should be forced to be a keyframe.
+    fn take(&mut self) -> bool {
+        self.take_at(Instant::now())
+    }
+
+    fn take_at(&mut self, now: Instant) -> bool {
+        let due = self
+            .last_keyframe
+            .is_none_or(|last| now.duration_since(last) >= self.min_interval);
+        if self.pending && due {
+            self.keyframe_sent_at(now);
+            return true;
+        }
+        false
+    }
+}
+
+/// The encoder thread's ends of the pipeline.
+pub struct EncoderHandles {
+    pub cam_rx: LatestReceiver<CameraPacket>,
+    pub video_sender: LatestSender<VideoPacket>,
+    /// Feedback from every viewer for the rate controller.
+    pub rate_rx: Receiver<RateFeedback>,
+    /// Peers asking for a keyframe, by generation.
+    pub keyframe_rx: Receiver<u64>,
+    pub metrics: Arc<Metrics>,
 }
 
+/// Encodes camera frames at whatever the rate controller makes of the viewers' feedback,
+/// scaling frames and skipping them to meet its resolution and frame rate.
 pub fn encoder_thread(
-    fps_tx: Sender<u128>,
-    cam_rx: Receiver<CameraPacket>,
-    video_sender: Sender<VideoPacket>,
+    handles: EncoderHandles,
     encoder: Encoder,
-    cfg: Config,
-    width: usize,
+    settings: EncodingConfig,
+    mut rate: RateController,
+    max_frame_age: Duration,
 ) -> JoinHandle<()> {
+    let EncoderHandles {
+        cam_rx,
+        video_sender,
+        rate_rx,
+        keyframe_rx,
+        metrics,
+    } = handles;
     thread::spawn(move || {
-        let fps_tx_copy = fps_tx.clone();
-        let mut ctx: Context<u8> = cfg.new_context().unwrap();
-        loop {
-            let (frame, age) = cam_rx.recv().unwrap();
+        let mut keyframes =
+            KeyframeRequests::new(Duration::from_millis(settings.keyframe_request_interval_ms));
+        let mut target = rate.current();
+        metrics.target_bitrate.set(i64::from(target.bitrate_bps));
+        let mut video_encoder = match encoder.open(&settings, &target) {
+            Ok(video_encoder) => video_encoder,
+            Err(e) => {
+                error!("could not start {:?} encoder: {e}", encoder);
+                return;
+            }
+        };
+        let mut last_encoded: Option<Instant> = None;
+        while let Ok((frame, captured_ms)) = cam_rx.recv() {
+            metrics.dropped("capture", cam_rx.skipped());
+            while let Ok(feedback) = rate_rx.try_recv() {
+                rate.on_feedback(feedback);
+            }
+            while let Ok(peer) = keyframe_rx.try_recv() {
+                debug!("peer {peer} asked for a keyframe");
+                keyframes.request();
+            }
+            if let Some(new_target) = rate.poll() {
+                info!("encoding at {:?}", new_target);
+                let same_size = (new_target.width, new_target.height, new_target.framerate)
+                    == (target.width, target.height, target.framerate);
+                let result = if same_size {
+                    video_encoder.set_bitrate(new_target.bitrate_bps)
+                } else {
+                    video_encoder.configure(&new_target)
+                };
+                match result {
+                    Ok(()) => {
+                        // either way the encoder starts over with a keyframe, for a new
+                        // resolution that is how the browser learns about it
+                        keyframes.keyframe_sent();
+                        target = new_target;
+                        metrics.target_bitrate.set(i64::from(target.bitrate_bps));
+                    }
+                    Err(e) => error!("could not reconfigure encoder: {e}"),
+                }
+            }
+
             // If age older than threshold, throw it away.
-            let frame_age = since_the_epoch().as_millis() - age;
+            let frame_age = since_the_epoch().as_millis() - captured_ms;
             debug!("frame age {}", frame_age);
-            if frame_age > THRESHOLD_MILLIS {
+            if frame_age > max_frame_age.as_millis() {
                 debug!("throwing away old frame with age {} ms", frame_age);
+                metrics.dropped("stale", 1);
+                continue;
+            }
+            // leave some slack so frames arriving a bit early don't halve the rate
+            let interval = Duration::from_secs(1) / target.framerate;
+            if last_encoded.is_some_and(|last| last.elapsed() < interval.mul_f32(0.9)) {
                 continue;
             }
+            last_encoded = Some(Instant::now());
+            if keyframes.take() {
+                video_encoder.force_keyframe();
+            }
 
-            let video_frame = if encoder == Encoder::MJPEG {
-                encode_mjpeg(&frame, encoder.clone())
-            } else {
-                match encode_idk(&frame, encoder.clone(), &mut ctx, width) {
-                    Ok(x) => x,
-                    Err(_) => continue,
-                }
-            };
-            let _ = video_sender.send(video_frame);
-            fps_tx_copy.send(since_the_epoch().as_millis()).unwrap();
+            let captured = Duration::from_millis(captured_ms as u64);
+            let timer = metrics.encode_seconds.start_timer();
+            let result = video_encoder.encode(frame, captured);
+            timer.observe_duration();
+            match result {
+                Ok(packets) => send_packets(packets, &video_sender, &metrics),
+                Err(e) => error!("could not encode frame: {e}"),
+            }
+        }
+        match video_encoder.flush() {
+            Ok(packets) => send_packets(packets, &video_sender, &metrics),
+            Err(e) => error!("could not flush encoder: {e}"),
         }
     })
 }
 
-fn encode_mjpeg(frame: &ImageBuffer<Rgb<u8>, Vec<u8>>, encoder: Encoder) -> VideoPacket {
-    let mut buf: Vec<u8> = Vec::new();
-    let mut jpeg_encoder = codecs::jpeg::JpegEncoder::new_with_quality(&mut buf, 80);
-    let _res = jpeg_encoder
-        .encode_image(frame)
-        .map_err(|e| error!("{:?}", e));
-    VideoPacket {
-        data: buf.clone(),
-        frameType: None,
-        epochTime: since_the_epoch(),
-        encoding: encoder.clone(),
+fn send_packets(
+    packets: Vec<VideoPacket>,
+    video_sender: &LatestSender<VideoPacket>,
+    metrics: &Metrics,
+) {
+    for packet in packets {
+        let frame_type = packet.frameType.as_deref().unwrap_or("key");
+        metrics
+            .frames_encoded
+            .with_label_values(&[frame_type])
+            .inc();
+        metrics.encoded_bytes.inc_by(packet.data.len() as u64);
+        let _ = video_sender.send(packet);
     }
 }
 
-fn encode_idk(
-    frame: &ImageBuffer<Rgb<u8>, Vec<u8>>,
-    encoder: Encoder,
-    context: &mut Context<u8>,
-    width: usize,
-) -> Result<VideoPacket> {
-    let mut r_slice: Vec<u8> = vec![];
-    let mut g_slice: Vec<u8> = vec![];
-    let mut b_slice: Vec<u8> = vec![];
-    for pixel in frame.pixels() {
-        let (r, g, b) = to_ycbcr(pixel);
-        r_slice.push(r);
-        g_slice.push(g);
-        b_slice.push(b);
-    }
-    let planes = vec![r_slice, g_slice, b_slice];
-    debug!("Creating new frame");
-    let mut frame = context.new_frame();
-    let encoding_time = Instant::now();
-    for (dst, src) in frame.planes.iter_mut().zip(planes) {
-        dst.copy_from_raw_u8(&src, width, 1);
-    }
-
-    context.send_frame(frame)?;
-    debug!("receiving encoded frame");
-    let pkt = context.receive_packet()?;
-    debug!("time encoding {:?}", encoding_time.elapsed());
-    debug!("read thread: base64 Encoding packet {}", pkt.input_frameno);
-    let frame_type = if pkt.frame_type == FrameType::KEY {
-        "key"
+/// Scales the frame to the target size unless it already is.
+fn fit_rgb(frame: RgbFrame, width: u32, height: u32) -> RgbFrame {
+    if frame.dimensions() == (width, height) {
+        frame
     } else {
-        "delta"
-    };
-    let data = pkt.data;
-    debug!("read thread: base64 Encoded packet {}", pkt.input_frameno);
-    let frame = VideoPacket {
-        data,
-        frameType: Some(frame_type.to_string()),
-        epochTime: since_the_epoch(),
-        encoding: encoder.clone(),
-    };
-    Ok(frame)
+        imageops::resize(&frame, width, height, imageops::FilterType::Triangle)
+    }
 }
 
-fn clamp(val: f32) -> u8 {
-    (val.round() as u8).clamp(0_u8, 255_u8)
+/// Puts the frame into `yuv` at the target size, converting from RGB only when the source
+/// didn't deliver YUV to begin with. Frames at the target size reuse the planes of `yuv`.
+fn fit_i420(
+    frame: Frame,
+    width: u32,
+    height: u32,
+    conversion: &Conversion,
+    yuv: &mut I420,
+) -> Result<()> {
+    match frame {
+        Frame::Yuv {
+            layout,
+            width: frame_width,
+            height: frame_height,
+            data,
+        } => {
+            let (frame_width, frame_height) = (frame_width as usize, frame_height as usize);
+            if (frame_width, frame_height) == (width as usize, height as usize) {
+                return yuv.convert_yuv(layout, &data, frame_width, frame_height);
+            }
+            // scaling allocates anyway, only while the rate controller shrinks the stream
+            let mut full = I420::default();
+            full.convert_yuv(layout, &data, frame_width, frame_height)?;
+            yuv.scale(&full, width as usize, height as usize);
+        }
+        Frame::Rgb(frame) => yuv.convert_rgb(&fit_rgb(frame, width, height), conversion),
+    }
+    Ok(())
 }
 
-fn to_ycbcr(pixel: &Rgb<u8>) -> (u8, u8, u8) {
-    let [r, g, b] = pixel.0;
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-    let y = 16_f32 + (65.481 * r as f32 + 128.553 * g as f32 + 24.966 * b as f32) / 255_f32;
-    let cb = 128_f32 + (-37.797 * r as f32 - 74.203 * g as f32 + 112.000 * b as f32) / 255_f32;
-    let cr = 128_f32 + (112.000 * r as f32 - 93.786 * g as f32 - 18.214 * b as f32) / 255_f32;
+    const INTERVAL: Duration = Duration::from_millis(500);
 
-    (clamp(y), clamp(cb), clamp(cr))
+    #[test]
+    fn first_request_is_honoured_at_once() {
+        let mut keyframes = KeyframeRequests::new(INTERVAL);
+        let now = Instant::now();
+        assert!(!keyframes.take_at(now));
+        keyframes.request();
+        assert!(keyframes.take_at(now));
+        assert!(!keyframes.take_at(now));
+    }
+
+    #[test]
+    fn early_requests_wait_for_the_interval() {
+        let mut keyframes = KeyframeRequests::new(INTERVAL);
+        let start = Instant::now();
+        keyframes.request();
+        assert!(keyframes.take_at(start));
+
+        // however many viewers ask in between, there is one keyframe once the interval is over
+        for _ in 0..3 {
+            keyframes.request();
+        }
+        assert!(!keyframes.take_at(start + INTERVAL / 2));
+        assert!(!keyframes.take_at(start + INTERVAL - Duration::from_millis(1)));
+        assert!(keyframes.take_at(start + INTERVAL));
+        assert!(!keyframes.take_at(start + INTERVAL * 3));
+    }
+
+    #[test]
+    fn natural_keyframes_answer_requests() {
+        let mut keyframes = KeyframeRequests::new(INTERVAL);
+        let start = Instant::now();
+        keyframes.request();
+        keyframes.keyframe_sent_at(start);
+        assert!(!keyframes.take_at(start + INTERVAL * 2));
+
+        // and restart the interval for the next request
+        keyframes.request();
+        assert!(!keyframes.take_at(start + INTERVAL / 2));
+        assert!(keyframes.take_at(start + INTERVAL));
+    }
 }
@@ -0,0 +1,164 @@
+use std::time::Instant;
+
+use rav1e::{
+    color::ChromaSampling,
+    config::SpeedSettings,
+    data::{FrameType, Rational},
+    prelude::{EncoderStatus, FrameParameters, FrameTypeOverride},
+    Config, Context, EncoderConfig, Packet,
+};
+
+use super::{fit_i420, Encoder, VideoEncoder};
+use crate::{
+    camera::{since_the_epoch, VideoPacket},
+    colorspace::{Conversion, I420},
+    config::EncodingConfig,
+    prelude::*,
+    rate::EncodeTarget,
+    source::Frame,
+};
+
+pub const CHROMA_SAMPLING: ChromaSampling = ChromaSampling::Cs420;
+
+/// AV1 seq_profile for the given chroma subsampling at 8 bit depth.
+pub fn av1_profile(chroma_sampling: ChromaSampling) -> u8 {
+    match chroma_sampling {
+        ChromaSampling::Cs420 | ChromaSampling::Cs400 => 0,
+        ChromaSampling::Cs444 => 1,
+        ChromaSampling::Cs422 => 2,
+    }
+}
+
+/// rav1e config for the target, a bitrate of 0 keeps the fixed quantizer.
+fn encoder_config(settings: &EncodingConfig, target: &EncodeTarget) -> Config {
+    let mut speed_settings = SpeedSettings::from_preset(settings.speed_preset);
+    speed_settings.rdo_lookahead_frames = 1;
+
+    let enc = EncoderConfig {
+        width: target.width,
+        height: target.height,
+        time_base: Rational::new(1, u64::from(target.framerate)),
+        bitrate: i32::try_from(target.bitrate_bps).unwrap_or(i32::MAX),
+        bit_depth: 8,
+        error_resilient: true,
+        min_key_frame_interval: settings.min_key_frame_interval,
+        max_key_frame_interval: settings.max_key_frame_interval,
+        low_latency: true,
+        min_quantizer: settings.min_quantizer,
+        quantizer: settings.quantizer,
+        still_picture: false,
+        tiles: settings.tiles,
+        chroma_sampling: CHROMA_SAMPLING,
+        color_description: Some(settings.color_matrix.color_description()),
+        pixel_range: settings.color_range.pixel_range(),
+        speed_settings,
+        ..Default::default()
+    };
+    Config::new()
+        .with_encoder_config(enc)
+        .with_threads(settings.threads)
+}
+
+/// rav1e, which can't change its bitrate on the fly, so every change starts a new context.
+pub struct Av1Encoder {
+    settings: EncodingConfig,
+    conversion: Conversion,
+    target: EncodeTarget,
+    context: Context<u8>,
+    yuv: I420,
+    force_keyframe: bool,
+}
+
+impl Av1Encoder {
+    pub fn new(settings: &EncodingConfig, target: &EncodeTarget) -> Result<Self> {
+        Ok(Self {
+            settings: settings.clone(),
+            conversion: Conversion::new(settings.color_matrix, settings.color_range),
+            target: *target,
+            context: encoder_config(settings, target).new_context()?,
+            yuv: I420::default(),
+            force_keyframe: false,
+        })
+    }
+}
+
+fn video_packet(packet: Packet<u8>, captured: Duration) -> VideoPacket {
+    debug!("encoded packet {}", packet.input_frameno);
+    let frame_type = if packet.frame_type == FrameType::KEY {
+        "key"
+    } else {
+        "delta"
+    };
+    VideoPacket {
+        data: packet.data,
+        frameType: Some(frame_type.to_string()),
+        epochTime: captured,
+        encoding: Encoder::AV1,
+    }
+}
+
+impl VideoEncoder for Av1Encoder {
+    fn configure(&mut self, target: &EncodeTarget) -> Result<()> {
+        self.context = encoder_config(&self.settings, target).new_context()?;
+        self.target = *target;
+        Ok(())
+    }
+
+    fn encode(&mut self, frame: Frame, captured: Duration) -> Result<Vec<VideoPacket>> {
+        let (width, height) = (self.target.width as u32, self.target.height as u32);
+        fit_i420(frame, width, height, &self.conversion, &mut self.yuv)?;
+
+        let mut frame = self.context.new_frame();
+        let encoding_time = Instant::now();
+        let planes = [
+            (&self.yuv.y, self.yuv.width),
+            (&self.yuv.u, self.yuv.chroma_width()),
+            (&self.yuv.v, self.yuv.chroma_width()),
+        ];
+        for (dst, (src, stride)) in frame.planes.iter_mut().zip(planes) {
+            dst.copy_from_raw_u8(src, stride, 1);
+        }
+
+        let params = FrameParameters {
+            frame_type_override: if std::mem::take(&mut self.force_keyframe) {
+                FrameTypeOverride::Key
+            } else {
+                FrameTypeOverride::No
+            },
+            ..Default::default()
+        };
+        self.context.send_frame((frame, params))?;
+        let packets = match self.context.receive_packet() {
+            Ok(packet) => vec![video_packet(packet, captured)],
+            Err(EncoderStatus::NeedMoreData | EncoderStatus::Encoded) => vec![],
+            Err(e) => return Err(e.into()),
+        };
+        debug!("time encoding {:?}", encoding_time.elapsed());
+        Ok(packets)
+    }
+
+    fn force_keyframe(&mut self) {
+        self.force_keyframe = true;
+    }
+
+    fn set_bitrate(&mut self, bitrate_bps: u32) -> Result<()> {
+        let target = EncodeTarget {
+            bitrate_bps,
+            ..self.target
+        };
+        self.configure(&target)
+    }
+
+    fn flush(&mut self) -> Result<Vec<VideoPacket>> {
+        self.context.flush();
+        let mut packets = vec![];
+        loop {
+            match self.context.receive_packet() {
+                Ok(packet) => packets.push(video_packet(packet, since_the_epoch())),
+                Err(EncoderStatus::Encoded) => {}
+                Err(EncoderStatus::LimitReached) => return Ok(packets),
+                Err(e) => return Err(e.into()),
+            }
+        }
+    }
+}
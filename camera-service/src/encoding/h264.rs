@@ -0,0 +1,124 @@
+use openh264::{
+    encoder::{EncoderConfig, FrameType},
+    formats::YUVSource,
+};
+
+use super::{bitrate_or_default, fit_i420, Encoder, VideoEncoder};
+use crate::{
+    camera::VideoPacket,
+    colorspace::{Conversion, I420},
+    config::EncodingConfig,
+    prelude::*,
+    rate::EncodeTarget,
+    source::Frame,
+};
+
+/// OpenH264, constrained baseline. Bitrate changes start a new encoder, whose first frame
+/// is an IDR.
+pub struct H264Encoder {
+    conversion: Conversion,
+    target: EncodeTarget,
+    /// `None` until the next frame starts a new encoder.
+    encoder: Option<openh264::encoder::Encoder>,
+    yuv: I420,
+}
+
+impl H264Encoder {
+    pub fn new(settings: &EncodingConfig, target: &EncodeTarget) -> Result<Self> {
+        Ok(Self {
+            conversion: Conversion::new(settings.color_matrix, settings.color_range),
+            target: *target,
+            encoder: None,
+            yuv: I420::default(),
+        })
+    }
+
+    fn start(&self) -> Result<openh264::encoder::Encoder> {
+        let config = EncoderConfig::new(self.target.width as u32, self.target.height as u32)
+            .set_bitrate_bps(bitrate_or_default(self.target.bitrate_bps))
+            .max_frame_rate(self.target.framerate as f32)
+            // the rate controller already drops frames, don't let OpenH264 skip more
+            .enable_skip_frame(false);
+        Ok(openh264::encoder::Encoder::with_config(config)?)
+    }
+}
+
+impl YUVSource for I420 {
+    fn width(&self) -> i32 {
+        self.width as i32
+    }
+
+    fn height(&self) -> i32 {
+        self.height as i32
+    }
+
+    fn y(&self) -> &[u8] {
+        &self.y
+    }
+
+    fn u(&self) -> &[u8] {
+        &self.u
+    }
+
+    fn v(&self) -> &[u8] {
+        &self.v
+    }
+
+    fn y_stride(&self) -> i32 {
+        self.width as i32
+    }
+
+    fn u_stride(&self) -> i32 {
+        self.chroma_width() as i32
+    }
+
+    fn v_stride(&self) -> i32 {
+        self.chroma_width() as i32
+    }
+}
+
+impl VideoEncoder for H264Encoder {
+    fn configure(&mut self, target: &EncodeTarget) -> Result<()> {
+        self.target = *target;
+        self.encoder = None;
+        Ok(())
+    }
+
+    fn encode(&mut self, frame: Frame, captured: Duration) -> Result<Vec<VideoPacket>> {
+        let (width, height) = (self.target.width as u32, self.target.height as u32);
+        fit_i420(frame, width, height, &self.conversion, &mut self.yuv)?;
+        if self.encoder.is_none() {
+            self.encoder = Some(self.start()?);
+        }
+        let encoder = self.encoder.as_mut().expect("started above");
+        let bitstream = encoder.encode(&self.yuv)?;
+        let frame_type = match bitstream.frame_type() {
+            FrameType::Skip | FrameType::Invalid => return Ok(vec![]),
+            FrameType::IDR | FrameType::I => "key",
+            _ => "delta",
+        };
+        Ok(vec![VideoPacket {
+            data: bitstream.to_vec(),
+            frameType: Some(frame_type.to_string()),
+            epochTime: captured,
+            encoding: Encoder::H264,
+        }])
+    }
+
+    fn force_keyframe(&mut self) {
+        if let Some(encoder) = &mut self.encoder {
+            encoder.force_intra_frame();
+        }
+    }
+
+    fn set_bitrate(&mut self, bitrate_bps: u32) -> Result<()> {
+        self.target.bitrate_bps = bitrate_bps;
+        self.encoder = None;
+        Ok(())
+    }
+
+    /// OpenH264 doesn't hold frames back, there is nothing left to flush.
+    fn flush(&mut self) -> Result<Vec<VideoPacket>> {
+        Ok(vec![])
+    }
+}
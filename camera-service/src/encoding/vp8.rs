@@ -0,0 +1,234 @@
+use std::{mem::MaybeUninit, os::raw::c_ulong, ptr, slice};
+
+use env_libvpx_sys::{
+    vpx_codec_ctx_t, vpx_codec_cx_pkt_kind, vpx_codec_destroy, vpx_codec_enc_cfg_t,
+    vpx_codec_enc_config_default, vpx_codec_enc_init_ver, vpx_codec_encode, vpx_codec_err_t,
+    vpx_codec_get_cx_data, vpx_codec_iter_t, vpx_codec_vp8_cx, vpx_image_t, vpx_img_fmt,
+    vpx_img_wrap, VPX_DL_REALTIME, VPX_EFLAG_FORCE_KF, VPX_ENCODER_ABI_VERSION,
+    VPX_ERROR_RESILIENT_DEFAULT, VPX_FRAME_IS_KEY,
+};
+
+use super::{bitrate_or_default, fit_i420, Encoder, VideoEncoder};
+use crate::{
+    camera::{since_the_epoch, VideoPacket},
+    colorspace::{Conversion, I420},
+    config::EncodingConfig,
+    prelude::*,
+    rate::EncodeTarget,
+    source::Frame,
+};
+
+fn check(result: vpx_codec_err_t, what: &str) -> Result<()> {
+    match result {
+        vpx_codec_err_t::VPX_CODEC_OK => Ok(()),
+        error => anyhow::bail!("{what} failed: {error:?}"),
+    }
+}
+
+/// A frame libvpx encoded.
+struct VpxFrame {
+    data: Vec<u8>,
+    key: bool,
+}
+
+/// libvpx's VP8 encoder through its C API, which takes a keyframe request as a flag
+/// on the frame it should apply to.
+struct Vpx {
+    ctx: vpx_codec_ctx_t,
+    width: u32,
+    height: u32,
+}
+
+impl Vpx {
+    fn new(target: &EncodeTarget) -> Result<Self> {
+        let (width, height) = (target.width as u32, target.height as u32);
+        // SAFETY: plain C structs, libvpx fills them in before they are read
+        let mut config: vpx_codec_enc_cfg_t = unsafe { MaybeUninit::zeroed().assume_init() };
+        let mut ctx: vpx_codec_ctx_t = unsafe { MaybeUninit::zeroed().assume_init() };
+        unsafe {
+            let iface = vpx_codec_vp8_cx();
+            check(
+                vpx_codec_enc_config_default(iface, &mut config, 0),
+                "VP8 encoder defaults",
+            )?;
+            config.g_w = width;
+            config.g_h = height;
+            // pts are capture times in milliseconds
+            config.g_timebase.num = 1;
+            config.g_timebase.den = 1000;
+            config.rc_target_bitrate = bitrate_or_default(target.bitrate_bps) / 1000;
+            config.g_threads = 8;
+            config.g_error_resilient = VPX_ERROR_RESILIENT_DEFAULT as _;
+            check(
+                vpx_codec_enc_init_ver(&mut ctx, iface, &config, 0, VPX_ENCODER_ABI_VERSION as i32),
+                "starting the VP8 encoder",
+            )?;
+        }
+        Ok(Self { ctx, width, height })
+    }
+
+    /// Encodes I420 planes, or flushes what libvpx holds back without them.
+    fn encode(&mut self, planes: Option<&[u8]>, pts: i64, keyframe: bool) -> Result<Vec<VpxFrame>> {
+        // SAFETY: filled in by vpx_img_wrap before it is passed on
+        let mut image: vpx_image_t = unsafe { MaybeUninit::zeroed().assume_init() };
+        let image = match planes {
+            Some(planes) => {
+                let (width, height) = (self.width as usize, self.height as usize);
+                let size = width * height + 2 * width.div_ceil(2) * height.div_ceil(2);
+                anyhow::ensure!(planes.len() >= size, "VP8 frame is missing planes");
+                // SAFETY: libvpx only reads the planes, which outlive the encode call below
+                let wrapped = unsafe {
+                    vpx_img_wrap(
+                        &mut image,
+                        vpx_img_fmt::VPX_IMG_FMT_I420,
+                        self.width,
+                        self.height,
+                        1,
+                        planes.as_ptr() as *mut u8,
+                    )
+                };
+                anyhow::ensure!(!wrapped.is_null(), "libvpx can't take the VP8 frame");
+                &image as *const vpx_image_t
+            }
+            None => ptr::null(),
+        };
+        let flags = if keyframe { VPX_EFLAG_FORCE_KF } else { 0 };
+        check(
+            unsafe {
+                vpx_codec_encode(
+                    &mut self.ctx,
+                    image,
+                    pts,
+                    1,
+                    flags as _,
+                    VPX_DL_REALTIME as c_ulong,
+                )
+            },
+            "VP8 encoding",
+        )?;
+
+        let mut frames = vec![];
+        let mut iter: vpx_codec_iter_t = ptr::null();
+        loop {
+            // SAFETY: packets stay valid until the next call into the encoder, they are
+            // copied out before that
+            unsafe {
+                let packet = vpx_codec_get_cx_data(&mut self.ctx, &mut iter);
+                if packet.is_null() {
+                    break;
+                }
+                if (*packet).kind != vpx_codec_cx_pkt_kind::VPX_CODEC_CX_FRAME_PKT {
+                    continue;
+                }
+                let frame = &(*packet).data.frame;
+                frames.push(VpxFrame {
+                    data: slice::from_raw_parts(frame.buf as *const u8, frame.sz as usize).to_vec(),
+                    key: frame.flags & VPX_FRAME_IS_KEY != 0,
+                });
+            }
+        }
+        Ok(frames)
+    }
+}
+
+impl Drop for Vpx {
+    fn drop(&mut self) {
+        // SAFETY: initialised in `new`, and never used again
+        unsafe {
+            vpx_codec_destroy(&mut self.ctx);
+        }
+    }
+}
+
+/// libvpx VP8. Keyframe requests flag the next frame, a new size or bitrate starts a new
+/// encoder, whose first frame is a keyframe.
+pub struct Vp8Encoder {
+    conversion: Conversion,
+    target: EncodeTarget,
+    /// `None` until the next frame starts a new encoder.
+    encoder: Option<Vpx>,
+    /// Whether the next frame is flagged as a keyframe.
+    force_keyframe: bool,
+    yuv: I420,
+    /// The three planes back to back, as libvpx takes them.
+    buf: Vec<u8>,
+}
+
+impl Vp8Encoder {
+    pub fn new(settings: &EncodingConfig, target: &EncodeTarget) -> Result<Self> {
+        Ok(Self {
+            conversion: Conversion::new(settings.color_matrix, settings.color_range),
+            target: *target,
+            encoder: None,
+            force_keyframe: false,
+            yuv: I420::default(),
+            buf: vec![],
+        })
+    }
+}
+
+fn video_packet(frame: VpxFrame, captured: Duration) -> VideoPacket {
+    VideoPacket {
+        data: frame.data,
+        frameType: Some(if frame.key { "key" } else { "delta" }.to_string()),
+        epochTime: captured,
+        encoding: Encoder::VP8,
+    }
+}
+
+impl VideoEncoder for Vp8Encoder {
+    fn configure(&mut self, target: &EncodeTarget) -> Result<()> {
+        self.target = *target;
+        self.encoder = None;
+        Ok(())
+    }
+
+    fn encode(&mut self, frame: Frame, captured: Duration) -> Result<Vec<VideoPacket>> {
+        let (width, height) = (self.target.width as u32, self.target.height as u32);
+        fit_i420(frame, width, height, &self.conversion, &mut self.yuv)?;
+        self.buf.clear();
+        self.buf.extend_from_slice(&self.yuv.y);
+        self.buf.extend_from_slice(&self.yuv.u);
+        self.buf.extend_from_slice(&self.yuv.v);
+
+        if self.encoder.is_none() {
+            self.encoder = Some(Vpx::new(&self.target)?);
+        }
+        let encoder = self.encoder.as_mut().expect("started above");
+        let keyframe = std::mem::take(&mut self.force_keyframe);
+        let frames = encoder.encode(Some(&self.buf), captured.as_millis() as i64, keyframe)?;
+        Ok(frames
+            .into_iter()
+            .map(|frame| video_packet(frame, captured))
+            .collect())
+    }
+
+    fn force_keyframe(&mut self) {
+        self.force_keyframe = true;
+    }
+
+    fn set_bitrate(&mut self, bitrate_bps: u32) -> Result<()> {
+        self.target.bitrate_bps = bitrate_bps;
+        self.encoder = None;
+        Ok(())
+    }
+
+    fn flush(&mut self) -> Result<Vec<VideoPacket>> {
+        let Some(mut encoder) = self.encoder.take() else {
+            return Ok(vec![]);
+        };
+        let mut packets = vec![];
+        loop {
+            let frames = encoder.encode(None, -1, false)?;
+            if frames.is_empty() {
+                return Ok(packets);
+            }
+            let captured = since_the_epoch();
+            packets.extend(
+                frames
+                    .into_iter()
+                    .map(|frame| video_packet(frame, captured)),
+            );
+        }
+    }
+}
@@ -0,0 +1,4 @@
+//! The parts of camera-service that stand on their own, as a library so the benchmarks can
+//! link them.
+
+pub mod colorspace;
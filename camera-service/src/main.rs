@@ -1,26 +1,37 @@
 #[macro_use]
 extern crate log;
 
-use camera::{fps_thread, VideoPacket};
-use connection::{ConnState, WebRtcEnumCommand};
-use encoding::encoder_config;
-use encoding::{encoder_thread, Encoder};
+use camera::VideoPacket;
+use camera_service::colorspace;
+use clap::Parser;
+use config::{Cli, ServiceConfig};
+use connection::{ConnState, ConnectionHandles, WebRtcEnumCommand};
+use control::{control_thread, ControlEvent, SimulatedActuator};
+use encoding::{encoder_thread, EncoderHandles};
 use log::SetLoggerError;
+use metrics::Metrics;
 use nokhwa::utils::ApiBackend;
 use prelude::*;
-use signaling::WebSocketCommand;
+use protocol::{TankId, TankInfo};
+use rate::{RateController, RateFeedback};
+use signaling::{SignalingState, WebSocketCommand};
 use simplelog::*;
-use std::env;
-use std::str::FromStr;
+use source::SourceKind;
+use std::collections::BTreeMap;
 
 pub mod camera;
+pub mod config;
 pub mod connection;
+pub mod control;
 pub mod encoding;
+pub mod metrics;
+pub mod pipeline;
+pub mod rate;
 pub mod signaling;
+pub mod source;
+pub mod tls;
 
-pub use camera::camera_thread;
-
-static THRESHOLD_MILLIS: u128 = 1000;
+pub use camera::{camera_thread, CameraHandles};
 
 fn setup_logging() -> Result<(), SetLoggerError> {
     CombinedLogger::init(vec![TermLogger::new(
@@ -31,54 +42,141 @@ fn setup_logging() -> Result<(), SetLoggerError> {
 }
 #[tokio::main]
 async fn main() -> Result<()> {
+    let cli = Cli::parse();
+    let service_config = ServiceConfig::load(&cli)?;
+    if cli.print_config {
+        print!("{}", service_config.to_toml()?);
+        return Ok(());
+    }
     setup_logging()?;
-    let width = 720;
-    let height = 480;
-    let video_device_index: usize = env::var("VIDEO_DEVICE_INDEX")
-        .ok()
-        .and_then(|n| n.parse::<usize>().ok())
-        .unwrap_or(0);
-    let framerate: u32 = env::var("FRAMERATE")
-        .ok()
-        .and_then(|n| n.parse::<u32>().ok())
-        .unwrap_or(10u32);
-    let encoder = env::var("ENCODER")
-        .ok()
-        .and_then(|o| Encoder::from_str(o.as_ref()).ok())
-        .unwrap_or(Encoder::AV1);
+
+    let width = service_config.capture.width as usize;
+    let height = service_config.capture.height as usize;
+    let framerate = service_config.capture.framerate;
+    let encoder = service_config.encoding.encoder.clone();
+    let source = service_config.source()?;
 
     warn!("Framerate {framerate}");
 
-    let config = encoder_config(width, height);
+    let codec = encoder.codec_capability()?;
+
+    let rate = RateController::new(service_config.rate.clone(), width, height, framerate);
     let client_counter = Arc::new(Mutex::new(ConnState::NotConnected));
 
     let (soc_cmd_tx, soc_cmd_rx) = mpsc::channel::<WebSocketCommand>();
     let (rtc_cmd_tx, rtc_cmd_rx) = mpsc::channel::<WebRtcEnumCommand>();
-    let (fps_tx, fps_rx) = mpsc::channel::<u128>();
-    let (cam_tx, cam_rx) = mpsc::channel::<CameraPacket>();
-    let (vid_tx, vid_rx) = mpsc::channel::<VideoPacket>();
-
-    let devices = nokhwa::query(ApiBackend::Video4Linux)?;
-    info!("available cameras: {:?}", devices);
-
-    let fps_thread = fps_thread(fps_rx);
+    let metrics = Arc::new(Metrics::new()?);
+    // one frame between stages, a stage that falls behind skips to the newest
+    let (cam_tx, cam_rx) = pipeline::latest::<CameraPacket>(metrics.queue("encode"));
+    let (vid_tx, vid_rx) = pipeline::latest::<VideoPacket>(metrics.queue("send"));
+    let (control_tx, control_rx) = mpsc::channel::<ControlEvent>();
+    let (rate_tx, rate_rx) = mpsc::channel::<RateFeedback>();
+    let (keyframe_tx, keyframe_rx) = mpsc::channel::<u64>();
+
+    let mut hardware = BTreeMap::new();
+    hardware.insert("source".to_owned(), format!("{source:?}"));
+    hardware.insert("encoder".to_owned(), format!("{encoder:?}"));
+    hardware.insert("resolution".to_owned(), format!("{width}x{height}"));
+    hardware.insert("framerate".to_owned(), framerate.to_string());
+
+    if let SourceKind::Camera(index) = source {
+        let devices = nokhwa::query(ApiBackend::Video4Linux)?;
+        info!("available cameras: {:?}", devices);
+        if let Some(camera) = devices.get(index as usize) {
+            hardware.insert("camera".to_owned(), camera.human_name());
+        }
+    }
 
-    let camera_thread = camera_thread(client_counter.clone(), video_device_index as u32, cam_tx);
+    let tank_info = TankInfo {
+        id: TankId::new(service_config.identity.id.clone()),
+        name: service_config.tank_name(),
+        hardware,
+    };
 
-    let encoder_thread = encoder_thread(fps_tx, cam_rx, vid_tx, encoder, config, width);
+    if service_config.metrics.enabled {
+        metrics::serve(metrics.clone(), service_config.metrics.listen)?;
+    }
 
-    let _ =
-        connection::init_connection(client_counter, vid_rx, rtc_cmd_rx, soc_cmd_tx.clone()).await;
-    let signaling_result = signaling::socket_cmd_thread(soc_cmd_rx, rtc_cmd_tx).await;
+    let camera_thread = camera_thread(
+        CameraHandles {
+            client_counter: client_counter.clone(),
+            cam_tx,
+            metrics: metrics.clone(),
+        },
+        source,
+        width as u32,
+        height as u32,
+        framerate,
+    );
+
+    let control_thread = control_thread(
+        control_rx,
+        Box::new(SimulatedActuator::default()),
+        service_config.deadman_timeout(),
+        soc_cmd_tx.clone(),
+    );
+
+    let encoder_thread = encoder_thread(
+        EncoderHandles {
+            cam_rx,
+            video_sender: vid_tx,
+            rate_rx,
+            keyframe_rx,
+            metrics: metrics.clone(),
+        },
+        encoder,
+        service_config.encoding.clone(),
+        rate,
+        service_config.max_frame_age(),
+    );
+
+    let _ = connection::init_connection(
+        codec,
+        &service_config.ice.servers,
+        ConnectionHandles {
+            counter: client_counter,
+            frame_receiver: vid_rx,
+            webrtc_cmd_receiver: rtc_cmd_rx,
+            ws_sender: soc_cmd_tx.clone(),
+            control_sender: control_tx.clone(),
+            rate_sender: rate_tx,
+            keyframe_sender: keyframe_tx,
+            metrics: metrics.clone(),
+        },
+        framerate,
+        service_config.max_frame_age(),
+    )
+    .await;
+    let signaling_control_tx = control_tx.clone();
+    let signaling_result = signaling::socket_cmd_thread(
+        soc_cmd_rx,
+        rtc_cmd_tx,
+        control_tx,
+        tank_info,
+        service_config.identity.token.clone(),
+        tls::connector(service_config.signaling.ca_bundle.as_deref())?,
+        move |state| {
+            info!("signaling is {:?}", state);
+            // Connected comes with the server's LoginResponse, a socket that is still
+            // waiting for it (or got a LoginError) doesn't count
+            metrics
+                .signaling_connected
+                .set(i64::from(state == SignalingState::Connected));
+            if state == SignalingState::Disconnected {
+                // the server forgets our sessions with us, nobody holds the lease anymore
+                let _ = signaling_control_tx.send(ControlEvent::ControllerChanged(None));
+            }
+        },
+    )
+    .await;
 
-    const CONNECTION: &str = "ws://127.0.0.1:9002";
     let _ = soc_cmd_tx.send(WebSocketCommand::ConnectToSignalServer(
-        CONNECTION.to_owned(),
+        service_config.signaling.url.clone(),
     ));
 
     encoder_thread.join().unwrap();
-    fps_thread.join().unwrap();
     camera_thread.join().unwrap();
+    control_thread.join().unwrap();
 
     if let Ok(task_set) = signaling_result {
         task_set.join_all().await;
@@ -88,7 +186,6 @@ async fn main() -> Result<()> {
 
 pub mod prelude {
     pub use anyhow::Result;
-    use image::{ImageBuffer, Rgb};
 
     pub use std::sync::mpsc;
     pub use std::sync::mpsc::{channel, Receiver, Sender};
@@ -99,8 +196,8 @@ pub mod prelude {
         time::{Duration, SystemTime, UNIX_EPOCH},
     };
 
-    use crate::connection::ConnState;
+    use crate::{connection::ConnState, source::Frame};
 
     pub type ConnectionState = Arc<Mutex<ConnState>>;
-    pub type CameraPacket = (ImageBuffer<Rgb<u8>, Vec<u8>>, u128);
+    pub type CameraPacket = (Frame, u128);
 }
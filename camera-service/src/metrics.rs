@@ -0,0 +1,349 @@
+use std::net::SocketAddr;
+
+use prometheus::{
+    exponential_buckets, Encoder as _, GaugeVec, Histogram, HistogramOpts, IntCounter,
+    IntCounterVec, IntGauge, IntGaugeVec, Opts, Registry, TextEncoder,
+};
+use warp::{
+    http::{header::CONTENT_TYPE, Response, StatusCode},
+    Filter,
+};
+use webrtc::{
+    peer_connection::peer_connection_state::RTCPeerConnectionState,
+    rtcp::{packet::Packet, receiver_report::ReceiverReport},
+};
+
+use crate::{camera::since_the_epoch, prelude::*};
+
+/// RTP clock rate of every video codec, which receiver reports count jitter in.
+const VIDEO_CLOCK_RATE: f64 = 90000.0;
+/// Seconds between the NTP epoch (1900) and the Unix epoch.
+const NTP_UNIX_OFFSET: u64 = 2_208_988_800;
+
+/// Everything the pipeline reports, served in the Prometheus text format on `/metrics`.
+pub struct Metrics {
+    registry: Registry,
+    pub frames_captured: IntCounter,
+    /// By frame type, `key` or `delta`.
+    pub frames_encoded: IntCounterVec,
+    /// By the stage that threw them away, `capture`, `stale` or `send`.
+    frames_dropped: IntCounterVec,
+    /// Converting and encoding one frame.
+    pub encode_seconds: Histogram,
+    pub encoded_bytes: IntCounter,
+    /// What the rate controller asks of the encoder, 0 while it encodes at a fixed quantizer.
+    pub target_bitrate: IntGauge,
+    /// Frames waiting between two stages, by the stage that takes them.
+    queue_depth: IntGaugeVec,
+    rtcp_fraction_lost: GaugeVec,
+    rtcp_jitter: GaugeVec,
+    rtcp_rtt: GaugeVec,
+    /// Peers by their state, closed ones are no longer counted.
+    peer_connections: IntGaugeVec,
+    /// Set once the signaling server accepted the login, not merely when the socket opened.
+    pub signaling_connected: IntGauge,
+}
+
+impl Metrics {
+    pub fn new() -> Result<Self> {
+        let registry = Registry::new_custom(Some("camera".to_owned()), None)?;
+        let metrics = Self {
+            frames_captured: IntCounter::new(
+                "frames_captured_total",
+                "Frames delivered by the video source",
+            )?,
+            frames_encoded: IntCounterVec::new(
+                Opts::new("frames_encoded_total", "Frames the encoder produced"),
+                &["type"],
+            )?,
+            frames_dropped: IntCounterVec::new(
+                Opts::new("frames_dropped_total", "Frames thrown away before sending"),
+                &["reason"],
+            )?,
+            encode_seconds: Histogram::with_opts(
+                HistogramOpts::new(
+                    "encode_seconds",
+                    "Time spent converting and encoding a frame",
+                )
+                // 1 ms to half a second
+                .buckets(exponential_buckets(0.001, 2.0, 10)?),
+            )?,
+            encoded_bytes: IntCounter::new("encoded_bytes_total", "Size of the encoded frames")?,
+            target_bitrate: IntGauge::new(
+                "target_bitrate_bps",
+                "Bitrate the encoder is asked for, 0 for the fixed quantizer",
+            )?,
+            queue_depth: IntGaugeVec::new(
+                Opts::new("queue_depth", "Frames waiting for the next stage"),
+                &["stage"],
+            )?,
+            rtcp_fraction_lost: GaugeVec::new(
+                Opts::new(
+                    "rtcp_fraction_lost",
+                    "Share of packets lost according to the last receiver report",
+                ),
+                &["peer"],
+            )?,
+            rtcp_jitter: GaugeVec::new(
+                Opts::new(
+                    "rtcp_jitter_seconds",
+                    "Interarrival jitter according to the last receiver report",
+                ),
+                &["peer"],
+            )?,
+            rtcp_rtt: GaugeVec::new(
+                Opts::new(
+                    "rtcp_rtt_seconds",
+                    "Round trip time from the last receiver report",
+                ),
+                &["peer"],
+            )?,
+            peer_connections: IntGaugeVec::new(
+                Opts::new("peer_connections", "Peer connections by state"),
+                &["state"],
+            )?,
+            signaling_connected: IntGauge::new(
+                "signaling_connected",
+                "1 while logged in to the signaling server",
+            )?,
+            registry,
+        };
+        metrics.register()?;
+        Ok(metrics)
+    }
+
+    fn register(&self) -> Result<()> {
+        let collectors: [Box<dyn prometheus::core::Collector>; 12] = [
+            Box::new(self.frames_captured.clone()),
+            Box::new(self.frames_encoded.clone()),
+            Box::new(self.frames_dropped.clone()),
+            Box::new(self.encode_seconds.clone()),
+            Box::new(self.encoded_bytes.clone()),
+            Box::new(self.target_bitrate.clone()),
+            Box::new(self.queue_depth.clone()),
+            Box::new(self.rtcp_fraction_lost.clone()),
+            Box::new(self.rtcp_jitter.clone()),
+            Box::new(self.rtcp_rtt.clone()),
+            Box::new(self.peer_connections.clone()),
+            Box::new(self.signaling_connected.clone()),
+        ];
+        for collector in collectors {
+            self.registry.register(collector)?;
+        }
+        Ok(())
+    }
+
+    /// Depth of the slot in front of `stage`, see [`crate::pipeline::latest`].
+    pub fn queue(&self, stage: &str) -> IntGauge {
+        self.queue_depth.with_label_values(&[stage])
+    }
+
+    pub fn dropped(&self, reason: &str, frames: u64) {
+        self.frames_dropped
+            .with_label_values(&[reason])
+            .inc_by(frames);
+    }
+
+    /// Records loss, jitter and round trip time from a peer's receiver reports.
+    pub fn observe_rtcp(&self, peer: u64, packet: &(dyn Packet + Send + Sync)) {
+        self.observe_rtcp_at(peer, packet, ntp_middle_bits(since_the_epoch()));
+    }
+
+    /// [`Self::observe_rtcp`] with the packet arriving at `now`, in the middle bits of NTP time.
+    fn observe_rtcp_at(&self, peer: u64, packet: &(dyn Packet + Send + Sync), now: u32) {
+        let Some(report) = packet.as_any().downcast_ref::<ReceiverReport>() else {
+            return;
+        };
+        let peer = peer.to_string();
+        for reception in &report.reports {
+            self.rtcp_fraction_lost
+                .with_label_values(&[&peer])
+                .set(f64::from(reception.fraction_lost) / 256.0);
+            self.rtcp_jitter
+                .with_label_values(&[&peer])
+                .set(f64::from(reception.jitter) / VIDEO_CLOCK_RATE);
+            // without a sender report to refer to there is nothing to measure against
+            if reception.last_sender_report == 0 {
+                continue;
+            }
+            // all three are the middle 32 bits of NTP time, 1/65536 s units
+            let rtt = now
+                .wrapping_sub(reception.last_sender_report)
+                .wrapping_sub(reception.delay);
+            // a report from before a clock step comes out negative
+            if (rtt as i32) >= 0 {
+                self.rtcp_rtt
+                    .with_label_values(&[&peer])
+                    .set(f64::from(rtt) / 65536.0);
+            }
+        }
+    }
+
+    /// Forgets a peer's RTCP figures once it is gone.
+    pub fn peer_closed(&self, peer: u64) {
+        let peer = peer.to_string();
+        for gauge in [&self.rtcp_fraction_lost, &self.rtcp_jitter, &self.rtcp_rtt] {
+            let _ = gauge.remove_label_values(&[&peer]);
+        }
+    }
+
+    /// Moves a peer from the state it was in to its new one.
+    pub fn peer_state(&self, from: Option<RTCPeerConnectionState>, to: RTCPeerConnectionState) {
+        if let Some(from) = from.filter(|s| *s != RTCPeerConnectionState::Closed) {
+            self.peer_connections
+                .with_label_values(&[&from.to_string()])
+                .dec();
+        }
+        if to != RTCPeerConnectionState::Closed {
+            self.peer_connections
+                .with_label_values(&[&to.to_string()])
+                .inc();
+        }
+    }
+
+    fn render(&self) -> Result<String> {
+        let mut text = vec![];
+        TextEncoder::new().encode(&self.registry.gather(), &mut text)?;
+        Ok(String::from_utf8(text)?)
+    }
+}
+
+/// The middle 32 bits of the NTP timestamp of `now` since the Unix epoch, as RTCP reports
+/// round trips in.
+fn ntp_middle_bits(now: Duration) -> u32 {
+    let seconds = now.as_secs() + NTP_UNIX_OFFSET;
+    let fraction = (u64::from(now.subsec_nanos()) << 16) / 1_000_000_000;
+    ((seconds << 16) | fraction) as u32
+}
+
+/// Serves the metrics on `http://<listen>/metrics` until the runtime stops.
+pub fn serve(metrics: Arc<Metrics>, listen: SocketAddr) -> Result<()> {
+    let route = warp::path("metrics")
+        .and(warp::path::end())
+        .and(warp::get())
+        .map(move || {
+            let (status, body) = match metrics.render() {
+                Ok(text) => (StatusCode::OK, text),
+                Err(e) => {
+                    error!("could not render metrics: {e}");
+                    (StatusCode::INTERNAL_SERVER_ERROR, e.to_string())
+                }
+            };
+            Response::builder()
+                .status(status)
+                .header(CONTENT_TYPE, prometheus::TEXT_FORMAT)
+                .body(body)
+        });
+    let (address, server) = warp::serve(route).try_bind_ephemeral(listen)?;
+    info!("serving metrics on http://{address}/metrics");
+    tokio::spawn(server);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use webrtc::rtcp::reception_report::ReceptionReport;
+
+    use super::*;
+
+    const PEER: u64 = 3;
+
+    /// 1.5 s, in the 1/65536 s units of the middle bits of NTP time.
+    const NOW: u32 = 0x0001_8000;
+
+    fn report(reception: ReceptionReport) -> ReceiverReport {
+        ReceiverReport {
+            ssrc: 1,
+            reports: vec![ReceptionReport {
+                ssrc: 2,
+                ..reception
+            }],
+            ..Default::default()
+        }
+    }
+
+    fn rtt(metrics: &Metrics) -> f64 {
+        metrics
+            .rtcp_rtt
+            .with_label_values(&[&PEER.to_string()])
+            .get()
+    }
+
+    #[test]
+    fn ntp_time() {
+        // 2208988800 s is 0x83AA7E80, the middle bits keep its low half and the top of the fraction
+        assert_eq!(ntp_middle_bits(Duration::from_millis(500)), 0x7E80_8000);
+        assert_eq!(ntp_middle_bits(Duration::from_secs(1)), 0x7E81_0000);
+    }
+
+    #[test]
+    fn receiver_reports() {
+        let metrics = Metrics::new().unwrap();
+        // sent at 1 s, held back for 0.25 s before the report was sent, back at 1.5 s
+        let reception = ReceptionReport {
+            fraction_lost: 64,
+            jitter: 900,
+            last_sender_report: 0x0001_0000,
+            delay: 0x0000_4000,
+            ..Default::default()
+        };
+        metrics.observe_rtcp_at(PEER, &report(reception), NOW);
+        let peer = PEER.to_string();
+        assert_eq!(
+            metrics.rtcp_fraction_lost.with_label_values(&[&peer]).get(),
+            0.25
+        );
+        assert_eq!(metrics.rtcp_jitter.with_label_values(&[&peer]).get(), 0.01);
+        assert_eq!(rtt(&metrics), 0.25);
+
+        // no sender report to refer to yet, and one from after the report arrived
+        for last_sender_report in [0, 0x0002_0000] {
+            let reception = ReceptionReport {
+                last_sender_report,
+                delay: 0x0000_4000,
+                ..Default::default()
+            };
+            metrics.observe_rtcp_at(PEER, &report(reception), NOW + 0x8000);
+            assert_eq!(rtt(&metrics), 0.25);
+        }
+
+        metrics.peer_closed(PEER);
+        assert!(!metrics.render().unwrap().contains("rtcp_rtt_seconds{"));
+    }
+
+    #[test]
+    fn peers_by_state() {
+        use RTCPeerConnectionState::*;
+
+        let metrics = Metrics::new().unwrap();
+        let peers = |state: RTCPeerConnectionState| {
+            metrics
+                .peer_connections
+                .with_label_values(&[&state.to_string()])
+                .get()
+        };
+        metrics.peer_state(None, New);
+        metrics.peer_state(None, New);
+        assert_eq!(peers(New), 2);
+
+        for (from, to) in [(New, Connecting), (Connecting, Connected)] {
+            metrics.peer_state(Some(from), to);
+        }
+        assert_eq!((peers(New), peers(Connecting), peers(Connected)), (1, 0, 1));
+
+        metrics.peer_state(Some(Connected), Disconnected);
+        metrics.peer_state(Some(New), Failed);
+        assert_eq!(
+            (peers(Connected), peers(Disconnected), peers(Failed)),
+            (0, 1, 1)
+        );
+
+        // closed peers leave the count, and aren't taken off it twice
+        metrics.peer_state(Some(Disconnected), Closed);
+        metrics.peer_state(Some(Failed), Closed);
+        metrics.peer_state(Some(Closed), Closed);
+        for state in [New, Connecting, Connected, Disconnected, Failed, Closed] {
+            assert_eq!(peers(state), 0, "{state}");
+        }
+    }
+}
@@ -0,0 +1,244 @@
+use std::sync::{
+    atomic::{AtomicU64, Ordering},
+    mpsc::{RecvError, TryRecvError},
+    Condvar,
+};
+
+use prometheus::IntGauge;
+use tokio::sync::Notify;
+
+use crate::prelude::*;
+
+/// A single slot between two pipeline stages. Sending replaces whatever the receiver hasn't
+/// taken yet, so a stage that falls behind gets the freshest frame instead of a backlog.
+/// `depth` follows whether a value is waiting.
+pub fn latest<T>(depth: IntGauge) -> (LatestSender<T>, LatestReceiver<T>) {
+    let shared = Arc::new(Shared {
+        slot: Mutex::new(Slot {
+            value: None,
+            skipped: 0,
+            sender_alive: true,
+            receiver_alive: true,
+        }),
+        ready: Condvar::new(),
+        ready_async: Notify::new(),
+        depth,
+    });
+    (
+        LatestSender {
+            shared: shared.clone(),
+        },
+        LatestReceiver {
+            shared,
+            skipped: AtomicU64::new(0),
+        },
+    )
+}
+
+struct Slot<T> {
+    value: Option<T>,
+    /// Values replaced since the receiver last took one.
+    skipped: u64,
+    sender_alive: bool,
+    receiver_alive: bool,
+}
+
+struct Shared<T> {
+    slot: Mutex<Slot<T>>,
+    ready: Condvar,
+    /// Wakes a receiver waiting in [`LatestReceiver::recv_async`].
+    ready_async: Notify,
+    depth: IntGauge,
+}
+
+pub struct LatestSender<T> {
+    shared: Arc<Shared<T>>,
+}
+
+impl<T> LatestSender<T> {
+    /// Hands the value back once the receiver is gone.
+    pub fn send(&self, value: T) -> Result<(), T> {
+        let mut slot = self.shared.slot.lock().unwrap();
+        if !slot.receiver_alive {
+            return Err(value);
+        }
+        if slot.value.replace(value).is_some() {
+            slot.skipped += 1;
+        }
+        self.shared.depth.set(1);
+        self.shared.ready.notify_one();
+        self.shared.ready_async.notify_one();
+        Ok(())
+    }
+}
+
+impl<T> Drop for LatestSender<T> {
+    fn drop(&mut self) {
+        self.shared.slot.lock().unwrap().sender_alive = false;
+        self.shared.ready.notify_one();
+        self.shared.ready_async.notify_one();
+    }
+}
+
+pub struct LatestReceiver<T> {
+    shared: Arc<Shared<T>>,
+    skipped: AtomicU64,
+}
+
+impl<T> LatestReceiver<T> {
+    /// Blocks until there is a value, fails once the sender is gone and the slot is empty.
+    pub fn recv(&self) -> Result<T, RecvError> {
+        let mut slot = self.shared.slot.lock().unwrap();
+        loop {
+            if let Some(value) = self.take(&mut slot) {
+                return Ok(value);
+            }
+            if !slot.sender_alive {
+                return Err(RecvError);
+            }
+            slot = self.shared.ready.wait(slot).unwrap();
+        }
+    }
+
+    /// Like [`Self::recv`], but waits without blocking the runtime's thread.
+    pub async fn recv_async(&self) -> Result<T, RecvError> {
+        loop {
+            // a send between `try_recv` and awaiting leaves a permit, it isn't missed
+            let ready = self.shared.ready_async.notified();
+            match self.try_recv() {
+                Ok(value) => return Ok(value),
+                Err(TryRecvError::Disconnected) => return Err(RecvError),
+                Err(TryRecvError::Empty) => ready.await,
+            }
+        }
+    }
+
+    pub fn try_recv(&self) -> Result<T, TryRecvError> {
+        let mut slot = self.shared.slot.lock().unwrap();
+        match self.take(&mut slot) {
+            Some(value) => Ok(value),
+            None if slot.sender_alive => Err(TryRecvError::Empty),
+            None => Err(TryRecvError::Disconnected),
+        }
+    }
+
+    /// How many values were replaced before the one last received.
+    pub fn skipped(&self) -> u64 {
+        self.skipped.load(Ordering::Relaxed)
+    }
+
+    fn take(&self, slot: &mut Slot<T>) -> Option<T> {
+        let value = slot.value.take()?;
+        self.skipped
+            .store(std::mem::take(&mut slot.skipped), Ordering::Relaxed);
+        self.shared.depth.set(0);
+        Some(value)
+    }
+}
+
+impl<T> Drop for LatestReceiver<T> {
+    fn drop(&mut self) {
+        let mut slot = self.shared.slot.lock().unwrap();
+        slot.receiver_alive = false;
+        slot.value = None;
+        self.shared.depth.set(0);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Long enough for the receiver to be waiting before the sender acts.
+    const SETTLE: Duration = Duration::from_millis(50);
+
+    fn slot() -> (LatestSender<u32>, LatestReceiver<u32>, IntGauge) {
+        let depth = IntGauge::new("depth", "values waiting").unwrap();
+        let (tx, rx) = latest(depth.clone());
+        (tx, rx, depth)
+    }
+
+    /// Blocks in [`LatestReceiver::recv`] on a thread of its own, which reports what it got.
+    fn recv_on_a_thread(rx: LatestReceiver<u32>) -> Receiver<Result<u32, RecvError>> {
+        let (result_tx, result_rx) = channel();
+        thread::spawn(move || result_tx.send(rx.recv()));
+        result_rx
+    }
+
+    #[test]
+    fn overwritten_values_are_skipped() {
+        let (tx, rx, depth) = slot();
+        for value in 1..=3 {
+            tx.send(value).unwrap();
+        }
+        assert_eq!(depth.get(), 1);
+        assert_eq!(rx.recv(), Ok(3));
+        assert_eq!(rx.skipped(), 2);
+        assert_eq!(depth.get(), 0);
+        assert_eq!(rx.try_recv(), Err(TryRecvError::Empty));
+
+        tx.send(4).unwrap();
+        assert_eq!(rx.recv(), Ok(4));
+        assert_eq!(rx.skipped(), 0);
+    }
+
+    #[test]
+    fn recv_wakes_on_send() {
+        let (tx, rx, _) = slot();
+        let waiting = recv_on_a_thread(rx);
+        thread::sleep(SETTLE);
+        assert!(waiting.try_recv().is_err());
+        tx.send(7).unwrap();
+        assert_eq!(waiting.recv_timeout(Duration::from_secs(1)), Ok(Ok(7)));
+    }
+
+    #[tokio::test]
+    async fn recv_async_wakes_on_send() {
+        let (tx, rx, _) = slot();
+        let waiting = tokio::spawn(async move { rx.recv_async().await });
+        tokio::time::sleep(SETTLE).await;
+        assert!(!waiting.is_finished());
+        tx.send(7).unwrap();
+        let received = tokio::time::timeout(Duration::from_secs(1), waiting).await;
+        assert_eq!(received.unwrap().unwrap(), Ok(7));
+    }
+
+    #[test]
+    fn dropping_the_sender_ends_recv() {
+        let (tx, rx, _) = slot();
+        // what was sent before still arrives
+        tx.send(1).unwrap();
+        drop(tx);
+        assert_eq!(rx.recv(), Ok(1));
+        assert_eq!(rx.recv(), Err(RecvError));
+        assert_eq!(rx.try_recv(), Err(TryRecvError::Disconnected));
+
+        let (tx, rx, _) = slot();
+        let waiting = recv_on_a_thread(rx);
+        thread::sleep(SETTLE);
+        drop(tx);
+        assert_eq!(
+            waiting.recv_timeout(Duration::from_secs(1)),
+            Ok(Err(RecvError))
+        );
+    }
+
+    #[tokio::test]
+    async fn dropping_the_sender_ends_recv_async() {
+        let (tx, rx, _) = slot();
+        let waiting = tokio::spawn(async move { rx.recv_async().await });
+        tokio::time::sleep(SETTLE).await;
+        drop(tx);
+        let received = tokio::time::timeout(Duration::from_secs(1), waiting).await;
+        assert_eq!(received.unwrap().unwrap(), Err(RecvError));
+    }
+
+    #[test]
+    fn sending_without_a_receiver_hands_the_value_back() {
+        let (tx, rx, depth) = slot();
+        tx.send(1).unwrap();
+        drop(rx);
+        assert_eq!(depth.get(), 0);
+        assert_eq!(tx.send(2), Err(2));
+    }
+}
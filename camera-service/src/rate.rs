@@ -0,0 +1,591 @@
+use std::{collections::HashMap, time::Instant};
+
+use webrtc::rtcp::{
+    packet::Packet,
+    payload_feedbacks::receiver_estimated_maximum_bitrate::ReceiverEstimatedMaximumBitrate,
+    receiver_report::ReceiverReport,
+    transport_feedbacks::transport_layer_cc::{PacketStatusChunk, SymbolTypeTcc, TransportLayerCc},
+};
+
+use crate::{config::RateConfig, prelude::*};
+
+/// Above this loss the link is congested and the bitrate backs off.
+const HIGH_LOSS: f32 = 0.10;
+/// Below this loss the link has room and the bitrate probes upwards.
+const LOW_LOSS: f32 = 0.02;
+const INCREASE: f64 = 1.08;
+/// Receivers report several times a second, react to a few of them at most.
+const DECREASE_INTERVAL: Duration = Duration::from_millis(300);
+const INCREASE_INTERVAL: Duration = Duration::from_secs(1);
+/// Every reconfiguration costs a keyframe, so quality drops quickly but only recovers once
+/// the link has been good for a while.
+const DOWNGRADE_HOLD: Duration = Duration::from_secs(1);
+const UPGRADE_HOLD: Duration = Duration::from_secs(5);
+/// A link that stays congested would pay for a keyframe every `DOWNGRADE_HOLD`, each one
+/// making things worse, so back to back downgrades wait twice as long as the last, up to this.
+const MAX_DOWNGRADE_HOLD: Duration = Duration::from_secs(5);
+/// After this long without reconfiguring, downgrades are quick again.
+const DOWNGRADE_HOLD_RESET: Duration = Duration::from_secs(10);
+/// Bitrate changes smaller than this aren't worth a new keyframe.
+const MIN_BITRATE_CHANGE: f64 = 0.15;
+
+/// What a viewer's RTCP says about its link, `peer` is the generation of its peer connection.
+#[derive(Debug, Clone, Copy)]
+pub enum RateFeedback {
+    /// Share of packets lost since the last report, 0.0 to 1.0.
+    Loss { peer: u64, fraction: f32 },
+    /// Receiver estimated maximum bitrate (REMB).
+    Estimate { peer: u64, bitrate_bps: u32 },
+    /// The peer connection is gone, its link no longer matters.
+    PeerClosed { peer: u64 },
+}
+
+/// Turns an RTCP packet from a viewer into feedback, `None` for packets that say nothing
+/// about the link.
+pub fn feedback(peer: u64, packet: &(dyn Packet + Send + Sync)) -> Option<RateFeedback> {
+    let packet = packet.as_any();
+    if let Some(report) = packet.downcast_ref::<ReceiverReport>() {
+        let lost = report.reports.iter().map(|r| r.fraction_lost).max()?;
+        return Some(RateFeedback::Loss {
+            peer,
+            fraction: f32::from(lost) / 256.0,
+        });
+    }
+    if let Some(remb) = packet.downcast_ref::<ReceiverEstimatedMaximumBitrate>() {
+        return Some(RateFeedback::Estimate {
+            peer,
+            bitrate_bps: remb.bitrate as u32,
+        });
+    }
+    if let Some(cc) = packet.downcast_ref::<TransportLayerCc>() {
+        return transport_cc_loss(cc).map(|fraction| RateFeedback::Loss { peer, fraction });
+    }
+    None
+}
+
+/// Share of the packets a transport-cc report covers that never arrived.
+fn transport_cc_loss(cc: &TransportLayerCc) -> Option<f32> {
+    let mut remaining = usize::from(cc.packet_status_count);
+    let (mut total, mut lost) = (0, 0);
+    for chunk in &cc.packet_chunks {
+        // the last chunk may be padded beyond the packets the report is about
+        let (count, not_received) = match chunk {
+            PacketStatusChunk::RunLengthChunk(run) => {
+                let count = usize::from(run.run_length).min(remaining);
+                let lost = if run.packet_status_symbol == SymbolTypeTcc::PacketNotReceived {
+                    count
+                } else {
+                    0
+                };
+                (count, lost)
+            }
+            PacketStatusChunk::StatusVectorChunk(vector) => {
+                let symbols = &vector.symbol_list[..vector.symbol_list.len().min(remaining)];
+                let lost = symbols
+                    .iter()
+                    .filter(|symbol| **symbol == SymbolTypeTcc::PacketNotReceived)
+                    .count();
+                (symbols.len(), lost)
+            }
+        };
+        remaining -= count;
+        total += count;
+        lost += not_received;
+    }
+    (total > 0).then(|| lost as f32 / total as f32)
+}
+
+/// Resolution, frame rate and bitrate the encoder should run at.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct EncodeTarget {
+    pub width: usize,
+    pub height: usize,
+    pub framerate: u32,
+    /// 0 leaves the size of the stream to the fixed quantizer.
+    pub bitrate_bps: u32,
+}
+
+/// One viewer's link as far as we can tell.
+struct PeerRate {
+    bitrate: f64,
+    /// Cap from the viewer's REMB, if it sends any.
+    estimate: Option<f64>,
+    last_change: Instant,
+}
+
+/// Loss-based rate control in the spirit of GCC's loss controller, capped by REMB: back off
+/// while packets get lost, probe upwards while the link is clean. Every viewer shares the one
+/// encoded stream, so the weakest link sets the pace.
+pub struct RateController {
+    config: RateConfig,
+    width: usize,
+    height: usize,
+    framerate: u32,
+    peers: HashMap<u64, PeerRate>,
+    current: EncodeTarget,
+    last_reconfigure: Instant,
+    downgrade_hold: Duration,
+}
+
+impl RateController {
+    pub fn new(config: RateConfig, width: usize, height: usize, framerate: u32) -> Self {
+        let mut controller = Self {
+            config,
+            width,
+            height,
+            framerate,
+            peers: HashMap::new(),
+            current: EncodeTarget {
+                width,
+                height,
+                framerate,
+                bitrate_bps: 0,
+            },
+            last_reconfigure: Instant::now(),
+            downgrade_hold: DOWNGRADE_HOLD,
+        };
+        if controller.config.enabled {
+            controller.current = controller.target_for(controller.start_bitrate());
+        }
+        controller
+    }
+
+    pub fn current(&self) -> EncodeTarget {
+        self.current
+    }
+
+    pub fn on_feedback(&mut self, feedback: RateFeedback) {
+        self.on_feedback_at(feedback, Instant::now());
+    }
+
+    fn on_feedback_at(&mut self, feedback: RateFeedback, now: Instant) {
+        if !self.config.enabled {
+            return;
+        }
+        let (start, min, max) = (
+            self.start_bitrate(),
+            bps(self.config.min_bitrate_kbps),
+            bps(self.config.max_bitrate_kbps),
+        );
+        let peer = match feedback {
+            RateFeedback::PeerClosed { peer } => {
+                self.peers.remove(&peer);
+                return;
+            }
+            RateFeedback::Loss { peer, .. } | RateFeedback::Estimate { peer, .. } => {
+                self.peers.entry(peer).or_insert_with(|| PeerRate {
+                    bitrate: start,
+                    estimate: None,
+                    last_change: now,
+                })
+            }
+        };
+        match feedback {
+            RateFeedback::Loss { fraction, .. }
+                if fraction > HIGH_LOSS && now - peer.last_change >= DECREASE_INTERVAL =>
+            {
+                peer.bitrate *= 1.0 - 0.5 * f64::from(fraction);
+                peer.last_change = now;
+            }
+            RateFeedback::Loss { fraction, .. }
+                if fraction < LOW_LOSS && now - peer.last_change >= INCREASE_INTERVAL =>
+            {
+                peer.bitrate *= INCREASE;
+                peer.last_change = now;
+            }
+            RateFeedback::Estimate { bitrate_bps, .. } => {
+                peer.estimate = Some(f64::from(bitrate_bps));
+            }
+            _ => {}
+        }
+        // never above what the receiver says it can take
+        let cap = peer.estimate.unwrap_or(max).min(max);
+        peer.bitrate = peer.bitrate.clamp(min, cap.max(min));
+    }
+
+    /// A new target once the links changed enough to be worth reconfiguring the encoder.
+    pub fn poll(&mut self) -> Option<EncodeTarget> {
+        self.poll_at(Instant::now())
+    }
+
+    fn poll_at(&mut self, now: Instant) -> Option<EncodeTarget> {
+        if !self.config.enabled {
+            return None;
+        }
+        let bitrate = self
+            .peers
+            .values()
+            .map(|peer| peer.bitrate)
+            .reduce(f64::min)
+            .unwrap_or_else(|| self.start_bitrate());
+        let target = self.target_for(bitrate);
+        let current = self.current;
+        let same_rung = (target.width, target.height, target.framerate)
+            == (current.width, current.height, current.framerate);
+        let change = (f64::from(target.bitrate_bps) / f64::from(current.bitrate_bps) - 1.0).abs();
+        if same_rung && change < MIN_BITRATE_CHANGE {
+            return None;
+        }
+        let since = now.saturating_duration_since(self.last_reconfigure);
+        if since >= DOWNGRADE_HOLD_RESET {
+            self.downgrade_hold = DOWNGRADE_HOLD;
+        }
+        let downgrade = target.bitrate_bps < current.bitrate_bps;
+        let hold = if downgrade {
+            self.downgrade_hold
+        } else {
+            UPGRADE_HOLD
+        };
+        if since < hold {
+            return None;
+        }
+        self.downgrade_hold = if downgrade {
+            (self.downgrade_hold * 2).min(MAX_DOWNGRADE_HOLD)
+        } else {
+            DOWNGRADE_HOLD
+        };
+        self.current = target;
+        self.last_reconfigure = now;
+        Some(target)
+    }
+
+    /// Full quality down to half the maximum bitrate, then half the frame rate,
+    /// then half and finally a quarter of the resolution.
+    fn target_for(&self, bitrate: f64) -> EncodeTarget {
+        let share = bitrate / bps(self.config.max_bitrate_kbps);
+        let (scale, framerate_divisor) = match share {
+            s if s >= 0.5 => (1, 1),
+            s if s >= 0.25 => (1, 2),
+            s if s >= 0.1 => (2, 2),
+            _ => (4, 2),
+        };
+        EncodeTarget {
+            width: (self.width / scale).max(2) & !1,
+            height: (self.height / scale).max(2) & !1,
+            framerate: (self.framerate / framerate_divisor).max(1),
+            bitrate_bps: bitrate as u32,
+        }
+    }
+
+    fn start_bitrate(&self) -> f64 {
+        bps(self.config.start_bitrate_kbps)
+    }
+}
+
+fn bps(kbps: u32) -> f64 {
+    f64::from(kbps) * 1000.0
+}
+
+#[cfg(test)]
+mod tests {
+    use webrtc::rtcp::{
+        payload_feedbacks::picture_loss_indication::PictureLossIndication,
+        reception_report::ReceptionReport,
+        transport_feedbacks::transport_layer_cc::{RunLengthChunk, StatusVectorChunk},
+    };
+
+    use super::*;
+
+    const PEER: u64 = 7;
+
+    fn config() -> RateConfig {
+        RateConfig {
+            enabled: true,
+            min_bitrate_kbps: 50,
+            start_bitrate_kbps: 1000,
+            max_bitrate_kbps: 1500,
+        }
+    }
+
+    fn controller() -> RateController {
+        RateController::new(config(), 1280, 720, 30)
+    }
+
+    fn loss(fraction: f32) -> RateFeedback {
+        RateFeedback::Loss {
+            peer: PEER,
+            fraction,
+        }
+    }
+
+    fn bitrate(controller: &RateController) -> f64 {
+        controller.peers[&PEER].bitrate
+    }
+
+    fn receiver_report(fractions: &[u8]) -> ReceiverReport {
+        ReceiverReport {
+            reports: fractions
+                .iter()
+                .map(|&fraction_lost| ReceptionReport {
+                    fraction_lost,
+                    ..Default::default()
+                })
+                .collect(),
+            ..Default::default()
+        }
+    }
+
+    fn run(symbol: SymbolTypeTcc, run_length: u16) -> PacketStatusChunk {
+        PacketStatusChunk::RunLengthChunk(RunLengthChunk {
+            packet_status_symbol: symbol,
+            run_length,
+            ..Default::default()
+        })
+    }
+
+    fn vector(symbols: &[SymbolTypeTcc]) -> PacketStatusChunk {
+        PacketStatusChunk::StatusVectorChunk(StatusVectorChunk {
+            symbol_list: symbols.to_vec(),
+            ..Default::default()
+        })
+    }
+
+    #[test]
+    fn worst_reception_report_counts() {
+        let Some(RateFeedback::Loss { peer, fraction }) =
+            feedback(PEER, &receiver_report(&[64, 128, 0]))
+        else {
+            panic!("no loss");
+        };
+        assert_eq!((peer, fraction), (PEER, 0.5));
+        // nothing was received, so nothing to say
+        assert!(feedback(PEER, &receiver_report(&[])).is_none());
+    }
+
+    #[test]
+    fn remb_and_other_packets() {
+        let remb = ReceiverEstimatedMaximumBitrate {
+            bitrate: 300_000.0,
+            ..Default::default()
+        };
+        assert!(matches!(
+            feedback(PEER, &remb),
+            Some(RateFeedback::Estimate {
+                peer: PEER,
+                bitrate_bps: 300_000
+            })
+        ));
+        assert!(feedback(PEER, &PictureLossIndication::default()).is_none());
+    }
+
+    #[test]
+    fn transport_cc_skips_padding() {
+        use SymbolTypeTcc::*;
+        let cc = TransportLayerCc {
+            packet_status_count: 10,
+            packet_chunks: vec![
+                run(PacketReceivedSmallDelta, 4),
+                // the last two symbols are padding past the 10 packets
+                vector(&[
+                    PacketNotReceived,
+                    PacketReceivedSmallDelta,
+                    PacketNotReceived,
+                    PacketReceivedLargeDelta,
+                    PacketReceivedSmallDelta,
+                    PacketReceivedSmallDelta,
+                    PacketNotReceived,
+                    PacketNotReceived,
+                ]),
+            ],
+            ..Default::default()
+        };
+        assert_eq!(transport_cc_loss(&cc), Some(0.2));
+
+        // a run longer than the report is cut off as well
+        let cc = TransportLayerCc {
+            packet_status_count: 4,
+            packet_chunks: vec![
+                run(PacketReceivedSmallDelta, 2),
+                run(PacketNotReceived, 100),
+            ],
+            ..Default::default()
+        };
+        assert_eq!(transport_cc_loss(&cc), Some(0.5));
+        assert!(matches!(
+            feedback(PEER, &cc),
+            Some(RateFeedback::Loss { fraction, .. }) if fraction == 0.5
+        ));
+
+        assert_eq!(transport_cc_loss(&TransportLayerCc::default()), None);
+    }
+
+    #[test]
+    fn loss_backs_off_and_clean_links_probe() {
+        let mut controller = controller();
+        let start = Instant::now();
+        // the first report creates the peer at the start bitrate
+        controller.on_feedback_at(loss(0.05), start);
+        assert_eq!(bitrate(&controller), 1_000_000.0);
+
+        controller.on_feedback_at(loss(0.5), start + DECREASE_INTERVAL);
+        assert_eq!(bitrate(&controller), 750_000.0);
+        // too soon after the last change
+        controller.on_feedback_at(
+            loss(0.5),
+            start + DECREASE_INTERVAL + Duration::from_millis(100),
+        );
+        assert_eq!(bitrate(&controller), 750_000.0);
+        // moderate loss holds the bitrate
+        controller.on_feedback_at(loss(0.05), start + Duration::from_secs(10));
+        assert_eq!(bitrate(&controller), 750_000.0);
+
+        controller.on_feedback_at(loss(0.0), start + Duration::from_secs(10));
+        assert_eq!(bitrate(&controller), 750_000.0 * INCREASE);
+        controller.on_feedback_at(loss(0.0), start + Duration::from_millis(10_500));
+        assert_eq!(bitrate(&controller), 750_000.0 * INCREASE);
+
+        // never below the minimum
+        let mut now = start + Duration::from_secs(10);
+        for _ in 0..50 {
+            now += DECREASE_INTERVAL;
+            controller.on_feedback_at(loss(1.0), now);
+        }
+        assert_eq!(bitrate(&controller), 50_000.0);
+    }
+
+    #[test]
+    fn remb_caps_the_bitrate() {
+        let mut controller = controller();
+        let mut now = Instant::now();
+        let estimate = |bitrate_bps| RateFeedback::Estimate {
+            peer: PEER,
+            bitrate_bps,
+        };
+        controller.on_feedback_at(estimate(300_000), now);
+        assert_eq!(bitrate(&controller), 300_000.0);
+        for _ in 0..10 {
+            now += INCREASE_INTERVAL;
+            controller.on_feedback_at(loss(0.0), now);
+        }
+        assert_eq!(bitrate(&controller), 300_000.0);
+
+        // an estimate below the minimum doesn't starve the stream
+        controller.on_feedback_at(estimate(10_000), now);
+        assert_eq!(bitrate(&controller), 50_000.0);
+        // nor does one above the maximum lift it past it
+        controller.on_feedback_at(estimate(10_000_000), now);
+        for _ in 0..100 {
+            now += INCREASE_INTERVAL;
+            controller.on_feedback_at(loss(0.0), now);
+        }
+        assert_eq!(bitrate(&controller), 1_500_000.0);
+    }
+
+    #[test]
+    fn starts_at_full_frame_rate() {
+        let controller = RateController::new(RateConfig::default(), 1280, 720, 30);
+        let target = controller.current();
+        assert_eq!(
+            (target.width, target.height, target.framerate),
+            (1280, 720, 30)
+        );
+        assert_eq!(
+            target.bitrate_bps,
+            RateConfig::default().start_bitrate_kbps * 1000
+        );
+    }
+
+    #[test]
+    fn disabled_keeps_the_fixed_quantizer() {
+        let mut controller = RateController::new(
+            RateConfig {
+                enabled: false,
+                ..config()
+            },
+            1280,
+            720,
+            30,
+        );
+        controller.on_feedback(loss(1.0));
+        assert_eq!(controller.current().bitrate_bps, 0);
+        assert!(controller.poll().is_none());
+    }
+
+    #[test]
+    fn weakest_link_and_rungs() {
+        let mut controller = controller();
+        let start = controller.last_reconfigure;
+        controller.on_feedback_at(
+            RateFeedback::Estimate {
+                peer: 1,
+                bitrate_bps: 1_200_000,
+            },
+            start,
+        );
+        controller.on_feedback_at(
+            RateFeedback::Estimate {
+                peer: 2,
+                bitrate_bps: 200_000,
+            },
+            start,
+        );
+        let target = controller.poll_at(start + DOWNGRADE_HOLD).unwrap();
+        // 200 of 1500 kbps is half the width and height at half the frame rate
+        assert_eq!(
+            target,
+            EncodeTarget {
+                width: 640,
+                height: 360,
+                framerate: 15,
+                bitrate_bps: 200_000,
+            }
+        );
+        // the weak viewer leaving brings the rest back up, once the link has proven itself
+        controller.on_feedback_at(RateFeedback::PeerClosed { peer: 2 }, start);
+        assert!(controller.poll_at(start + DOWNGRADE_HOLD * 2).is_none());
+        let target = controller
+            .poll_at(start + DOWNGRADE_HOLD + UPGRADE_HOLD)
+            .unwrap();
+        assert_eq!((target.width, target.framerate), (1280, 30));
+        assert_eq!(target.bitrate_bps, 1_000_000);
+    }
+
+    #[test]
+    fn small_changes_are_not_worth_a_keyframe() {
+        let mut controller = controller();
+        let start = controller.last_reconfigure;
+        controller.on_feedback_at(
+            RateFeedback::Estimate {
+                peer: PEER,
+                bitrate_bps: 900_000,
+            },
+            start,
+        );
+        assert!(controller
+            .poll_at(start + Duration::from_secs(60))
+            .is_none());
+    }
+
+    #[test]
+    fn congestion_backs_off_its_downgrades() {
+        let mut controller = controller();
+        let start = controller.last_reconfigure;
+        let mut downgrade = |bitrate_bps, at| {
+            controller.on_feedback_at(
+                RateFeedback::Estimate {
+                    peer: PEER,
+                    bitrate_bps,
+                },
+                at,
+            );
+            controller.poll_at(at).map(|_| at - start)
+        };
+        // the link keeps getting worse, every step down would be worth a keyframe
+        let mut bitrate_bps = 1_000_000;
+        let mut reconfigured = vec![];
+        for step in 1..=120 {
+            bitrate_bps = bitrate_bps * 49 / 50;
+            reconfigured.extend(downgrade(
+                bitrate_bps,
+                start + Duration::from_millis(step * 100),
+            ));
+        }
+        let secs = Duration::from_secs;
+        assert_eq!(reconfigured, [secs(1), secs(3), secs(7), secs(12)]);
+
+        // after a calm while the holds start over
+        let calm = start + secs(12) + DOWNGRADE_HOLD_RESET;
+        assert!(downgrade(bitrate_bps * 2 / 3, calm).is_some());
+        assert!(downgrade(bitrate_bps / 2, calm + secs(2)).is_some());
+    }
+}
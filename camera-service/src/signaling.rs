@@ -1,111 +1,368 @@
-use crate::{connection::WebRtcEnumCommand, prelude::*};
+use crate::{connection::WebRtcEnumCommand, control::ControlEvent, prelude::*};
 
-use futures_util::{
-    stream::{SplitSink, SplitStream},
-    SinkExt, StreamExt,
-};
-use protocol::{SignalEnum, TankCommand, TankMessage};
-use tokio::net::TcpStream;
-use tokio_tungstenite::{connect_async, tungstenite::Message, MaybeTlsStream, WebSocketStream};
+use futures_util::{SinkExt, StreamExt};
+use protocol::{SignalEnum, TankCommand, TankInfo, TankMessage};
+use rand::Rng;
+use tokio::sync::mpsc::{UnboundedReceiver, UnboundedSender};
+use tokio_tungstenite::{connect_async_tls_with_config, tungstenite::Message, Connector};
 
-type SocketWriteChannel = SplitSink<WebSocketStream<MaybeTlsStream<TcpStream>>, Message>;
-type SocketReadChannel = SplitStream<WebSocketStream<MaybeTlsStream<TcpStream>>>;
+const INITIAL_BACKOFF: Duration = Duration::from_millis(500);
+const MAX_BACKOFF: Duration = Duration::from_secs(30);
 
 pub enum WebSocketCommand {
     ConnectToSignalServer(String),
     SendSignal(SignalEnum),
 }
-pub async fn socket_cmd_thread(
+
+/// Whether the tank can currently reach the signaling server.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SignalingState {
+    Connecting,
+    /// The server accepted our login.
+    Connected,
+    Disconnected,
+}
+
+/// Exponential backoff between reconnects, with jitter so a fleet of tanks
+/// doesn't hammer a restarted signaling server in lockstep.
+struct Backoff {
+    next: Duration,
+}
+
+impl Backoff {
+    fn new() -> Self {
+        Self {
+            next: INITIAL_BACKOFF,
+        }
+    }
+
+    fn reset(&mut self) {
+        self.next = INITIAL_BACKOFF;
+    }
+
+    fn next_delay(&mut self) -> Duration {
+        let delay = self.next;
+        self.next = (self.next * 2).min(MAX_BACKOFF);
+        let jitter = rand::thread_rng().gen_range(0..=delay.as_millis() as u64 / 2);
+        delay + Duration::from_millis(jitter)
+    }
+}
+
+/// Keeps the tank connected to the signaling server, logging in again after every reconnect.
+/// Signals sent while disconnected are dropped, the sessions they belong to are gone anyway.
+pub async fn socket_cmd_thread<F>(
     cmd_receiver: Receiver<WebSocketCommand>,
     rtc_sender: Sender<WebRtcEnumCommand>,
-) -> Result<tokio::task::JoinSet<()>> {
-    let (mut socket_tx, socket_rx) = futures_channel::mpsc::unbounded::<Message>();
-    let (ch_soc_tx, ch_soc_rx) = mpsc::channel::<SocketWriteChannel>();
-    let (ch_socr_tx, ch_socr_rx) = mpsc::channel::<SocketReadChannel>();
-    let (sig_tx, sig_rx) = mpsc::channel::<SignalEnum>();
-
-    let mut socket_tx2 = socket_tx.clone();
+    control_sender: Sender<ControlEvent>,
+    tank_info: TankInfo,
+    tank_token: String,
+    connector: Option<Connector>,
+    on_state: F,
+) -> Result<tokio::task::JoinSet<()>>
+where
+    F: Fn(SignalingState) + Send + Sync + 'static,
+{
+    let (cmd_tx, cmd_rx) = tokio::sync::mpsc::unbounded_channel::<WebSocketCommand>();
+    let (sig_tx, mut sig_rx) = tokio::sync::mpsc::unbounded_channel::<SignalEnum>();
 
     let mut set = tokio::task::JoinSet::new();
 
-    set.spawn(async move {
-        if let Ok(write) = ch_soc_rx.recv() {
-            debug!("received socket write channel");
-            let _ = socket_rx.map(Ok).forward(write).await;
+    set.spawn_blocking(move || {
+        while let Ok(cmd) = cmd_receiver.recv() {
+            if cmd_tx.send(cmd).is_err() {
+                break;
+            }
         }
     });
 
     set.spawn(async move {
-        if let Ok(read) = ch_socr_rx.recv() {
-            debug!("received socket read channel");
+        let login = SignalEnum::TankCommand(TankCommand::Login(tank_info, tank_token));
+        connection_loop(cmd_rx, sig_tx, login, connector, on_state).await;
+    });
 
-            read.for_each(|message| async {
-                if let Ok(data) = message.unwrap().into_text() {
-                    if let Ok(signal) = serde_json::from_str::<SignalEnum>(&data) {
-                        let _ = sig_tx.send(signal);
+    set.spawn(async move {
+        while let Some(cmd) = sig_rx.recv().await {
+            match cmd {
+                SignalEnum::TankMessage(response) => match response {
+                    TankMessage::LoginResponse(tank_id) => {
+                        info!("My tank id is: {0}", tank_id.inner());
+                    }
+                    TankMessage::LoginError(reason) => {
+                        error!("signaling server rejected login: {reason}");
+                    }
+                    TankMessage::IceServers(servers) => {
+                        debug!("signaling server handed out {} ice servers", servers.len());
+                        let _ = rtc_sender.send(WebRtcEnumCommand::SetIceServers(servers));
                     }
+                    TankMessage::IceCandidate(id, candidate) => {
+                        debug!("receiving ICE candidate");
+                        let _ =
+                            rtc_sender.send(WebRtcEnumCommand::ReceiveIceCandidate(id, candidate));
+                    }
+                    TankMessage::SdpConnectionOffer(id, data) => {
+                        info!("receiving SDP offer");
+                        let _ = rtc_sender.send(WebRtcEnumCommand::ReceiveSdpOffer(id, data));
+                    }
+                    TankMessage::OperatorDisconnected(id) => {
+                        info!("operator {:?} left", id);
+                        let _ = rtc_sender.send(WebRtcEnumCommand::CloseConn(id));
+                    }
+                    TankMessage::ControllerChanged(id) => {
+                        let _ = control_sender.send(ControlEvent::ControllerChanged(id));
+                    }
+                },
 
-                    info!("message from signal server: {0}", data);
-                }
-            })
-            .await;
+                _ => trace!("ignore"),
+            }
         }
     });
 
-    set.spawn(async move {
-        loop {
-            if let Ok(cmd) = cmd_receiver.recv() {
-                match cmd {
-                    WebSocketCommand::ConnectToSignalServer(ip) => {
-                        info!("connecting to signal server at {0}", &ip);
-                        let (ws_stream, _) = connect_async(ip).await.expect("Failed to connect");
-                        let (write, read) = ws_stream.split();
+    Ok(set)
+}
 
-                        let _ = ch_soc_tx.send(write);
-                        let _ = ch_socr_tx.send(read);
+/// Waits for drop-able commands until the delay is over, returns `false` once nobody can send any.
+async fn wait_dropping_signals(
+    cmd_rx: &mut UnboundedReceiver<WebSocketCommand>,
+    url: &mut String,
+    delay: Duration,
+) -> bool {
+    let sleep = tokio::time::sleep(delay);
+    tokio::pin!(sleep);
+    loop {
+        tokio::select! {
+            _ = &mut sleep => return true,
+            cmd = cmd_rx.recv() => match cmd {
+                Some(WebSocketCommand::ConnectToSignalServer(new_url)) => {
+                    *url = new_url;
+                    return true;
+                }
+                Some(WebSocketCommand::SendSignal(signal)) => {
+                    debug!("signaling is down, dropping {:?}", signal);
+                }
+                None => return false,
+            },
+        }
+    }
+}
 
-                        let ser_text =
-                            serde_json::to_string(&SignalEnum::TankCommand(TankCommand::Login));
+async fn connection_loop<F>(
+    mut cmd_rx: UnboundedReceiver<WebSocketCommand>,
+    sig_tx: UnboundedSender<SignalEnum>,
+    login: SignalEnum,
+    connector: Option<Connector>,
+    on_state: F,
+) where
+    F: Fn(SignalingState),
+{
+    // nothing to do until we are told where the signaling server is
+    let mut url = loop {
+        match cmd_rx.recv().await {
+            Some(WebSocketCommand::ConnectToSignalServer(url)) => break url,
+            Some(WebSocketCommand::SendSignal(signal)) => {
+                debug!("not connected yet, dropping {:?}", signal);
+            }
+            None => return,
+        }
+    };
+    let mut backoff = Backoff::new();
 
-                        if let Ok(text) = ser_text {
-                            let _ = socket_tx.send(Message::text(text)).await;
-                        }
-                    }
-                    WebSocketCommand::SendSignal(signal) => {
-                        let ser_text = serde_json::to_string(&signal);
-                        if let Ok(text) = ser_text {
-                            let _ = socket_tx.send(Message::text(text)).await;
-                        }
-                    }
+    loop {
+        info!("connecting to signal server at {0}", &url);
+        on_state(SignalingState::Connecting);
+        let connect = connect_async_tls_with_config(url.as_str(), None, false, connector.clone());
+        let ws_stream = match connect.await {
+            Ok((ws_stream, _)) => ws_stream,
+            Err(e) => {
+                let delay = backoff.next_delay();
+                warn!(
+                    "could not reach signal server: {e}, retrying in {:?}",
+                    delay
+                );
+                on_state(SignalingState::Disconnected);
+                if !wait_dropping_signals(&mut cmd_rx, &mut url, delay).await {
+                    return;
                 }
+                continue;
             }
+        };
+        let (mut write, mut read) = ws_stream.split();
+
+        if let Ok(text) = serde_json::to_string(&login) {
+            let _ = write.send(Message::text(text)).await;
         }
-    });
 
-    set.spawn(async move {
         loop {
-            if let Ok(cmd) = sig_rx.recv() {
-                match cmd {
-                    SignalEnum::TankMessage(response) => match response {
-                        TankMessage::LoginResponse(tank_id) => {
-                            info!("My tank id is: {0}", tank_id.inner());
-                        }
-                        TankMessage::IceConnectionOffer(id, data) => {
-                            info!("receiving ICE handshake");
-                            let _ =
-                                rtc_sender.send(WebRtcEnumCommand::ReceiveIceHandshake(id, data));
+            tokio::select! {
+                cmd = cmd_rx.recv() => match cmd {
+                    Some(WebSocketCommand::SendSignal(signal)) => {
+                        let Ok(text) = serde_json::to_string(&signal) else {
+                            continue;
+                        };
+                        if let Err(e) = write.send(Message::text(text)).await {
+                            warn!("lost signal server while sending: {e}");
+                            break;
                         }
-                        TankMessage::SdpConnectionOffer(id, data) => {
-                            info!("receiving SDP offer");
-                            let _ = rtc_sender.send(WebRtcEnumCommand::ReceiveSdpOffer(id, data));
+                    }
+                    Some(WebSocketCommand::ConnectToSignalServer(new_url)) => {
+                        url = new_url;
+                        let _ = write.close().await;
+                        break;
+                    }
+                    None => return,
+                },
+                message = read.next() => match message {
+                    Some(Ok(Message::Text(data))) => {
+                        info!("message from signal server: {0}", data);
+                        let Ok(signal) = serde_json::from_str::<SignalEnum>(&data) else {
+                            continue;
+                        };
+                        // only a server that took our login counts as reached
+                        let rejected = match &signal {
+                            SignalEnum::TankMessage(TankMessage::LoginResponse(_)) => {
+                                backoff.reset();
+                                on_state(SignalingState::Connected);
+                                false
+                            }
+                            SignalEnum::TankMessage(TankMessage::LoginError(_)) => true,
+                            _ => false,
+                        };
+                        let _ = sig_tx.send(signal);
+                        if rejected {
+                            let _ = write.close().await;
+                            break;
                         }
-                    },
+                    }
+                    Some(Ok(Message::Close(frame))) => {
+                        warn!("signal server closed the connection: {:?}", frame);
+                        break;
+                    }
+                    Some(Ok(_)) => {}
+                    Some(Err(e)) => {
+                        warn!("lost signal server: {e}");
+                        break;
+                    }
+                    None => break,
+                },
+            }
+        }
+
+        on_state(SignalingState::Disconnected);
+        let delay = backoff.next_delay();
+        info!("reconnecting to signal server in {:?}", delay);
+        if !wait_dropping_signals(&mut cmd_rx, &mut url, delay).await {
+            return;
+        }
+    }
+}
 
-                    _ => trace!("ignore"),
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+    use std::time::Instant;
+
+    use protocol::TankId;
+    use tokio::sync::mpsc::unbounded_channel;
+
+    use super::*;
+
+    /// The delay `backoff` would wait before it jitters it, checked against the jitter bounds.
+    fn assert_jittered(delay: Duration, base: Duration) {
+        assert!(
+            delay >= base && delay <= base + base / 2,
+            "{delay:?} isn't between {base:?} and half as much again"
+        );
+    }
+
+    #[test]
+    fn backoff_doubles_up_to_the_cap() {
+        let mut backoff = Backoff::new();
+        let mut base = INITIAL_BACKOFF;
+        for _ in 0..10 {
+            assert_jittered(backoff.next_delay(), base);
+            base = (base * 2).min(MAX_BACKOFF);
+        }
+        assert_eq!(base, MAX_BACKOFF);
+        for _ in 0..10 {
+            assert_jittered(backoff.next_delay(), MAX_BACKOFF);
+        }
+    }
+
+    #[test]
+    fn backoff_jitters() {
+        let mut backoff = Backoff::new();
+        let delays: HashSet<Duration> = (0..20)
+            .map(|_| {
+                backoff.reset();
+                backoff.next_delay()
+            })
+            .collect();
+        assert!(delays.len() > 1, "tanks would reconnect in lockstep");
+    }
+
+    #[test]
+    fn backoff_reset() {
+        let mut backoff = Backoff::new();
+        for _ in 0..5 {
+            backoff.next_delay();
+        }
+        backoff.reset();
+        assert_jittered(backoff.next_delay(), INITIAL_BACKOFF);
+        assert_jittered(backoff.next_delay(), INITIAL_BACKOFF * 2);
+    }
+
+    #[tokio::test]
+    async fn a_login_resets_the_backoff() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("ws://{}", listener.local_addr().unwrap());
+        // logs the tank in and hangs up on it straight away, over and over
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                let mut ws = tokio_tungstenite::accept_async(stream).await.unwrap();
+                let _login = ws.next().await;
+                let response = SignalEnum::TankMessage(TankMessage::LoginResponse(TankId::new(
+                    "tank-1".to_owned(),
+                )));
+                let text = serde_json::to_string(&response).unwrap();
+                ws.send(Message::text(text)).await.unwrap();
+                let _ = ws.close(None).await;
+            }
+        });
+
+        let (cmd_tx, cmd_rx) = unbounded_channel();
+        let (sig_tx, _sig_rx) = unbounded_channel();
+        let (state_tx, mut states) = unbounded_channel();
+        cmd_tx
+            .send(WebSocketCommand::ConnectToSignalServer(url))
+            .unwrap();
+        let login = SignalEnum::TankCommand(TankCommand::Login(
+            TankInfo {
+                id: TankId::new("tank-1".to_owned()),
+                name: "tank".to_owned(),
+                hardware: Default::default(),
+            },
+            "token".to_owned(),
+        ));
+        tokio::spawn(connection_loop(cmd_rx, sig_tx, login, None, move |state| {
+            let _ = state_tx.send((state, Instant::now()));
+        }));
+
+        // without the reset the third reconnect would wait two seconds or more
+        let (mut logins, mut disconnected) = (0, None);
+        while logins < 4 {
+            let (state, at) = tokio::time::timeout(Duration::from_secs(5), states.recv())
+                .await
+                .expect("the tank should keep reconnecting")
+                .unwrap();
+            match state {
+                SignalingState::Connecting => {
+                    if let Some(since) = disconnected.take() {
+                        let waited: Duration = at - since;
+                        assert!(waited < INITIAL_BACKOFF * 2, "waited {waited:?}");
+                    }
                 }
+                SignalingState::Connected => logins += 1,
+                SignalingState::Disconnected => disconnected = Some(at),
             }
         }
-    });
-
-    Ok(set)
+    }
 }
@@ -0,0 +1,613 @@
+use std::{
+    fs::File,
+    io::{BufRead, BufReader, Read, Seek, SeekFrom},
+    path::{Path, PathBuf},
+    str::FromStr,
+    time::Instant,
+};
+
+use image::{imageops, ImageBuffer, Rgb};
+use nokhwa::{
+    pixel_format::RgbFormat,
+    utils::{CameraIndex, FrameFormat, RequestedFormat, RequestedFormatType},
+    Camera,
+};
+
+use crate::{colorspace::YuvLayout, prelude::*};
+
+pub type RgbFrame = ImageBuffer<Rgb<u8>, Vec<u8>>;
+
+/// A frame the way its source delivers it, so the encoder only converts what it has to.
+pub enum Frame {
+    Rgb(RgbFrame),
+    /// Raw YCbCr, which the encoders convert straight into their own 4:2:0 planes.
+    Yuv {
+        layout: YuvLayout,
+        width: u32,
+        height: u32,
+        data: Vec<u8>,
+    },
+}
+
+impl Frame {
+    pub fn dimensions(&self) -> (u32, u32) {
+        match self {
+            Frame::Rgb(frame) => frame.dimensions(),
+            Frame::Yuv { width, height, .. } => (*width, *height),
+        }
+    }
+}
+
+/// Anything that can feed frames into the encoder.
+/// Sources are created on the camera thread when a viewer connects
+/// and dropped when the last one leaves.
+pub trait VideoSource {
+    /// Blocks until the next frame is available.
+    fn frame(&mut self) -> Result<Frame>;
+}
+
+/// Which [`VideoSource`] to open, parsed from `VIDEO_SOURCE`:
+/// `camera`, `test-pattern`, `dir:<path>` or `y4m:<path>`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum SourceKind {
+    Camera(u32),
+    TestPattern,
+    ImageDir(PathBuf),
+    Y4m(PathBuf),
+}
+
+impl FromStr for SourceKind {
+    type Err = anyhow::Error;
+
+    fn from_str(input: &str) -> Result<SourceKind, Self::Err> {
+        match input.split_once(':') {
+            None if input == "camera" => Ok(SourceKind::Camera(0)),
+            None if input == "test-pattern" => Ok(SourceKind::TestPattern),
+            Some(("camera", index)) => Ok(SourceKind::Camera(index.parse()?)),
+            Some(("dir", path)) => Ok(SourceKind::ImageDir(path.into())),
+            Some(("y4m", path)) => Ok(SourceKind::Y4m(path.into())),
+            _ => Err(anyhow::anyhow!("unknown video source {input}")),
+        }
+    }
+}
+
+impl SourceKind {
+    pub fn open(&self, width: u32, height: u32, framerate: u32) -> Result<Box<dyn VideoSource>> {
+        Ok(match self {
+            SourceKind::Camera(index) => Box::new(CameraSource::open(*index)?),
+            SourceKind::TestPattern => Box::new(TestPattern::new(width, height, framerate)),
+            SourceKind::ImageDir(dir) => Box::new(ImageDir::open(dir, width, height, framerate)?),
+            SourceKind::Y4m(path) => Box::new(Y4mFile::open(path, framerate)?),
+        })
+    }
+}
+
+pub struct CameraSource {
+    camera: Camera,
+}
+
+impl CameraSource {
+    /// Opens the camera in a YCbCr format the encoders take without decoding to RGB first,
+    /// or in whatever it offers if it has none of those.
+    pub fn open(video_device_index: u32) -> Result<Self> {
+        let index = CameraIndex::Index(video_device_index);
+        let native = &[FrameFormat::NV12, FrameFormat::YUYV];
+        let requested =
+            RequestedFormat::with_formats(RequestedFormatType::AbsoluteHighestFrameRate, native);
+        let mut camera = match Camera::new(index.clone(), requested) {
+            Ok(camera) => camera,
+            Err(e) => {
+                warn!("camera has no {native:?} ({e}), decoding its frames to RGB");
+                let requested = RequestedFormat::new::<RgbFormat>(
+                    RequestedFormatType::AbsoluteHighestFrameRate,
+                );
+                Camera::new(index, requested)?
+            }
+        };
+        camera.open_stream()?;
+        info!("camera delivers {}", camera.camera_format());
+        Ok(Self { camera })
+    }
+}
+
+impl VideoSource for CameraSource {
+    fn frame(&mut self) -> Result<Frame> {
+        let buffer = self.camera.frame()?;
+        let resolution = buffer.resolution();
+        let (width, height) = (resolution.width(), resolution.height());
+        Ok(match buffer.source_frame_format() {
+            FrameFormat::YUYV => Frame::Yuv {
+                layout: YuvLayout::Yuyv,
+                width,
+                height,
+                data: buffer.buffer().to_vec(),
+            },
+            FrameFormat::NV12 => Frame::Yuv {
+                layout: YuvLayout::Nv12,
+                width,
+                height,
+                data: buffer.buffer().to_vec(),
+            },
+            _ => Frame::Rgb(buffer.decode_image::<RgbFormat>()?),
+        })
+    }
+}
+
+/// Sleeps between frames so generated and file sources run at the requested rate.
+struct Pacer {
+    interval: Duration,
+    next: Instant,
+}
+
+impl Pacer {
+    fn new(framerate: u32) -> Self {
+        Self {
+            interval: Duration::from_secs(1) / framerate.max(1),
+            next: Instant::now(),
+        }
+    }
+
+    fn wait(&mut self) {
+        let now = Instant::now();
+        if self.next > now {
+            thread::sleep(self.next - now);
+        } else {
+            // fell behind, don't try to catch up with a burst of frames
+            self.next = now;
+        }
+        self.next += self.interval;
+    }
+}
+
+/// Color bars with a bouncing box and the frame number burned in.
+pub struct TestPattern {
+    width: u32,
+    height: u32,
+    frame_number: u64,
+    pacer: Pacer,
+}
+
+const BARS: [[u8; 3]; 7] = [
+    [191, 191, 191],
+    [191, 191, 0],
+    [0, 191, 191],
+    [0, 191, 0],
+    [191, 0, 191],
+    [191, 0, 0],
+    [0, 0, 191],
+];
+
+/// 3x5 bitmaps of the digits 0-9, one row per entry, high bit on the left.
+const DIGITS: [[u8; 5]; 10] = [
+    [0b111, 0b101, 0b101, 0b101, 0b111],
+    [0b010, 0b110, 0b010, 0b010, 0b111],
+    [0b111, 0b001, 0b111, 0b100, 0b111],
+    [0b111, 0b001, 0b111, 0b001, 0b111],
+    [0b101, 0b101, 0b111, 0b001, 0b001],
+    [0b111, 0b100, 0b111, 0b001, 0b111],
+    [0b111, 0b100, 0b111, 0b101, 0b111],
+    [0b111, 0b001, 0b010, 0b010, 0b010],
+    [0b111, 0b101, 0b111, 0b101, 0b111],
+    [0b111, 0b101, 0b111, 0b001, 0b111],
+];
+
+impl TestPattern {
+    pub fn new(width: u32, height: u32, framerate: u32) -> Self {
+        Self {
+            width,
+            height,
+            frame_number: 0,
+            pacer: Pacer::new(framerate),
+        }
+    }
+
+    fn draw_box(&self, frame: &mut RgbFrame) {
+        let size = (self.height / 6).max(1);
+        let travel_x = self.width.saturating_sub(size).max(1) as u64;
+        let travel_y = self.height.saturating_sub(size).max(1) as u64;
+        // bounce back and forth instead of wrapping around
+        let bounce = |pos: u64, travel: u64| {
+            let pos = pos % (2 * travel);
+            (if pos < travel { pos } else { 2 * travel - pos }) as u32
+        };
+        let x0 = bounce(self.frame_number * 4, travel_x);
+        let y0 = bounce(self.frame_number * 3, travel_y);
+        for y in y0..(y0 + size).min(self.height) {
+            for x in x0..(x0 + size).min(self.width) {
+                frame.put_pixel(x, y, Rgb([255, 255, 255]));
+            }
+        }
+    }
+
+    fn draw_counter(&self, frame: &mut RgbFrame) {
+        const SCALE: u32 = 4;
+        let text = self.frame_number.to_string();
+        let text_width = (text.len() as u32 * 4 + 1) * SCALE;
+        let text_height = 7 * SCALE;
+        for y in 0..text_height.min(self.height) {
+            for x in 0..text_width.min(self.width) {
+                frame.put_pixel(x, y, Rgb([0, 0, 0]));
+            }
+        }
+        for (i, digit) in text.bytes().enumerate() {
+            let glyph = DIGITS[(digit - b'0') as usize];
+            let left = (i as u32 * 4 + 1) * SCALE;
+            for (row, bits) in glyph.iter().enumerate() {
+                for col in 0..3 {
+                    if bits & (0b100 >> col) == 0 {
+                        continue;
+                    }
+                    let px = left + col * SCALE;
+                    let py = (row as u32 + 1) * SCALE;
+                    for y in py..(py + SCALE).min(self.height) {
+                        for x in px..(px + SCALE).min(self.width) {
+                            frame.put_pixel(x, y, Rgb([255, 255, 255]));
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
+impl VideoSource for TestPattern {
+    fn frame(&mut self) -> Result<Frame> {
+        self.pacer.wait();
+        let bars_height = self.height * 2 / 3;
+        let mut frame = RgbFrame::from_fn(self.width, self.height, |x, y| {
+            if y < bars_height {
+                Rgb(BARS[(x * BARS.len() as u32 / self.width) as usize])
+            } else {
+                let level = (x * 255 / self.width.max(1)) as u8;
+                Rgb([level, level, level])
+            }
+        });
+        self.draw_box(&mut frame);
+        self.draw_counter(&mut frame);
+        self.frame_number += 1;
+        Ok(Frame::Rgb(frame))
+    }
+}
+
+/// Loops over the PNG/JPEG files of a directory in file name order.
+pub struct ImageDir {
+    files: Vec<PathBuf>,
+    position: usize,
+    width: u32,
+    height: u32,
+    pacer: Pacer,
+}
+
+impl ImageDir {
+    pub fn open(dir: &Path, width: u32, height: u32, framerate: u32) -> Result<Self> {
+        let mut files = vec![];
+        for entry in std::fs::read_dir(dir)? {
+            let path = entry?.path();
+            let is_image = path
+                .extension()
+                .and_then(|ext| ext.to_str())
+                .map(|ext| matches!(ext.to_ascii_lowercase().as_str(), "png" | "jpg" | "jpeg"))
+                .unwrap_or(false);
+            if is_image {
+                files.push(path);
+            }
+        }
+        if files.is_empty() {
+            anyhow::bail!("no PNG or JPEG frames in {}", dir.display());
+        }
+        files.sort();
+        info!("playing {} frames from {}", files.len(), dir.display());
+        Ok(Self {
+            files,
+            position: 0,
+            width,
+            height,
+            pacer: Pacer::new(framerate),
+        })
+    }
+}
+
+impl VideoSource for ImageDir {
+    fn frame(&mut self) -> Result<Frame> {
+        self.pacer.wait();
+        let path = &self.files[self.position];
+        self.position = (self.position + 1) % self.files.len();
+        let frame = image::open(path)?.to_rgb8();
+        if frame.dimensions() == (self.width, self.height) {
+            Ok(Frame::Rgb(frame))
+        } else {
+            Ok(Frame::Rgb(imageops::resize(
+                &frame,
+                self.width,
+                self.height,
+                imageops::FilterType::Triangle,
+            )))
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Y4mChroma {
+    Cs420,
+    Cs422,
+    Cs444,
+    Mono,
+}
+
+/// Loops over an 8 bit YUV4MPEG2 file.
+pub struct Y4mFile {
+    reader: BufReader<File>,
+    data_start: u64,
+    width: usize,
+    height: usize,
+    chroma: Y4mChroma,
+    buf: Vec<u8>,
+    pacer: Pacer,
+}
+
+impl Y4mFile {
+    pub fn open(path: &Path, framerate: u32) -> Result<Self> {
+        let mut reader = BufReader::new(File::open(path)?);
+        let mut header = String::new();
+        reader.read_line(&mut header)?;
+        let mut params = header.split_ascii_whitespace();
+        if params.next() != Some("YUV4MPEG2") {
+            anyhow::bail!("{} is not a y4m file", path.display());
+        }
+        let (mut width, mut height, mut chroma) = (0, 0, Y4mChroma::Cs420);
+        for param in params {
+            let (tag, value) = param.split_at(1);
+            match tag {
+                "W" => width = value.parse()?,
+                "H" => height = value.parse()?,
+                "C" => {
+                    chroma = match value {
+                        "420" | "420jpeg" | "420paldv" | "420mpeg2" => Y4mChroma::Cs420,
+                        "422" => Y4mChroma::Cs422,
+                        "444" => Y4mChroma::Cs444,
+                        "mono" => Y4mChroma::Mono,
+                        other => anyhow::bail!("unsupported y4m colorspace {other}"),
+                    }
+                }
+                _ => {}
+            }
+        }
+        if width == 0 || height == 0 {
+            anyhow::bail!("y4m header of {} has no frame size", path.display());
+        }
+        let data_start = reader.stream_position()?;
+        info!(
+            "playing {width}x{height} {chroma:?} frames from {}",
+            path.display()
+        );
+        Ok(Self {
+            reader,
+            data_start,
+            width,
+            height,
+            chroma,
+            buf: vec![],
+            pacer: Pacer::new(framerate),
+        })
+    }
+
+    fn chroma_size(&self) -> (usize, usize) {
+        match self.chroma {
+            Y4mChroma::Cs420 => (self.width.div_ceil(2), self.height.div_ceil(2)),
+            Y4mChroma::Cs422 => (self.width.div_ceil(2), self.height),
+            Y4mChroma::Cs444 => (self.width, self.height),
+            Y4mChroma::Mono => (0, 0),
+        }
+    }
+
+    /// Reads the next `FRAME` line, rewinding to the first frame at the end of the file.
+    fn next_frame_header(&mut self) -> Result<()> {
+        let mut line = String::new();
+        if self.reader.read_line(&mut line)? == 0 {
+            self.reader.seek(SeekFrom::Start(self.data_start))?;
+            self.reader.read_line(&mut line)?;
+        }
+        if !line.starts_with("FRAME") {
+            anyhow::bail!("corrupt y4m frame header");
+        }
+        Ok(())
+    }
+}
+
+impl VideoSource for Y4mFile {
+    fn frame(&mut self) -> Result<Frame> {
+        self.pacer.wait();
+        self.next_frame_header()?;
+        let (chroma_width, chroma_height) = self.chroma_size();
+        let luma_len = self.width * self.height;
+        let chroma_len = chroma_width * chroma_height;
+        self.buf.resize(luma_len + 2 * chroma_len, 0);
+        self.reader.read_exact(&mut self.buf)?;
+
+        if let Y4mChroma::Cs420 = self.chroma {
+            // already what the encoders want
+            return Ok(Frame::Yuv {
+                layout: YuvLayout::I420,
+                width: self.width as u32,
+                height: self.height as u32,
+                data: self.buf.clone(),
+            });
+        }
+        // the encoders take 4:2:0, so wider chroma is averaged down and grey gets neutral chroma
+        let (luma, chroma) = self.buf.split_at(luma_len);
+        let (width, height) = (self.width.div_ceil(2), self.height.div_ceil(2));
+        let mut data = Vec::with_capacity(luma_len + 2 * width * height);
+        data.extend_from_slice(luma);
+        let (x_step, y_step) = match self.chroma {
+            Y4mChroma::Cs422 => (1, 2),
+            _ => (2, 2),
+        };
+        if chroma_len == 0 {
+            data.resize(luma_len + 2 * width * height, 128);
+        } else {
+            for plane in chroma.chunks_exact(chroma_len) {
+                for y in 0..height {
+                    let rows = [y * y_step, (y * y_step + y_step - 1).min(chroma_height - 1)];
+                    for x in 0..width {
+                        let columns = [x * x_step, (x * x_step + x_step - 1).min(chroma_width - 1)];
+                        let sum: u16 = rows
+                            .iter()
+                            .flat_map(|row| {
+                                columns.map(|column| plane[row * chroma_width + column])
+                            })
+                            .map(u16::from)
+                            .sum();
+                        data.push(((sum + 2) / 4) as u8);
+                    }
+                }
+            }
+        }
+        Ok(Frame::Yuv {
+            layout: YuvLayout::I420,
+            width: self.width as u32,
+            height: self.height as u32,
+            data,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use image::ImageFormat;
+
+    use super::*;
+
+    #[test]
+    fn source_kinds() {
+        let parse = |input: &str| input.parse::<SourceKind>().unwrap();
+        assert_eq!(parse("camera"), SourceKind::Camera(0));
+        assert_eq!(parse("camera:2"), SourceKind::Camera(2));
+        assert_eq!(parse("test-pattern"), SourceKind::TestPattern);
+        assert_eq!(
+            parse("dir:/srv/frames"),
+            SourceKind::ImageDir("/srv/frames".into())
+        );
+        assert_eq!(parse("y4m:clip.y4m"), SourceKind::Y4m("clip.y4m".into()));
+        for bad in ["", "dir", "camera:front", "rtsp://camera", "test-pattern:1"] {
+            assert!(bad.parse::<SourceKind>().is_err(), "{bad:?} parsed");
+        }
+    }
+
+    #[test]
+    fn test_pattern_frames() {
+        let mut source = TestPattern::new(64, 48, 1000);
+        for _ in 0..3 {
+            let Frame::Rgb(frame) = source.frame().unwrap() else {
+                panic!("the test pattern is drawn in RGB");
+            };
+            assert_eq!(frame.dimensions(), (64, 48));
+            // right of the box and below the counter sits the last color bar
+            assert_eq!(*frame.get_pixel(63, 30), Rgb(BARS[6]));
+            // the counter's background
+            assert_eq!(*frame.get_pixel(0, 0), Rgb([0, 0, 0]));
+        }
+        assert_eq!(source.frame_number, 3);
+    }
+
+    fn y4m(contents: &[u8]) -> tempfile::NamedTempFile {
+        let mut file = tempfile::NamedTempFile::new().unwrap();
+        file.write_all(contents).unwrap();
+        file
+    }
+
+    #[test]
+    fn bad_y4m_headers() {
+        let error = |contents: &[u8]| {
+            let file = y4m(contents);
+            match Y4mFile::open(file.path(), 1000) {
+                Ok(_) => panic!("opened {:?}", String::from_utf8_lossy(contents)),
+                Err(e) => e.to_string(),
+            }
+        };
+        assert!(error(b"YUV4MPEG W4 H2\n").ends_with("is not a y4m file"));
+        assert!(error(b"").ends_with("is not a y4m file"));
+        assert!(error(b"YUV4MPEG2 W4\n").ends_with("has no frame size"));
+        assert!(error(b"YUV4MPEG2 W0 H2\n").ends_with("has no frame size"));
+        assert_eq!(
+            error(b"YUV4MPEG2 W4 H2 C410\n"),
+            "unsupported y4m colorspace 410"
+        );
+        assert!(Y4mFile::open(Path::new("/nonexistent/clip.y4m"), 1000).is_err());
+    }
+
+    #[test]
+    fn y4m_frames_loop() {
+        // two 4x2 4:2:0 frames: 8 luma, 2 Cb and 2 Cr bytes each
+        let mut contents = b"YUV4MPEG2 W4 H2 F25:1 C420jpeg\n".to_vec();
+        for value in [16, 235] {
+            contents.extend_from_slice(b"FRAME\n");
+            contents.extend_from_slice(&[value; 12]);
+        }
+        let file = y4m(&contents);
+        let mut source = Y4mFile::open(file.path(), 1000).unwrap();
+        for expected in [16, 235, 16] {
+            let Frame::Yuv {
+                layout,
+                width,
+                height,
+                data,
+            } = source.frame().unwrap()
+            else {
+                panic!("4:2:0 is passed on as it is");
+            };
+            assert_eq!(layout, YuvLayout::I420);
+            assert_eq!((width, height), (4, 2));
+            assert_eq!(data, vec![expected; 12]);
+        }
+    }
+
+    #[test]
+    fn y4m_becomes_420() {
+        // 2x2 frames with Cb then Cr at full, half horizontal and no chroma resolution
+        let frames: [(&str, &[u8], [u8; 2]); 3] = [
+            ("444", &[10, 20, 30, 40, 100, 100, 100, 104], [25, 101]),
+            ("422", &[10, 30, 100, 104], [20, 102]),
+            ("mono", &[], [128, 128]),
+        ];
+        for (chroma, samples, expected) in frames {
+            let mut contents = format!("YUV4MPEG2 W2 H2 C{chroma}\nFRAME\n").into_bytes();
+            contents.extend_from_slice(&[50; 4]);
+            contents.extend_from_slice(samples);
+            let file = y4m(&contents);
+            let mut source = Y4mFile::open(file.path(), 1000).unwrap();
+            let Frame::Yuv { layout, data, .. } = source.frame().unwrap() else {
+                panic!("y4m frames stay YCbCr");
+            };
+            assert_eq!(layout, YuvLayout::I420);
+            assert_eq!(data, [50, 50, 50, 50, expected[0], expected[1]], "{chroma}");
+        }
+    }
+
+    #[test]
+    fn image_dir_plays_in_file_name_order() {
+        let dir = tempfile::tempdir().unwrap();
+        for (name, level) in [("10.png", 10), ("2.PNG", 20), ("1.png", 1)] {
+            RgbFrame::from_pixel(2, 2, Rgb([level; 3]))
+                .save_with_format(dir.path().join(name), ImageFormat::Png)
+                .unwrap();
+        }
+        std::fs::write(dir.path().join("notes.txt"), "not a frame").unwrap();
+
+        let mut source = ImageDir::open(dir.path(), 2, 2, 1000).unwrap();
+        let levels: Vec<u8> = (0..4)
+            .map(|_| match source.frame().unwrap() {
+                Frame::Rgb(frame) => frame.get_pixel(0, 0)[0],
+                _ => panic!("images are decoded to RGB"),
+            })
+            .collect();
+        assert_eq!(levels, vec![1, 10, 20, 1]);
+    }
+
+    #[test]
+    fn empty_image_dir() {
+        let dir = tempfile::tempdir().unwrap();
+        assert!(ImageDir::open(dir.path(), 2, 2, 1000).is_err());
+    }
+}
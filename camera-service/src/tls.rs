@@ -0,0 +1,33 @@
+use std::{fs::File, io::BufReader, path::Path};
+
+use anyhow::Context as _;
+use tokio_tungstenite::Connector;
+
+use crate::prelude::*;
+
+/// How `wss://` signaling servers are verified. Without a CA bundle the webpki roots are
+/// trusted, with one only the certificates in it are, which is what self-signed servers need.
+pub fn connector(ca_bundle: Option<&Path>) -> Result<Option<Connector>> {
+    let Some(path) = ca_bundle else {
+        return Ok(None);
+    };
+    let certs = rustls_pemfile::certs(&mut BufReader::new(
+        File::open(path).with_context(|| format!("can't open CA bundle {}", path.display()))?,
+    ))
+    .collect::<Result<Vec<_>, _>>()
+    .with_context(|| format!("invalid CA bundle {}", path.display()))?;
+
+    let mut roots = rustls::RootCertStore::empty();
+    for cert in certs {
+        roots
+            .add(cert)
+            .with_context(|| format!("invalid certificate in {}", path.display()))?;
+    }
+    if roots.is_empty() {
+        anyhow::bail!("no certificate in CA bundle {}", path.display());
+    }
+    let config = rustls::ClientConfig::builder()
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+    Ok(Some(Connector::Rustls(Arc::new(config))))
+}
@@ -1,42 +1,112 @@
 use crate::ice::{self, received_new_ice_candidate};
-use crate::sdp::{receive_sdp_answer, receive_sdp_offer_send_answer};
+use crate::sdp::receive_sdp_answer;
 use crate::ui::*;
 use std::cell::RefCell;
 use std::convert::TryInto;
 use std::rc::Rc;
 
-use js_sys::{Array, Object, Reflect};
+use js_sys::Array;
 use log::{debug, error, info, warn};
 use wasm_bindgen::closure::Closure;
 use wasm_bindgen::{JsCast, JsValue, UnwrapThrowExt};
 use web_sys::console::info;
 use web_sys::{
-    Document, Element, HtmlButtonElement, HtmlVideoElement, MediaStream, MessageEvent,
+    Document, Element, HtmlButtonElement, HtmlVideoElement, MediaStream, MessageEvent, MouseEvent,
     RtcConfiguration, RtcDataChannel, RtcDataChannelEvent, RtcIceConnectionState,
-    RtcIceCredentialType, RtcIceServer, RtcIceTransportPolicy, RtcPeerConnection, WebSocket,
+    RtcIceCredentialType, RtcIceServer, RtcPeerConnection, WebSocket,
 };
 
 use protocol::*;
 
+use crate::control::{create_control_channel, setup_keyboard_control, show_session};
 use crate::{create_sdp_offer, setup_rtc_peer_connection_ice_callbacks};
 
-const STUN_SERVER: &str = "stun:stun.l.google.com:19302";
-const TURN: &str = "turn:64.226.89.43:3478";
-
 #[derive(Debug)]
 pub struct AppState {
     user_id: Option<UserId>,
-    tanks: Option<Vec<TankId>>,
+    tanks: Vec<TankListing>,
+    /// Tank picked in the tank list, connected to with the connect button.
+    selected_tank: Option<TankId>,
+    tank_id: Option<TankId>,
+    /// Candidates that arrived before the tank's answer, added once it is applied.
+    pending_candidates: Option<Vec<Option<IceCandidate>>>,
 }
 
 impl AppState {
     pub(crate) fn new() -> Self {
         AppState {
             user_id: None,
-            tanks: None,
+            tanks: vec![],
+            selected_tank: None,
+            tank_id: None,
+            pending_candidates: None,
         }
     }
 
+    pub(crate) fn set_tanks(&mut self, tanks: Vec<TankListing>) {
+        self.tanks = tanks;
+    }
+
+    pub(crate) fn apply_presence(&mut self, presence: TankPresence) {
+        match presence {
+            TankPresence::Online(listing) => {
+                self.tanks.retain(|tank| tank.info.id != listing.info.id);
+                self.tanks.push(listing);
+            }
+            TankPresence::Offline(tank_id) => {
+                self.tanks.retain(|tank| tank.info.id != tank_id);
+                if self.selected_tank.as_ref() == Some(&tank_id) {
+                    self.selected_tank = None;
+                }
+            }
+            TankPresence::Busy(tank_id, busy) => {
+                if let Some(tank) = self.tanks.iter_mut().find(|tank| tank.info.id == tank_id) {
+                    tank.busy = busy;
+                }
+            }
+        }
+    }
+
+    pub(crate) fn select_tank(&mut self, tank_id: TankId) {
+        self.selected_tank = Some(tank_id);
+    }
+
+    pub(crate) fn get_selected_tank(&self) -> Option<TankId> {
+        self.selected_tank.clone()
+    }
+
+    pub(crate) fn render_tanks(&self) {
+        render_tank_list(&self.tanks, self.selected_tank.as_ref());
+    }
+
+    /// Starts a connection attempt to `tank_id`, buffering its candidates until the answer arrives.
+    pub(crate) fn set_tank_id(&mut self, tank_id: TankId) {
+        self.tank_id = Some(tank_id);
+        self.pending_candidates = Some(vec![]);
+    }
+
+    pub(crate) fn get_tank_id(&self) -> Option<TankId> {
+        self.tank_id.clone()
+    }
+
+    /// Returns the candidate back if it can be added right away.
+    pub(crate) fn buffer_candidate(
+        &mut self,
+        candidate: Option<IceCandidate>,
+    ) -> Option<Option<IceCandidate>> {
+        match &mut self.pending_candidates {
+            Some(pending) => {
+                pending.push(candidate);
+                None
+            }
+            None => Some(candidate),
+        }
+    }
+
+    pub(crate) fn take_pending_candidates(&mut self) -> Vec<Option<IceCandidate>> {
+        self.pending_candidates.take().unwrap_or_default()
+    }
+
     pub(crate) fn set_user_id(&mut self, user_id: UserId) {
         self.user_id = Some(user_id)
     }
@@ -50,62 +120,34 @@ pub fn create_plain_peer_connection() -> Result<RtcPeerConnection, JsValue> {
     RtcPeerConnection::new()
 }
 
-pub fn create_turn_peer_connection() -> Result<RtcPeerConnection, JsValue> {
-    // STUN HERE
-    let stun_server = RtcIceServer::new();
-    stun_server.set_url(&STUN_SERVER);
-
-    // TURN SERVER
-    let turn_url = format!("{}", TURN);
-    warn!("Turn URL: {}", TURN);
-    let turn_server = RtcIceServer::new();
-    turn_server.set_url(&turn_url);
-    let r_num = f64::ceil(js_sys::Math::random() * 10.0);
-    let r_num2 = r_num as u8;
-
-    // Both users can have the same username + password,
-    // The turn server doesnt really care
-    let user = format!("user{}", r_num2);
-    let pass = format!("pass{}", r_num2);
-
-    info!("{}", format!("Creds: user:{} pass:{}", user, pass));
-    turn_server.set_username(&user);
-    turn_server.set_credential(&pass);
-
-    // turn_server.credential_type( RtcIceCredentialType::Token);
-    turn_server.set_credential_type(RtcIceCredentialType::Password);
-    let turn_server_ref: &JsValue = turn_server.as_ref();
-    let rtc_config = RtcConfiguration::new();
-    // let arr_ice_svr = Array::of2(turn_server_ref,stun_server_ref);
-    let arr_ice_svr = Array::of1(turn_server_ref);
-    warn!("ICE server Length {}", arr_ice_svr.length());
-    let arr_ice_svr_ref: &JsValue = arr_ice_svr.as_ref();
-    rtc_config.set_ice_servers(arr_ice_svr_ref);
-
-    // rtc_config.ice_transport_policy(RtcIceTransportPolicy::All);
-    // warn!("All transport");
-    // let transport_policy = RtcIceTransportPolicy::All;
-    let transport_policy = RtcIceTransportPolicy::Relay;
-    warn!("ICE transport {:?}", transport_policy);
-    rtc_config.set_ice_transport_policy(transport_policy); // This is to force use of a TURN Server
-
-    RtcPeerConnection::new_with_configuration(&rtc_config)
-}
-
-pub fn create_stun_peer_connection() -> Result<RtcPeerConnection, JsValue> {
+/// Points the peer connection at the STUN/TURN servers the signaling server handed out.
+/// Has to happen before the offer is created to take effect for the tank's connection.
+pub fn apply_ice_servers(
+    rtc_conn: &RtcPeerConnection,
+    servers: &[IceServer],
+) -> Result<(), JsValue> {
     let ice_servers = Array::new();
-    {
-        let server_entry = Object::new();
-
-        Reflect::set(&server_entry, &"urls".into(), &STUN_SERVER.into())?;
-
-        ice_servers.push(&*server_entry);
+    for server in servers {
+        let entry = RtcIceServer::new();
+        let urls: Array = server
+            .urls
+            .iter()
+            .map(|url| JsValue::from_str(url))
+            .collect();
+        entry.set_urls(&urls);
+        if let Some(username) = &server.username {
+            entry.set_username(username);
+        }
+        if let Some(credential) = &server.credential {
+            entry.set_credential(credential);
+            entry.set_credential_type(RtcIceCredentialType::Password);
+        }
+        ice_servers.push(&entry);
     }
 
     let rtc_configuration = RtcConfiguration::new();
     rtc_configuration.set_ice_servers(&ice_servers);
-
-    RtcPeerConnection::new_with_configuration(&rtc_configuration)
+    rtc_conn.set_configuration_with_configuration(&rtc_configuration)
 }
 
 pub async fn handle_message_reply(
@@ -178,7 +220,18 @@ pub async fn handle_message_reply(
         //     info!("{}", session_id.inner())
         // }
         SignalEnum::Start => {
-            let signal = SignalEnum::UserCommand(UserCommand::Login);
+            let credentials = match (get_query_param("token"), get_query_param("api_key")) {
+                (Some(token), _) => OperatorCredentials::Token(token),
+                (None, Some(api_key)) => OperatorCredentials::ApiKey(api_key),
+                (None, None) => {
+                    error!("No credentials to log in with");
+                    set_session_connection_status_error(
+                        "add ?api_key=... or ?token=... to the page URL".into(),
+                    );
+                    return Ok(());
+                }
+            };
+            let signal = SignalEnum::UserCommand(UserCommand::Login(credentials));
             match serde_json_wasm::to_string(&signal) {
                 Ok(x) => match websocket.send_with_str(&x) {
                     Ok(_) => info!("Video Offer SignalEnum sent"),
@@ -195,18 +248,61 @@ pub async fn handle_message_reply(
                 let mut state = app_state.borrow_mut();
                 state.set_user_id(user_id);
             }
-            UserMessage::CameraListGetSuccess(tank_list) => {
-                for t in tank_list {
-                    info!("{0}", t.inner());
+            UserMessage::IceServers(servers) => {
+                info!("using ice servers {:?}", servers);
+                apply_ice_servers(&peer_connection, &servers)?;
+            }
+            UserMessage::LoginError(reason) => {
+                error!("Login rejected: {}", reason);
+                set_session_connection_status_error(reason);
+            }
+            UserMessage::EmergencyStop(tank_id, reason) => {
+                error!("tank {} stopped itself: {}", tank_id.inner(), reason);
+                set_session_connection_status_error(format!("Emergency stop: {}", reason));
+            }
+            UserMessage::SessionUpdate(tank_id, session) => {
+                info!("session of {} is now {:?}", tank_id.as_str(), session);
+                if app_state.borrow().get_tank_id() == Some(tank_id) {
+                    show_session(&session, app_state.borrow().user_id.as_ref());
                 }
             }
-            UserMessage::IceOfferAnswer(tank_id, data) => {
-                info!("received answer from {0}", tank_id.inner());
-                received_new_ice_candidate(data, peer_connection.clone());
+            UserMessage::SessionClosed(tank_id, reason) => {
+                warn!("session of {} closed: {}", tank_id.as_str(), reason);
+                if app_state.borrow().get_tank_id() == Some(tank_id) {
+                    set_session_connection_status_error(reason);
+                    set_html_label("control_status", "".into());
+                }
+            }
+            UserMessage::ControlDenied(tank_id, reason) => {
+                warn!("control of {} denied: {}", tank_id.as_str(), reason);
+                set_html_label("control_status", format!("Control denied: {}", reason));
+            }
+            UserMessage::CameraListGetSuccess(tank_list) => {
+                info!("{} tanks online", tank_list.len());
+                let mut state = app_state.borrow_mut();
+                state.set_tanks(tank_list);
+                state.render_tanks();
+            }
+            UserMessage::TankPresence(presence) => {
+                info!("tank presence {:?}", presence);
+                let mut state = app_state.borrow_mut();
+                state.apply_presence(presence);
+                state.render_tanks();
+            }
+            UserMessage::IceCandidate(tank_id, candidate) => {
+                info!("received ice candidate from {0}", tank_id.inner());
+                let ready = app_state.borrow_mut().buffer_candidate(candidate);
+                if let Some(candidate) = ready {
+                    received_new_ice_candidate(candidate, peer_connection.clone()).await?;
+                }
             }
             UserMessage::SdpAnswer(tank_id, data) => {
                 info!("received sdp answer from {0}", tank_id.inner());
-                receive_sdp_offer_send_answer(peer_connection.clone(), data);
+                receive_sdp_answer(peer_connection.clone(), data).await?;
+                let pending = app_state.borrow_mut().take_pending_candidates();
+                for candidate in pending {
+                    received_new_ice_candidate(candidate, peer_connection.clone()).await?;
+                }
             }
         },
         // SignalEnum::ICEError(err, session_id) => {
@@ -219,6 +315,28 @@ pub async fn handle_message_reply(
     Ok(())
 }
 
+/// Clicking a tank in the tank list selects it for the connect button.
+pub fn setup_tank_list(state: Rc<RefCell<AppState>>) {
+    let window = web_sys::window().expect("No window Found");
+    let document: Document = window.document().expect("Couldn't Get Document");
+
+    let list_cb = Closure::wrap(Box::new(move |ev: MouseEvent| {
+        if let Some(tank_id) = clicked_tank_id(&ev) {
+            info!("selected tank {}", tank_id.as_str());
+            let mut state = state.borrow_mut();
+            state.select_tank(tank_id);
+            state.render_tanks();
+        }
+    }) as Box<dyn FnMut(MouseEvent)>);
+
+    document
+        .get_element_by_id("camera-list")
+        .expect("should have camera-list on the page")
+        .add_event_listener_with_callback("click", list_cb.as_ref().unchecked_ref())
+        .expect("Could not listen to clicks on the tank list");
+    list_cb.forget();
+}
+
 pub fn setup_show_state(rtc_conn: RtcPeerConnection, state: Rc<RefCell<AppState>>) {
     let window = web_sys::window().expect("No window Found");
     let document: Document = window.document().expect("Couldn't Get Document");
@@ -369,15 +487,6 @@ pub async fn setup_listener(
     Ok(())
 }
 
-fn peer_a_dc_on_message(dc: RtcDataChannel) -> Closure<dyn FnMut(MessageEvent)> {
-    Closure::wrap(Box::new(move |ev: MessageEvent| {
-        if let Some(message) = ev.data().as_string() {
-            warn!("{:?}", message);
-            dc.send_with_str("Pong from peer_a data channel!").unwrap();
-        }
-    }) as Box<dyn FnMut(MessageEvent)>)
-}
-
 pub async fn setup_initiator(
     peer_a: RtcPeerConnection,
     websocket: WebSocket,
@@ -391,24 +500,20 @@ pub async fn setup_initiator(
     let rc_state_clone_ext = rc_state;
 
     /*
-     * Create DataChannel on peer_a to negotiate
-     * Message will be shown here after connection established
+     * Create the control DataChannel on peer_a before the offer,
+     * so it gets negotiated along with the video
      */
 
     info!("peer_a State 1: {:?}", peer_a.signaling_state());
-    let dc1 = peer_a.create_data_channel("my-data-channel");
-    info!("dc1 created: label {:?}", dc1.label());
-
-    let dc1_clone = dc1.clone();
-    let onmessage_callback = peer_a_dc_on_message(dc1_clone);
-    dc1.set_onmessage(Some(onmessage_callback.as_ref().unchecked_ref()));
-    onmessage_callback.forget();
+    let control_channel = create_control_channel(&peer_a);
+    setup_keyboard_control(&document, control_channel);
 
     let btn_cb = Closure::wrap(Box::new(move || {
         let ws_clone = ws_clone_external.clone();
         let peer_a_clone = peer_a_clone_external.clone();
         let peer_a_clone2 = peer_a_clone.clone();
         let rc_state_clone = rc_state_clone_ext.clone();
+        let rc_state_clone2 = rc_state_clone_ext.clone();
 
         let ws_clone1 = ws_clone.clone();
         wasm_bindgen_futures::spawn_local(async move {
@@ -418,7 +523,7 @@ pub async fn setup_initiator(
             if res.is_err() {
                 log::error!("Error Setting up ice callbacks {:?}", res.unwrap_err())
             }
-            try_connect_to_session(peer_a_clone2, ws_clone).await;
+            try_connect_to_session(peer_a_clone2, ws_clone, rc_state_clone2).await;
         });
     }) as Box<dyn FnMut()>);
     document
@@ -475,9 +580,17 @@ fn rtc_ice_state_change(
     }) as Box<dyn FnMut()>)
 }
 
-async fn try_connect_to_session(rtc_conn: RtcPeerConnection, ws: WebSocket) {
-    let session_id_string = get_session_id_from_input();
-    let session_id = TankId::new(session_id_string);
+async fn try_connect_to_session(
+    rtc_conn: RtcPeerConnection,
+    ws: WebSocket,
+    rc_state: Rc<RefCell<AppState>>,
+) {
+    let Some(session_id) = rc_state.borrow().get_selected_tank() else {
+        set_session_connection_status_error("pick a tank from the list first".into());
+        return;
+    };
+    set_session_connection_status_error("".into());
+    rc_state.borrow_mut().set_tank_id(session_id.clone());
     let sdp_offer = create_sdp_offer(rtc_conn).await.unwrap_throw();
     let msg = SignalEnum::UserCommand(UserCommand::SdpOffer(session_id, sdp_offer));
     let ser_msg: String = match serde_json_wasm::to_string(&msg) {
@@ -0,0 +1,184 @@
+use std::cell::RefCell;
+use std::collections::HashSet;
+use std::rc::Rc;
+
+use log::{error, info, warn};
+use protocol::{
+    ControlCommand, SessionInfo, SignalEnum, TankId, UserCommand, UserId, CONTROL_CHANNEL,
+};
+use wasm_bindgen::closure::Closure;
+use wasm_bindgen::JsCast;
+use web_sys::{
+    Document, HtmlButtonElement, KeyboardEvent, RtcDataChannel, RtcDataChannelInit,
+    RtcDataChannelState, RtcPeerConnection, WebSocket,
+};
+
+use crate::common::AppState;
+use crate::ui::set_html_label;
+
+// W/S drive, A/D steer, arrow keys move the turret, space stops everything.
+
+/// How often the drive state is repeated. The channel drops whatever gets lost instead of
+/// retransmitting it, and the repeats are what keeps the tank's dead-man switch from stopping
+/// it, so this has to stay well below its timeout, which defaults to one second.
+const STATE_INTERVAL_MS: i32 = 250;
+
+/// Creates the control data channel. Stale drive commands are worse than lost ones,
+/// so the channel is unordered and never retransmits.
+pub fn create_control_channel(peer: &RtcPeerConnection) -> RtcDataChannel {
+    let init = RtcDataChannelInit::new();
+    init.set_ordered(false);
+    init.set_max_retransmits(0);
+    let dc = peer.create_data_channel_with_data_channel_dict(CONTROL_CHANNEL, &init);
+    info!("control channel created: label {:?}", dc.label());
+    dc
+}
+
+fn axis(keys: &HashSet<String>, positive: &str, negative: &str) -> f32 {
+    match (keys.contains(positive), keys.contains(negative)) {
+        (true, false) => 1.0,
+        (false, true) => -1.0,
+        _ => 0.0,
+    }
+}
+
+fn send_command(dc: &RtcDataChannel, command: ControlCommand) {
+    if dc.ready_state() != RtcDataChannelState::Open {
+        return;
+    }
+    match serde_json_wasm::to_string(&command) {
+        Ok(message) => {
+            if let Err(e) = dc.send_with_str(&message) {
+                error!("Error sending control command {:?}", e);
+            }
+        }
+        Err(e) => error!("Could not serialize control command {}", e),
+    }
+}
+
+fn send_state(dc: &RtcDataChannel, keys: &HashSet<String>) {
+    send_command(
+        dc,
+        ControlCommand::Drive {
+            throttle: axis(keys, "KeyW", "KeyS"),
+            steering: axis(keys, "KeyD", "KeyA"),
+        },
+    );
+    send_command(
+        dc,
+        ControlCommand::Turret {
+            rotation: axis(keys, "ArrowRight", "ArrowLeft"),
+            elevation: axis(keys, "ArrowUp", "ArrowDown"),
+        },
+    );
+}
+
+const CONTROL_KEYS: [&str; 8] = [
+    "KeyW",
+    "KeyS",
+    "KeyA",
+    "KeyD",
+    "ArrowUp",
+    "ArrowDown",
+    "ArrowLeft",
+    "ArrowRight",
+];
+
+/// Sends drive and turret commands whenever the pressed keys change, and repeats the whole
+/// state periodically so a lost packet doesn't leave the tank doing the wrong thing and the
+/// tank knows this operator is still there.
+pub fn setup_keyboard_control(document: &Document, dc: RtcDataChannel) {
+    let keys = Rc::new(RefCell::new(HashSet::<String>::new()));
+
+    let (keydown_keys, keydown_dc) = (keys.clone(), dc.clone());
+    let keydown_callback = Closure::wrap(Box::new(move |ev: KeyboardEvent| {
+        let code = ev.code();
+        if code == "Space" {
+            ev.prevent_default();
+            keydown_keys.borrow_mut().clear();
+            send_command(&keydown_dc, ControlCommand::Stop);
+        } else if CONTROL_KEYS.contains(&code.as_str()) {
+            ev.prevent_default();
+            if keydown_keys.borrow_mut().insert(code) {
+                send_state(&keydown_dc, &keydown_keys.borrow());
+            }
+        }
+    }) as Box<dyn FnMut(KeyboardEvent)>);
+
+    let (keyup_keys, keyup_dc) = (keys.clone(), dc.clone());
+    let keyup_callback = Closure::wrap(Box::new(move |ev: KeyboardEvent| {
+        if keyup_keys.borrow_mut().remove(&ev.code()) {
+            send_state(&keyup_dc, &keyup_keys.borrow());
+        }
+    }) as Box<dyn FnMut(KeyboardEvent)>);
+
+    let repeat_callback = Closure::wrap(Box::new(move || {
+        send_state(&dc, &keys.borrow());
+    }) as Box<dyn FnMut()>);
+    let window = web_sys::window().expect("No window Found");
+    if let Err(e) = window.set_interval_with_callback_and_timeout_and_arguments_0(
+        repeat_callback.as_ref().unchecked_ref(),
+        STATE_INTERVAL_MS,
+    ) {
+        error!("Could not start repeating the drive state {:?}", e);
+    }
+    repeat_callback.forget();
+
+    for (event, callback) in [("keydown", &keydown_callback), ("keyup", &keyup_callback)] {
+        if let Err(e) =
+            document.add_event_listener_with_callback(event, callback.as_ref().unchecked_ref())
+        {
+            error!("Could not listen to {} {:?}", event, e);
+        }
+    }
+    keydown_callback.forget();
+    keyup_callback.forget();
+}
+
+/// Wires the request/release/force buttons to the control lease of the tank we're connected to.
+pub fn setup_control_lease(document: &Document, ws: WebSocket, rc_state: Rc<RefCell<AppState>>) {
+    type Command = fn(TankId) -> UserCommand;
+    let buttons: [(&str, Command); 3] = [
+        ("request_control", UserCommand::RequestControl),
+        ("release_control", UserCommand::ReleaseControl),
+        ("force_control", UserCommand::ForceControl),
+    ];
+    for (button, command) in buttons {
+        let (ws, rc_state) = (ws.clone(), rc_state.clone());
+        let btn_cb = Closure::wrap(Box::new(move || {
+            let Some(tank_id) = rc_state.borrow().get_tank_id() else {
+                warn!("not connected to a tank");
+                return;
+            };
+            let msg = SignalEnum::UserCommand(command(tank_id));
+            match serde_json_wasm::to_string(&msg) {
+                Ok(message) => {
+                    if let Err(e) = ws.send_with_str(&message) {
+                        error!("Error sending {:?}", e);
+                    }
+                }
+                Err(e) => error!("Could not serialize {:?} {}", msg, e),
+            }
+        }) as Box<dyn FnMut()>);
+        document
+            .get_element_by_id(button)
+            .unwrap_or_else(|| panic!("Should have {} on the page", button))
+            .dyn_ref::<HtmlButtonElement>()
+            .expect("#Button should be a be an `HtmlButtonElement`")
+            .set_onclick(Some(btn_cb.as_ref().unchecked_ref()));
+        btn_cb.forget();
+    }
+}
+
+/// Shows whether we drive the tank or only watch it.
+pub fn show_session(session: &SessionInfo, user_id: Option<&UserId>) {
+    let status = match &session.controller {
+        Some(controller) if Some(controller) == user_id => "You are in control".to_string(),
+        Some(controller) => format!("Spectating, {} is in control", controller.as_str()),
+        None => "Spectating, nobody is in control".to_string(),
+    };
+    set_html_label(
+        "control_status",
+        format!("{} ({} watching)", status, session.spectators.len()),
+    );
+}
@@ -1,28 +1,18 @@
 use std::cell::RefCell;
 use std::rc::Rc;
 
-use js_sys::JSON;
 use log::{error, info, warn};
-use serde::{Deserialize, Serialize};
 use wasm_bindgen::closure::Closure;
-use wasm_bindgen::{JsCast, JsValue, UnwrapThrowExt};
+use wasm_bindgen::{JsCast, JsValue};
 use wasm_bindgen_futures::JsFuture;
 use web_sys::{
     RtcIceCandidate, RtcIceCandidateInit, RtcPeerConnection, RtcPeerConnectionIceEvent, WebSocket,
 };
 
-use protocol::SignalEnum;
+use protocol::{IceCandidate, SignalEnum, UserCommand};
 
 use crate::common::AppState;
 
-#[derive(Debug, Serialize, Deserialize)]
-#[allow(non_snake_case)]
-pub struct IceCandidate {
-    pub candidate: String,
-    pub sdpMid: String, // must be non-snake case as this is the key in the parsed JSON
-    pub sdpMLineIndex: u16, // must be non-snake case as this is the key in the parsed JSON
-}
-
 pub async fn setup_rtc_peer_connection_ice_callbacks(
     rtc_conn: RtcPeerConnection,
     ws: WebSocket,
@@ -48,54 +38,52 @@ pub fn sleep(ms: i32) -> js_sys::Promise {
     })
 }
 
+/// Trickles a local candidate to the tank we are connecting to,
+/// the final event without a candidate is sent as end-of-candidates.
 pub fn send_ice_candidate(
     ws: WebSocket,
     rc_state: Rc<RefCell<AppState>>,
     ev: RtcPeerConnectionIceEvent,
 ) {
-    match ev.candidate() {
-        Some(candidate) => {
-            let json_obj_candidate = candidate.to_json();
-            let res = JSON::stringify(&json_obj_candidate).unwrap_throw();
+    let Some(tank_id) = rc_state.borrow().get_tank_id() else {
+        warn!("ICE candidate without a tank to send it to");
+        return;
+    };
+    let candidate = ev.candidate().map(|candidate| IceCandidate {
+        candidate: candidate.candidate(),
+        sdp_mid: candidate.sdp_mid(),
+        sdp_mline_index: candidate.sdp_m_line_index(),
+        username_fragment: None,
+    });
+    info!("Sending ICE candidate {:?}", candidate);
 
-            info!("{0}", res);
-            //
-            // let js_ob = String::from(res.clone());
-            //
-            // let ws = ws.clone();
-            // let rc_state = rc_state.clone();
-            //
-            // let state = rc_state.borrow();
-            // drop(state);
-            // let signal = SignalEnum::OperatorCommand(js_ob, session_id);
-            // let ice_candidate: String = serde_json_wasm::to_string(&signal).unwrap();
-            // info!("Sending IceCandidate to Other peer {:?}", res);
-            // match ws.send_with_str(&ice_candidate) {
-            //     Ok(_) => info!("IceCandidate sent {}", ice_candidate),
-            //     Err(err) => error!("error sending IceCandidate SignalEnum: {:?}", err),
-            // }
-        }
-        None => {
-            info!("No ICE candidate in RtcPeerConnectionIceEvent");
+    let signal = SignalEnum::UserCommand(UserCommand::IceCandidate(tank_id, candidate));
+    match serde_json_wasm::to_string(&signal) {
+        Ok(message) => {
+            if let Err(err) = ws.send_with_str(&message) {
+                error!("error sending IceCandidate SignalEnum: {:?}", err);
+            }
         }
+        Err(e) => error!("Could not serialize IceCandidate {}", e),
     }
 }
 
+/// Adds a candidate trickled by the tank, `None` marks the end of its candidates.
 pub async fn received_new_ice_candidate(
-    candidate: String,
+    candidate: Option<IceCandidate>,
     rtc_conn: RtcPeerConnection,
 ) -> Result<(), JsValue> {
-    warn!("ICECandidate Received! {}", candidate);
-
-    let icecandidate = serde_json_wasm::from_str::<IceCandidate>(&candidate).map_err(|_| {
-        let message = format!("Could not deserialize Ice Candidate {} ", candidate);
-        JsValue::from_str(&message)
-    })?;
+    let Some(candidate) = candidate else {
+        info!("End of remote ICE candidates");
+        JsFuture::from(rtc_conn.add_ice_candidate_with_opt_rtc_ice_candidate(None)).await?;
+        return Ok(());
+    };
+    warn!("ICECandidate Received! {}", candidate.candidate);
 
     let rtc_ice_init = RtcIceCandidateInit::new("");
-    rtc_ice_init.set_candidate(&icecandidate.candidate);
-    rtc_ice_init.set_sdp_m_line_index(Some(icecandidate.sdpMLineIndex));
-    rtc_ice_init.set_sdp_mid(Some(&icecandidate.sdpMid));
+    rtc_ice_init.set_candidate(&candidate.candidate);
+    rtc_ice_init.set_sdp_m_line_index(candidate.sdp_mline_index);
+    rtc_ice_init.set_sdp_mid(candidate.sdp_mid.as_deref());
 
     match RtcIceCandidate::new(&rtc_ice_init) {
         Ok(x) => {
@@ -105,7 +93,10 @@ pub async fn received_new_ice_candidate(
             info!("Added other peer's Ice Candidate ! {:?}", result);
         }
         Err(e) => {
-            info!("Ice Candidate Addition error, {} | {:?}", candidate, e);
+            info!(
+                "Ice Candidate Addition error, {} | {:?}",
+                candidate.candidate, e
+            );
             return Err(e);
         }
     };
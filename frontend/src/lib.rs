@@ -1,4 +1,5 @@
 mod common;
+mod control;
 mod ice;
 mod panic_utils;
 mod sdp;
@@ -16,8 +17,9 @@ use wasm_bindgen::UnwrapThrowExt;
 
 use common::{
     create_plain_peer_connection, setup_initiator, setup_listener,
-    setup_show_signalling_server_state, setup_show_state, AppState,
+    setup_show_signalling_server_state, setup_show_state, setup_tank_list, AppState,
 };
+use control::setup_control_lease;
 use ice::setup_rtc_peer_connection_ice_callbacks;
 use panic_utils::set_panic_hook;
 use sdp::create_sdp_offer;
@@ -31,17 +33,20 @@ pub async fn start() {
 
     let state: Rc<RefCell<AppState>> = Rc::new(RefCell::new(AppState::new()));
 
+    // the ICE servers arrive from the signaling server after login
     let rtc_connection = create_plain_peer_connection().unwrap_throw();
 
-    // To run the TURN client you need to be running a turn server as well.
-    // let rtc_connection = create_turn_peer_connection().unwrap_throw();
-
     let websocket = open_web_socket(rtc_connection.clone(), state.clone())
         .await
         .unwrap_throw();
 
     setup_show_state(rtc_connection.clone(), state.clone());
+    setup_tank_list(state.clone());
     setup_show_signalling_server_state(websocket.clone());
+    let document = web_sys::window()
+        .and_then(|window| window.document())
+        .expect_throw("Couldn't Get Document");
+    setup_control_lease(&document, websocket.clone(), state.clone());
 
     setup_initiator(rtc_connection.clone(), websocket.clone(), state.clone())
         .await
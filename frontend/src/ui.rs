@@ -1,6 +1,7 @@
 use crate::wasm_bindgen;
 use js_sys::Promise;
 use log::*;
+use protocol::{TankId, TankListing};
 use wasm_bindgen::{JsCast, JsValue};
 use web_sys::*;
 
@@ -15,21 +16,58 @@ pub fn set_html_label(html_label: &str, session_id: String) {
         .set_text_content(Some(&session_id));
 }
 
-pub fn get_session_id_from_input() -> String {
+/// Reads a parameter from the page's query string, e.g. `?api_key=...`.
+pub fn get_query_param(name: &str) -> Option<String> {
+    let window = web_sys::window()?;
+    let search = window.location().search().ok()?;
+    UrlSearchParams::new_with_str(&search).ok()?.get(name)
+}
+
+/// Renders the tanks as buttons in `#camera-list`, each carrying its id in `data-tank-id`.
+pub fn render_tank_list(tanks: &[TankListing], selected: Option<&TankId>) {
     let window = web_sys::window().expect("No window Found, We've got bigger problems here");
     let document: Document = window.document().expect("Couldn't Get Document");
-    let sid_input = "sid_input";
-
-    let sid_input = document
-        .get_element_by_id(sid_input)
-        .unwrap_or_else(|| panic!("Should have {} on the page", sid_input))
-        .dyn_ref::<HtmlInputElement>()
-        .expect("#HtmlInputElement should be a be an `HtmlInputElement`")
-        .value()
-        .trim()
-        .to_string();
-    info!("sid_inputs {}", sid_input);
-    sid_input
+    let list_id = "camera-list";
+
+    let list = document
+        .get_element_by_id(list_id)
+        .unwrap_or_else(|| panic!("Should have {} on the page", list_id));
+    list.set_inner_html("");
+    if tanks.is_empty() {
+        list.set_text_content(Some("No tanks online"));
+        return;
+    }
+    for tank in tanks {
+        let Ok(button) = document.create_element("button") else {
+            error!("Could not create a button for {}", tank.info.id.as_str());
+            continue;
+        };
+        let status = if tank.busy { "busy" } else { "available" };
+        button.set_text_content(Some(&format!(
+            "{} ({}) - {}",
+            tank.info.name,
+            tank.info.id.as_str(),
+            status
+        )));
+        let _ = button.set_attribute("data-tank-id", tank.info.id.as_str());
+        if selected == Some(&tank.info.id) {
+            let _ = button.set_attribute("style", "font-weight: bold; outline-style: solid;");
+        }
+        if let Err(e) = list.append_child(&button) {
+            error!(
+                "Could not add {} to the list {:?}",
+                tank.info.id.as_str(),
+                e
+            );
+        }
+    }
+}
+
+/// Id of the tank whose button in the tank list was clicked.
+pub fn clicked_tank_id(ev: &MouseEvent) -> Option<TankId> {
+    let target = ev.target()?.dyn_into::<Element>().ok()?;
+    let button = target.closest("[data-tank-id]").ok()??;
+    button.get_attribute("data-tank-id").map(TankId::new)
 }
 
 pub fn set_session_connection_status_error(error: String) {
@@ -2,7 +2,7 @@ use std::cell::RefCell;
 use std::rc::Rc;
 
 use log::{error, info};
-use protocol::{SignalEnum, UserCommand};
+use protocol::{SignalEnum, UserCommand, SERVER_PORT};
 use wasm_bindgen::closure::Closure;
 use wasm_bindgen::{JsCast, JsValue};
 use web_sys::{
@@ -10,6 +10,7 @@ use web_sys::{
 };
 
 use crate::common::{handle_message_reply, AppState};
+use crate::ui::get_query_param;
 
 // From Workspace
 
@@ -20,15 +21,34 @@ use crate::common::{handle_message_reply, AppState};
 //    \  /\  /    |  __/ | |_) |    ____) | | (_) | | (__  |   <  |  __/ | |_
 //     \/  \/      \___| |_.__/    |_____/   \___/   \___| |_|\_\  \___|  \__|
 
-const WS_IP_PORT: &str = "ws://64.226.89.43:9002";
+/// `?signaling=wss://host:port` on the page URL, otherwise the signaling server's port on the
+/// host that served the page, over `wss://` when the page itself came over HTTPS.
+fn signaling_url() -> String {
+    if let Some(url) = get_query_param("signaling") {
+        return url;
+    }
+    let location = web_sys::window()
+        .expect("No window Found, We've got bigger problems here")
+        .location();
+    let scheme = match location.protocol().as_deref() {
+        Ok("https:") => "wss",
+        _ => "ws",
+    };
+    let host = match location.hostname() {
+        Ok(host) if !host.is_empty() => host,
+        _ => "127.0.0.1".to_owned(),
+    };
+    format!("{}://{}:{}", scheme, host, SERVER_PORT)
+}
 
 pub async fn open_web_socket(
     rtc_conn: RtcPeerConnection,
     rc_state: Rc<RefCell<AppState>>,
 ) -> Result<WebSocket, JsValue> {
-    info!("Opening WS Connection");
+    let url = signaling_url();
+    info!("Opening WS Connection to {}", url);
 
-    let ws = WebSocket::new(WS_IP_PORT)?;
+    let ws = WebSocket::new(&url)?;
 
     let onopen_callback = Closure::wrap(Box::new(move || {}) as Box<dyn FnMut()>);
     ws.set_onopen(Some(onopen_callback.as_ref().unchecked_ref()));
@@ -91,8 +111,7 @@ pub async fn open_web_socket(
             .expect("#Button should be a be an `HtmlLabelElement`")
             .set_text_content(Some(&format!(
                 "{} {} ?",
-                "Could not make Websocket Connection, Is the Signalling Server running on: ",
-                WS_IP_PORT
+                "Could not make Websocket Connection, Is the Signalling Server running on: ", url
             )));
     }) as Box<dyn FnMut(ErrorEvent)>);
     ws.set_onerror(Some(onerror_callback.as_ref().unchecked_ref()));
@@ -1,6 +1,12 @@
+use std::collections::BTreeMap;
+
 use serde::{Deserialize, Serialize};
 
-pub const SERVER_PORT: &str = "9000";
+/// Port the signaling server listens on unless configured otherwise.
+pub const SERVER_PORT: &str = "9002";
+
+/// Label of the data channel operators drive the tank over.
+pub const CONTROL_CHANNEL: &str = "control";
 
 #[derive(Debug, Serialize, Deserialize, Clone, Eq, PartialEq, Hash)]
 pub struct UserId(String);
@@ -12,6 +18,9 @@ impl UserId {
     pub fn inner(self) -> String {
         self.0
     }
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone, Eq, PartialEq, Hash)]
@@ -24,6 +33,88 @@ impl TankId {
     pub fn inner(self) -> String {
         self.0
     }
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+/// What a tank announces about itself when it logs in.
+#[derive(Debug, Serialize, Deserialize, Clone, Eq, PartialEq)]
+pub struct TankInfo {
+    pub id: TankId,
+    pub name: String,
+    /// Free-form hardware description, e.g. camera, encoder or resolution.
+    pub hardware: BTreeMap<String, String>,
+}
+
+/// A tank as operators see it in the tank list.
+#[derive(Debug, Serialize, Deserialize, Clone, Eq, PartialEq)]
+pub struct TankListing {
+    pub info: TankInfo,
+    /// Somebody holds the control lease, the tank can still be watched.
+    pub busy: bool,
+}
+
+/// Pushed to every logged-in operator whenever the tank list changes.
+#[derive(Debug, Serialize, Deserialize, Clone, Eq, PartialEq)]
+pub enum TankPresence {
+    Online(TankListing),
+    Offline(TankId),
+    Busy(TankId, bool),
+}
+
+/// How an operator proves who they are at login.
+#[derive(Debug, Serialize, Deserialize, Clone, Eq, PartialEq)]
+pub enum OperatorCredentials {
+    ApiKey(String),
+    /// Token signed by whoever holds the signaling server's token secret.
+    Token(String),
+}
+
+/// One trickled ICE candidate, `None` in its place marks the end of candidates.
+/// Serializes like the browser's `RTCIceCandidateInit`.
+#[derive(Debug, Serialize, Deserialize, Clone, Eq, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct IceCandidate {
+    pub candidate: String,
+    pub sdp_mid: Option<String>,
+    #[serde(rename = "sdpMLineIndex")]
+    pub sdp_mline_index: Option<u16>,
+    pub username_fragment: Option<String>,
+}
+
+/// A STUN or TURN server, serializes like the browser's `RTCIceServer`.
+#[derive(Debug, Serialize, Deserialize, Clone, Eq, PartialEq, Default)]
+pub struct IceServer {
+    pub urls: Vec<String>,
+    pub username: Option<String>,
+    pub credential: Option<String>,
+}
+
+/// Who is watching a tank and who holds its control lease.
+#[derive(Debug, Serialize, Deserialize, Clone, Eq, PartialEq, Default)]
+pub struct SessionInfo {
+    /// The only operator whose control commands the tank follows.
+    pub controller: Option<UserId>,
+    /// Everyone else in the session, they get video but can't drive.
+    pub spectators: Vec<UserId>,
+}
+
+/// Sent by the operator over the [`CONTROL_CHANNEL`], axes range from -1.0 to 1.0.
+/// Drive and turret state are repeated periodically, the tank stops once they stop coming.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+pub enum ControlCommand {
+    /// Forward/backward speed and left/right turn rate.
+    Drive {
+        throttle: f32,
+        steering: f32,
+    },
+    /// Turret rotation and gun elevation speed.
+    Turret {
+        rotation: f32,
+        elevation: f32,
+    },
+    Stop,
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone, Eq, PartialEq, Hash)]
@@ -45,39 +136,64 @@ pub enum SignalEnum {
 
 #[derive(Debug, Serialize, Deserialize)]
 pub enum UserCommand {
-    Login,
-    IceOffer(TankId, String),
+    Login(OperatorCredentials),
+    IceCandidate(TankId, Option<IceCandidate>),
     SdpOffer(TankId, String),
+    /// Asks for the control lease, only granted while nobody else holds it.
+    RequestControl(TankId),
+    ReleaseControl(TankId),
+    /// Takes the control lease away from whoever holds it, admins only.
+    ForceControl(TankId),
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Serialize, Deserialize, Clone)]
 pub enum UserMessage {
     LoginResponse(UserId),
-    CameraListGetSuccess(Vec<TankId>),
+    LoginError(String),
+    /// STUN/TURN servers the operator's peer connections should use, sent right after login.
+    IceServers(Vec<IceServer>),
+    CameraListGetSuccess(Vec<TankListing>),
+    TankPresence(TankPresence),
     SdpAnswer(TankId, String),
-    IceOfferAnswer(TankId, String),
+    IceCandidate(TankId, Option<IceCandidate>),
+    /// The tank stopped itself, e.g. because the operator's drive state stopped arriving.
+    EmergencyStop(TankId, String),
+    /// Sent to everyone in a tank's session whenever somebody joins, leaves or the lease moves.
+    SessionUpdate(TankId, SessionInfo),
+    ControlDenied(TankId, String),
+    /// The tank went away, the peer connection to it won't recover.
+    SessionClosed(TankId, String),
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 pub enum TankCommand {
-    Login,
-    NewCamera(TankId),
+    /// Tank identity and its pre-shared token.
+    Login(TankInfo, String),
     SdpAnswer(UserId, String),
-    IceAnswer(UserId, String),
+    IceCandidate(UserId, Option<IceCandidate>),
+    /// Reports an emergency stop to the operator that was in control.
+    EmergencyStop(UserId, String),
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 pub enum TankMessage {
     LoginResponse(TankId),
+    LoginError(String),
+    /// STUN/TURN servers for the tank's peer connections, sent after login and before offers.
+    IceServers(Vec<IceServer>),
     SdpConnectionOffer(UserId, String),
-    IceConnectionOffer(UserId, String),
+    IceCandidate(UserId, Option<IceCandidate>),
+    /// The operator holding the control lease, commands from anyone else are ignored.
+    ControllerChanged(Option<UserId>),
+    /// The operator's signaling connection is gone, their peer connection can be closed.
+    OperatorDisconnected(UserId),
 }
 
 impl SignalEnum {
     pub fn is_login(&self) -> bool {
         match self {
-            SignalEnum::UserCommand(cmd) => matches!(cmd, UserCommand::Login),
-            SignalEnum::TankCommand(cmd) => matches!(cmd, TankCommand::Login),
+            SignalEnum::UserCommand(cmd) => matches!(cmd, UserCommand::Login(_)),
+            SignalEnum::TankCommand(cmd) => matches!(cmd, TankCommand::Login(..)),
             _ => false,
         }
     }
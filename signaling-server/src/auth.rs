@@ -0,0 +1,318 @@
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::OnceLock;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use hmac::{Hmac, Mac};
+use log::*;
+use protocol::{OperatorCredentials, TankId};
+use serde::Deserialize;
+use sha2::Sha256;
+
+type HmacSha256 = Hmac<Sha256>;
+
+static CREDENTIALS: OnceLock<Credentials> = OnceLock::new();
+
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum Role {
+    #[default]
+    Operator,
+    Admin,
+}
+
+#[derive(Debug, Deserialize)]
+struct OperatorEntry {
+    name: String,
+    api_key: String,
+    #[serde(default)]
+    role: Role,
+}
+
+/// Contents of the credential file.
+///
+/// ```json
+/// {
+///   "tanks": { "tank-1": "pre-shared token" },
+///   "operators": [{ "name": "alice", "api_key": "secret", "role": "admin" }],
+///   "token_secret": "key signed operator tokens are checked against"
+/// }
+/// ```
+#[derive(Debug, Deserialize, Default)]
+pub struct Credentials {
+    #[serde(default)]
+    tanks: HashMap<String, String>,
+    #[serde(default)]
+    operators: Vec<OperatorEntry>,
+    #[serde(default)]
+    token_secret: Option<String>,
+}
+
+/// An operator whose credentials checked out.
+#[derive(Debug, Clone)]
+pub struct Operator {
+    pub name: String,
+    pub role: Role,
+}
+
+pub fn init(path: &Path) -> anyhow::Result<()> {
+    let text = std::fs::read_to_string(path)
+        .map_err(|e| anyhow::anyhow!("can't read credentials {}: {}", path.display(), e))?;
+    let credentials: Credentials = serde_json::from_str(&text)
+        .map_err(|e| anyhow::anyhow!("invalid credentials {}: {}", path.display(), e))?;
+    info!(
+        "loaded credentials for {} tanks and {} operators",
+        credentials.tanks.len(),
+        credentials.operators.len()
+    );
+    CREDENTIALS
+        .set(credentials)
+        .map_err(|_| anyhow::anyhow!("credentials already loaded"))
+}
+
+/// The credentials `init` loaded. Without them nobody can log in, rather than everybody.
+fn credentials() -> anyhow::Result<&'static Credentials> {
+    CREDENTIALS
+        .get()
+        .ok_or_else(|| anyhow::anyhow!("credentials were never loaded"))
+}
+
+pub fn verify_tank(tank_id: &TankId, token: &str) -> anyhow::Result<()> {
+    credentials()?.verify_tank(tank_id, token)
+}
+
+pub fn verify_operator(login: &OperatorCredentials) -> anyhow::Result<Operator> {
+    credentials()?.verify_operator(login, now())
+}
+
+impl Credentials {
+    fn verify_tank(&self, tank_id: &TankId, token: &str) -> anyhow::Result<()> {
+        match self.tanks.get(tank_id.as_str()) {
+            Some(expected) if constant_time_eq(expected.as_bytes(), token.as_bytes()) => Ok(()),
+            _ => anyhow::bail!("invalid token for tank {}", tank_id.as_str()),
+        }
+    }
+
+    fn verify_operator(&self, login: &OperatorCredentials, now: u64) -> anyhow::Result<Operator> {
+        match login {
+            OperatorCredentials::ApiKey(key) => self
+                .operators
+                .iter()
+                .find(|op| constant_time_eq(op.api_key.as_bytes(), key.as_bytes()))
+                .map(|op| Operator {
+                    name: op.name.clone(),
+                    role: op.role,
+                })
+                .ok_or_else(|| anyhow::anyhow!("invalid api key")),
+            OperatorCredentials::Token(token) => {
+                let secret = self
+                    .token_secret
+                    .as_ref()
+                    .ok_or_else(|| anyhow::anyhow!("signed tokens are not enabled"))?;
+                let name = verify_token(secret, token, now)?;
+                // signed tokens carry no role, known operators keep theirs
+                let role = self
+                    .operators
+                    .iter()
+                    .find(|op| op.name == name)
+                    .map(|op| op.role)
+                    .unwrap_or_default();
+                Ok(Operator { name, role })
+            }
+        }
+    }
+}
+
+/// Checks a `<name>.<expiry unix seconds>.<hex hmac-sha256 of "name.expiry">` token
+/// and returns the operator name it was issued to.
+fn verify_token(secret: &str, token: &str, now: u64) -> anyhow::Result<String> {
+    let mut parts = token.rsplitn(3, '.');
+    let (Some(signature), Some(expiry), Some(name)) = (parts.next(), parts.next(), parts.next())
+    else {
+        anyhow::bail!("malformed token");
+    };
+    let signature = decode_hex(signature).ok_or_else(|| anyhow::anyhow!("malformed token"))?;
+    let mut mac = HmacSha256::new_from_slice(secret.as_bytes())?;
+    mac.update(name.as_bytes());
+    mac.update(b".");
+    mac.update(expiry.as_bytes());
+    mac.verify_slice(&signature)
+        .map_err(|_| anyhow::anyhow!("invalid token signature"))?;
+    if expiry.parse::<u64>()? < now {
+        anyhow::bail!("token expired");
+    }
+    Ok(name.to_owned())
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+fn decode_hex(hex: &str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SECRET: &str = "token secret";
+    const NOW: u64 = 1_700_000_000;
+
+    fn sign(secret: &str, name: &str, expiry: u64) -> String {
+        let mut mac = HmacSha256::new_from_slice(secret.as_bytes()).unwrap();
+        mac.update(format!("{name}.{expiry}").as_bytes());
+        let signature: String = mac
+            .finalize()
+            .into_bytes()
+            .iter()
+            .map(|b| format!("{b:02x}"))
+            .collect();
+        format!("{name}.{expiry}.{signature}")
+    }
+
+    fn credentials() -> Credentials {
+        serde_json::from_str(
+            r#"{
+                "tanks": { "tank-1": "tank token" },
+                "operators": [
+                    { "name": "alice", "api_key": "alice key", "role": "admin" },
+                    { "name": "bob", "api_key": "bob key" }
+                ],
+                "token_secret": "token secret"
+            }"#,
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn valid_token() {
+        let token = sign(SECRET, "carol", NOW + 60);
+        assert_eq!(verify_token(SECRET, &token, NOW).unwrap(), "carol");
+        // the expiry second itself is still fine
+        assert!(verify_token(SECRET, &sign(SECRET, "carol", NOW), NOW).is_ok());
+    }
+
+    #[test]
+    fn name_may_contain_dots() {
+        let token = sign(SECRET, "carol.smith", NOW + 60);
+        assert_eq!(verify_token(SECRET, &token, NOW).unwrap(), "carol.smith");
+    }
+
+    #[test]
+    fn expired_token() {
+        let token = sign(SECRET, "carol", NOW - 1);
+        let err = verify_token(SECRET, &token, NOW).unwrap_err();
+        assert_eq!(err.to_string(), "token expired");
+    }
+
+    #[test]
+    fn forged_tokens() {
+        let other_key = sign("another secret", "carol", NOW + 60);
+        assert!(verify_token(SECRET, &other_key, NOW).is_err());
+
+        // a valid signature doesn't carry over to another name or expiry
+        let token = sign(SECRET, "carol", NOW + 60);
+        let signature = token.rsplit('.').next().unwrap();
+        for forged in [
+            format!("alice.{}.{signature}", NOW + 60),
+            format!("carol.{}.{signature}", NOW + 3600),
+        ] {
+            let err = verify_token(SECRET, &forged, NOW).unwrap_err();
+            assert_eq!(err.to_string(), "invalid token signature");
+        }
+    }
+
+    #[test]
+    fn malformed_tokens() {
+        let token = sign(SECRET, "carol", NOW + 60);
+        let odd_hex = &token[..token.len() - 1];
+        for malformed in [
+            "",
+            "carol",
+            "carol.123",
+            "carol.123.not hex",
+            "carol.123.abc",
+            odd_hex,
+        ] {
+            assert!(
+                verify_token(SECRET, malformed, NOW).is_err(),
+                "{malformed:?} was accepted"
+            );
+        }
+        // signed, but not a number
+        let token = sign(SECRET, "carol", NOW).replace(&NOW.to_string(), "soon");
+        assert!(verify_token(SECRET, &token, NOW).is_err());
+    }
+
+    #[test]
+    fn operator_api_keys() {
+        let credentials = credentials();
+        let alice = credentials
+            .verify_operator(&OperatorCredentials::ApiKey("alice key".to_owned()), NOW)
+            .unwrap();
+        assert_eq!((alice.name.as_str(), alice.role), ("alice", Role::Admin));
+        let bob = credentials
+            .verify_operator(&OperatorCredentials::ApiKey("bob key".to_owned()), NOW)
+            .unwrap();
+        assert_eq!((bob.name.as_str(), bob.role), ("bob", Role::Operator));
+        for key in ["", "alice", "alice key "] {
+            assert!(credentials
+                .verify_operator(&OperatorCredentials::ApiKey(key.to_owned()), NOW)
+                .is_err());
+        }
+    }
+
+    #[test]
+    fn operator_tokens_keep_known_roles() {
+        let credentials = credentials();
+        let login = |name: &str| OperatorCredentials::Token(sign(SECRET, name, NOW + 60));
+        let alice = credentials.verify_operator(&login("alice"), NOW).unwrap();
+        assert_eq!(alice.role, Role::Admin);
+        let carol = credentials.verify_operator(&login("carol"), NOW).unwrap();
+        assert_eq!((carol.name.as_str(), carol.role), ("carol", Role::Operator));
+        let expired = OperatorCredentials::Token(sign(SECRET, "alice", NOW - 1));
+        assert!(credentials.verify_operator(&expired, NOW).is_err());
+    }
+
+    #[test]
+    fn tokens_need_a_secret() {
+        let credentials = Credentials::default();
+        let login = OperatorCredentials::Token(sign(SECRET, "alice", NOW + 60));
+        let err = credentials.verify_operator(&login, NOW).unwrap_err();
+        assert_eq!(err.to_string(), "signed tokens are not enabled");
+    }
+
+    #[test]
+    fn nobody_logs_in_before_init() {
+        let tank = TankId::new("tank-1".to_owned());
+        let err = verify_tank(&tank, "tank token").unwrap_err();
+        assert_eq!(err.to_string(), "credentials were never loaded");
+        let login = OperatorCredentials::ApiKey("alice key".to_owned());
+        assert!(verify_operator(&login).is_err());
+    }
+
+    #[test]
+    fn tank_tokens() {
+        let credentials = credentials();
+        let tank = TankId::new("tank-1".to_owned());
+        assert!(credentials.verify_tank(&tank, "tank token").is_ok());
+        assert!(credentials.verify_tank(&tank, "tank token!").is_err());
+        assert!(credentials.verify_tank(&tank, "").is_err());
+        let unknown = TankId::new("tank-2".to_owned());
+        assert!(credentials.verify_tank(&unknown, "tank token").is_err());
+    }
+}
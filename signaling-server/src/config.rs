@@ -0,0 +1,338 @@
+use std::fs;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use anyhow::Context;
+use clap::Parser;
+use log::LevelFilter;
+use protocol::{IceServer, SERVER_PORT};
+use serde::{Deserialize, Serialize};
+
+const DEFAULT_CONFIG_FILE: &str = "signaling-server.toml";
+
+/// Everything the signaling server can be configured with, see `signaling-server.example.toml`.
+/// Values come from the defaults, then the TOML file, then the command line and environment.
+#[derive(Debug, Clone, Serialize, Deserialize, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerConfig {
+    pub server: ListenConfig,
+    /// Serve `wss://` instead of `ws://` when set.
+    pub tls: Option<TlsConfig>,
+    pub log: LogConfig,
+    pub auth: AuthConfig,
+    pub ice: IceConfig,
+    pub heartbeat: HeartbeatConfig,
+    pub limits: LimitsConfig,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ListenConfig {
+    /// Every address the websocket server accepts connections on.
+    pub listen: Vec<SocketAddr>,
+}
+
+impl Default for ListenConfig {
+    fn default() -> Self {
+        Self {
+            listen: vec![format!("0.0.0.0:{SERVER_PORT}").parse().unwrap()],
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TlsConfig {
+    /// PEM certificate chain, leaf first.
+    pub cert_file: PathBuf,
+    /// PEM private key of the leaf certificate.
+    pub key_file: PathBuf,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LogConfig {
+    /// `off`, `error`, `warn`, `info`, `debug` or `trace`.
+    pub level: String,
+    /// Log file written next to the terminal output, none to only log to the terminal.
+    pub file: Option<PathBuf>,
+}
+
+impl Default for LogConfig {
+    fn default() -> Self {
+        Self {
+            level: "debug".to_owned(),
+            file: Some(PathBuf::from("signalling_server_prototype.log")),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AuthConfig {
+    /// Credential file tanks and operators are checked against.
+    pub credentials_file: PathBuf,
+}
+
+impl Default for AuthConfig {
+    fn default() -> Self {
+        Self {
+            credentials_file: PathBuf::from("credentials.json"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct IceConfig {
+    /// STUN/TURN servers handed to operators and tanks after they log in.
+    pub servers: Vec<IceServer>,
+    /// TURN servers sharing a secret with us, everyone gets their own short-lived credentials.
+    pub turn: Option<TurnConfig>,
+}
+
+impl Default for IceConfig {
+    fn default() -> Self {
+        Self {
+            servers: vec![IceServer {
+                urls: vec!["stun:stun.l.google.com:19302".to_owned()],
+                ..Default::default()
+            }],
+            turn: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TurnConfig {
+    pub urls: Vec<String>,
+    /// coturn's `static-auth-secret`.
+    pub secret: String,
+    /// How long minted credentials stay valid.
+    pub ttl_secs: u64,
+}
+
+impl Default for TurnConfig {
+    fn default() -> Self {
+        Self {
+            urls: vec![],
+            secret: String::new(),
+            ttl_secs: 24 * 60 * 60,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct HeartbeatConfig {
+    /// How often peers are pinged.
+    pub interval_secs: u64,
+    /// How long a peer may stay silent before it is dropped.
+    pub timeout_secs: u64,
+}
+
+impl Default for HeartbeatConfig {
+    fn default() -> Self {
+        Self {
+            interval_secs: 10,
+            timeout_secs: 30,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LimitsConfig {
+    /// Connections beyond this are closed right after they are accepted.
+    pub max_connections: usize,
+    /// Largest websocket message a peer may send, SDP offers are the biggest.
+    pub max_message_bytes: usize,
+    /// Operators watching a single tank, the one in control included.
+    pub max_session_size: usize,
+}
+
+impl Default for LimitsConfig {
+    fn default() -> Self {
+        Self {
+            max_connections: 1024,
+            max_message_bytes: 64 * 1024,
+            max_session_size: 16,
+        }
+    }
+}
+
+/// Command line, every option can also be given through the environment.
+#[derive(Debug, Parser)]
+#[command(version, about = "Signaling server connecting operators to tanks")]
+pub struct Cli {
+    /// TOML config file, `signaling-server.toml` is used if it exists.
+    #[arg(short, long, env = "SIGNALING_CONFIG")]
+    pub config: Option<PathBuf>,
+    /// Print the effective configuration and exit.
+    #[arg(long)]
+    pub print_config: bool,
+    /// Address to listen on, repeat for several.
+    #[arg(long, env = "LISTEN", value_delimiter = ',')]
+    pub listen: Vec<SocketAddr>,
+    /// PEM certificate chain to serve `wss://` with, needs `--tls-key` too.
+    #[arg(long, env = "TLS_CERT", requires = "tls_key")]
+    pub tls_cert: Option<PathBuf>,
+    #[arg(long, env = "TLS_KEY", requires = "tls_cert")]
+    pub tls_key: Option<PathBuf>,
+    #[arg(long, env = "LOG_LEVEL")]
+    pub log_level: Option<String>,
+    #[arg(long, env = "LOG_FILE")]
+    pub log_file: Option<PathBuf>,
+    /// Only log to the terminal.
+    #[arg(long, conflicts_with = "log_file")]
+    pub no_log_file: bool,
+    #[arg(long, env = "CREDENTIALS_FILE")]
+    pub credentials: Option<PathBuf>,
+    /// Shared secret of the `[ice.turn]` servers.
+    #[arg(long, env = "TURN_SECRET", hide_env_values = true)]
+    pub turn_secret: Option<String>,
+    #[arg(long, env = "HEARTBEAT_INTERVAL_SECS")]
+    pub heartbeat_interval_secs: Option<u64>,
+    #[arg(long, env = "HEARTBEAT_TIMEOUT_SECS")]
+    pub heartbeat_timeout_secs: Option<u64>,
+}
+
+impl ServerConfig {
+    /// Reads the config file the command line points at and applies the overrides on top.
+    pub fn load(cli: &Cli) -> anyhow::Result<Self> {
+        let mut config = match &cli.config {
+            Some(path) => Self::from_file(path)?,
+            None if Path::new(DEFAULT_CONFIG_FILE).exists() => {
+                Self::from_file(Path::new(DEFAULT_CONFIG_FILE))?
+            }
+            None => Self::default(),
+        };
+        config.apply(cli);
+        config.validate()?;
+        Ok(config)
+    }
+
+    fn from_file(path: &Path) -> anyhow::Result<Self> {
+        let text = fs::read_to_string(path)
+            .with_context(|| format!("can't read config {}", path.display()))?;
+        toml::from_str(&text).with_context(|| format!("invalid config {}", path.display()))
+    }
+
+    fn apply(&mut self, cli: &Cli) {
+        if !cli.listen.is_empty() {
+            self.server.listen = cli.listen.clone();
+        }
+        if let (Some(cert_file), Some(key_file)) = (&cli.tls_cert, &cli.tls_key) {
+            self.tls = Some(TlsConfig {
+                cert_file: cert_file.clone(),
+                key_file: key_file.clone(),
+            });
+        }
+        if let Some(level) = &cli.log_level {
+            self.log.level = level.clone();
+        }
+        if let Some(file) = &cli.log_file {
+            self.log.file = Some(file.clone());
+        }
+        if cli.no_log_file {
+            self.log.file = None;
+        }
+        if let Some(credentials) = &cli.credentials {
+            self.auth.credentials_file = credentials.clone();
+        }
+        if let Some(secret) = &cli.turn_secret {
+            self.ice.turn.get_or_insert_with(TurnConfig::default).secret = secret.clone();
+        }
+        if let Some(interval) = cli.heartbeat_interval_secs {
+            self.heartbeat.interval_secs = interval;
+        }
+        if let Some(timeout) = cli.heartbeat_timeout_secs {
+            self.heartbeat.timeout_secs = timeout;
+        }
+    }
+
+    pub fn validate(&self) -> anyhow::Result<()> {
+        if self.server.listen.is_empty() {
+            anyhow::bail!("server.listen needs at least one address");
+        }
+        self.log_level()?;
+        for server in &self.ice.servers {
+            for url in &server.urls {
+                if !["stun:", "turn:", "turns:"]
+                    .iter()
+                    .any(|scheme| url.starts_with(scheme))
+                {
+                    anyhow::bail!("ice server url {url} must start with stun:, turn: or turns:");
+                }
+            }
+        }
+        if let Some(turn) = &self.ice.turn {
+            if turn.secret.is_empty() {
+                anyhow::bail!("ice.turn.secret (TURN_SECRET) is not set");
+            }
+            if turn.urls.is_empty() {
+                anyhow::bail!("ice.turn.urls needs at least one turn: or turns: url");
+            }
+            if let Some(url) = turn
+                .urls
+                .iter()
+                .find(|url| !(url.starts_with("turn:") || url.starts_with("turns:")))
+            {
+                anyhow::bail!("ice.turn url {url} must start with turn: or turns:");
+            }
+            if turn.ttl_secs == 0 {
+                anyhow::bail!("ice.turn.ttl_secs must be above 0");
+            }
+        }
+        let heartbeat = &self.heartbeat;
+        if heartbeat.interval_secs == 0 {
+            anyhow::bail!("heartbeat.interval_secs must be above 0");
+        }
+        if heartbeat.timeout_secs <= heartbeat.interval_secs {
+            anyhow::bail!(
+                "heartbeat.timeout_secs ({}) must be longer than interval_secs ({})",
+                heartbeat.timeout_secs,
+                heartbeat.interval_secs
+            );
+        }
+        let limits = &self.limits;
+        if limits.max_connections == 0 || limits.max_message_bytes == 0 {
+            anyhow::bail!("limits.max_connections and max_message_bytes must be above 0");
+        }
+        if limits.max_session_size == 0 {
+            anyhow::bail!("limits.max_session_size must be above 0");
+        }
+        Ok(())
+    }
+
+    pub fn log_level(&self) -> anyhow::Result<LevelFilter> {
+        self.log
+            .level
+            .parse()
+            .map_err(|_| anyhow::anyhow!("unknown log.level {}", self.log.level))
+    }
+
+    pub fn heartbeat_interval(&self) -> Duration {
+        Duration::from_secs(self.heartbeat.interval_secs)
+    }
+
+    pub fn heartbeat_timeout(&self) -> Duration {
+        Duration::from_secs(self.heartbeat.timeout_secs)
+    }
+
+    /// The effective configuration as TOML, with secrets blanked out.
+    pub fn to_toml(&self) -> anyhow::Result<String> {
+        let mut redacted = self.clone();
+        for server in &mut redacted.ice.servers {
+            if server.credential.is_some() {
+                server.credential = Some("<redacted>".to_owned());
+            }
+        }
+        if let Some(turn) = &mut redacted.ice.turn {
+            turn.secret = "<redacted>".to_owned();
+        }
+        Ok(toml::to_string_pretty(&redacted)?)
+    }
+}
@@ -1,37 +1,95 @@
+use log::warn;
 use protocol::{SignalEnum, TankCommand, TankId, TankMessage, UserCommand, UserId, UserMessage};
 
+use crate::auth::Role;
 use crate::state;
+
 pub fn handle_operator_message(user_id: UserId, cmd: UserCommand) -> anyhow::Result<()> {
     match cmd {
-        UserCommand::IceOffer(tank_id, data) => {
-            let msg = SignalEnum::TankMessage(TankMessage::IceConnectionOffer(
-                user_id.clone(),
-                data.clone(),
-            ));
+        UserCommand::IceCandidate(tank_id, candidate) => {
+            let msg = SignalEnum::TankMessage(TankMessage::IceCandidate(user_id, candidate));
             state::send_message_to_tank(&tank_id, msg)?;
         }
-        UserCommand::Login => {
+        UserCommand::Login(_) => {
             let tanks = state::get_tank_list();
             let msg = SignalEnum::UserResponse(UserMessage::CameraListGetSuccess(tanks));
             state::send_message_to_operator(&user_id, msg)?;
         }
         UserCommand::SdpOffer(tank_id, data) => {
+            // everyone watching starts out as a spectator
+            if let Err(e) = state::join_session(&tank_id, &user_id) {
+                let msg =
+                    SignalEnum::UserResponse(UserMessage::SessionClosed(tank_id, e.to_string()));
+                state::send_message_to_operator(&user_id, msg)?;
+                return Ok(());
+            }
+            // credentials from the tank's login may have expired by now
+            let servers = state::ice_servers(tank_id.as_str());
+            let msg = SignalEnum::TankMessage(TankMessage::IceServers(servers));
+            state::send_message_to_tank(&tank_id, msg)?;
             let msg =
                 SignalEnum::TankMessage(TankMessage::SdpConnectionOffer(user_id.clone(), data));
             state::send_message_to_tank(&tank_id, msg)?;
         }
+        UserCommand::RequestControl(tank_id) => {
+            let result = state::take_control(&tank_id, &user_id, false);
+            deny_control(&user_id, tank_id, result)?;
+        }
+        UserCommand::ForceControl(tank_id) => {
+            let result = match state::get_user_role(&user_id) {
+                Some(Role::Admin) => {
+                    warn!(
+                        "{} forces control of {}",
+                        user_id.as_str(),
+                        tank_id.as_str()
+                    );
+                    state::take_control(&tank_id, &user_id, true)
+                }
+                _ => Err(anyhow::anyhow!("only admins can force control")),
+            };
+            deny_control(&user_id, tank_id, result)?;
+        }
+        UserCommand::ReleaseControl(tank_id) => {
+            let result = state::release_control(&tank_id, &user_id);
+            deny_control(&user_id, tank_id, result)?;
+        }
     };
     Ok(())
 }
+/// Tells the operator why their lease request or release didn't go through.
+fn deny_control(
+    user_id: &UserId,
+    tank_id: TankId,
+    result: anyhow::Result<()>,
+) -> anyhow::Result<()> {
+    if let Err(e) = result {
+        let msg = SignalEnum::UserResponse(UserMessage::ControlDenied(tank_id, e.to_string()));
+        state::send_message_to_operator(user_id, msg)?;
+    }
+    Ok(())
+}
+
 pub fn handle_tank_message(tank_id: TankId, cmd: TankCommand) -> anyhow::Result<()> {
     match cmd {
-        TankCommand::Login => {
-            let msg = SignalEnum::TankMessage(TankMessage::LoginResponse(tank_id.clone()));
+        TankCommand::Login(..) => {
+            let msg = SignalEnum::TankMessage(TankMessage::LoginError(format!(
+                "already logged in as {}",
+                tank_id.as_str()
+            )));
             state::send_message_to_tank(&tank_id, msg)?;
         }
-        TankCommand::NewCamera(_) => todo!(),
-        TankCommand::IceAnswer(user_id, data) => {
-            let msg = SignalEnum::UserResponse(UserMessage::IceOfferAnswer(tank_id, data));
+        TankCommand::IceCandidate(user_id, candidate) => {
+            let msg = SignalEnum::UserResponse(UserMessage::IceCandidate(tank_id, candidate));
+            state::send_message_to_operator(&user_id, msg)?;
+        }
+        TankCommand::EmergencyStop(user_id, reason) => {
+            warn!(
+                "tank {} stopped while {} was in control: {}",
+                tank_id.as_str(),
+                user_id.as_str(),
+                reason
+            );
+            let msg = SignalEnum::UserResponse(UserMessage::EmergencyStop(tank_id, reason));
             state::send_message_to_operator(&user_id, msg)?;
         }
         TankCommand::SdpAnswer(user_id, data) => {
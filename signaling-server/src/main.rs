@@ -3,40 +3,53 @@ use handler::{handle_operator_message, handle_tank_message};
 use std::any;
 use std::fs::File;
 use std::sync::Arc;
+use std::time::{Duration, Instant};
 use std::{io::Error as IoError, net::SocketAddr, sync::Mutex};
-use tokio::net::{TcpListener, TcpStream};
-use tokio_tungstenite::tungstenite::Result;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::TcpListener;
+use tokio_tungstenite::tungstenite::{protocol::WebSocketConfig, Error, Message, Result};
 
 use async_std::task;
-use futures::{channel::mpsc::unbounded, future, pin_mut};
-use log::{error, info, warn, SetLoggerError};
+use clap::Parser;
+use config::{Cli, ServerConfig};
+use futures::{channel::mpsc::unbounded, future, future::Either, pin_mut};
+use log::{debug, error, info, warn, SetLoggerError};
 use rand::distributions::Alphanumeric;
 use rand::{thread_rng, Rng};
-use simplelog::{CombinedLogger, LevelFilter, TermLogger, TerminalMode, WriteLogger};
+use simplelog::{CombinedLogger, SharedLogger, TermLogger, TerminalMode, WriteLogger};
 
+pub mod auth;
+pub mod config;
 pub mod handler;
 pub mod state;
+pub mod tls;
+pub mod turn;
 
-const LOG_FILE: &str = "signalling_server_prototype.log";
+/// How often peers are pinged and how long they may stay silent before they are dropped.
+#[derive(Debug, Clone, Copy)]
+struct Heartbeat {
+    interval: Duration,
+    timeout: Duration,
+}
 
 //////////////////////////////////////////////////////////////////////////////////////////////////////////////
 // Setup Logging
-fn setup_logging() -> Result<(), SetLoggerError> {
-    CombinedLogger::init(vec![
-        TermLogger::new(
-            LevelFilter::Debug,
-            simplelog::Config::default(),
-            TerminalMode::Mixed,
-        ),
-        WriteLogger::new(
-            LevelFilter::Debug,
-            simplelog::Config::default(),
-            File::create(LOG_FILE).unwrap(),
-        ),
-    ])
+fn setup_logging(config: &ServerConfig) -> anyhow::Result<()> {
+    let level = config.log_level()?;
+    let mut loggers: Vec<Box<dyn SharedLogger>> = vec![TermLogger::new(
+        level,
+        simplelog::Config::default(),
+        TerminalMode::Mixed,
+    )];
+    if let Some(path) = &config.log.file {
+        let file = File::create(path)
+            .map_err(|e| anyhow::anyhow!("can't create log file {}: {}", path.display(), e))?;
+        loggers.push(WriteLogger::new(level, simplelog::Config::default(), file));
+    }
+    CombinedLogger::init(loggers).map_err(|e: SetLoggerError| anyhow::anyhow!(e))
 }
 
-use protocol::{ProtoId, SignalEnum, TankId, UserId, UserMessage};
+use protocol::{ProtoId, SignalEnum, TankCommand, TankMessage, UserCommand, UserId, UserMessage};
 use std::net::UdpSocket;
 
 pub fn get_local_ip() -> Option<String> {
@@ -64,21 +77,121 @@ fn generate_id(length: u8) -> String {
     rand_string
 }
 
-async fn handle_connection(raw_stream: TcpStream, addr: SocketAddr) {
+/// A message as it goes into the log. Logins carry credentials, so they are never logged whole.
+fn describe(signal: &SignalEnum) -> String {
+    match signal {
+        SignalEnum::UserCommand(UserCommand::Login(_)) => "operator login".to_owned(),
+        SignalEnum::TankCommand(TankCommand::Login(info, _)) => {
+            format!("login of tank {}", info.id.as_str())
+        }
+        other => format!("{:?}", other),
+    }
+}
+
+/// Authenticates the first message of a connection and registers the peer under its id.
+/// Rejected logins are answered with a login error before the connection is closed.
+fn login(addr: SocketAddr, signal: SignalEnum, id: &Mutex<Option<ProtoId>>) -> anyhow::Result<()> {
+    match signal {
+        SignalEnum::TankCommand(TankCommand::Login(info, token)) => {
+            let tank_id = info.id.clone();
+            let result =
+                auth::verify_tank(&tank_id, &token).and_then(|_| state::insert_tank(addr, info));
+            if let Err(e) = result {
+                let msg = SignalEnum::TankMessage(TankMessage::LoginError(e.to_string()));
+                let _ = state::send(&addr, msg);
+                return Err(e);
+            }
+            info!("tank {} logged in from {}", tank_id.as_str(), addr);
+            let msg = SignalEnum::TankMessage(TankMessage::LoginResponse(tank_id.clone()));
+            state::send_message_to_tank(&tank_id, msg)?;
+            let servers = state::ice_servers(tank_id.as_str());
+            let msg = SignalEnum::TankMessage(TankMessage::IceServers(servers));
+            state::send_message_to_tank(&tank_id, msg)?;
+            if let Ok(mut x) = id.lock() {
+                *x = Some(ProtoId::Tank(tank_id));
+            }
+        }
+        SignalEnum::UserCommand(UserCommand::Login(credentials)) => {
+            let operator = match auth::verify_operator(&credentials) {
+                Ok(operator) => operator,
+                Err(e) => {
+                    let msg = SignalEnum::UserResponse(UserMessage::LoginError(e.to_string()));
+                    let _ = state::send(&addr, msg);
+                    return Err(e);
+                }
+            };
+            let user_id = UserId::new(generate_id(10));
+            info!(
+                "operator {} ({:?}) logged in from {} as {}",
+                operator.name,
+                operator.role,
+                addr,
+                user_id.as_str()
+            );
+            state::insert_user(addr, user_id.clone(), operator.role)?;
+
+            let msg = SignalEnum::UserResponse(UserMessage::LoginResponse(user_id.clone()));
+            state::send_message_to_operator(&user_id, msg)?;
+            let servers = state::ice_servers(&operator.name);
+            let msg = SignalEnum::UserResponse(UserMessage::IceServers(servers));
+            state::send_message_to_operator(&user_id, msg)?;
+            let tanks = state::get_tank_list();
+            let msg = SignalEnum::UserResponse(UserMessage::CameraListGetSuccess(tanks));
+            state::send_message_to_operator(&user_id, msg)?;
+            if let Ok(mut x) = id.lock() {
+                *x = Some(ProtoId::User(user_id));
+            }
+        }
+        _ => anyhow::bail!("not logged in"),
+    }
+    Ok(())
+}
+
+/// Pings the peer every interval and returns once it hasn't been heard from for the timeout,
+/// which catches half-open connections the socket itself never reports as closed.
+async fn keepalive(addr: SocketAddr, last_seen: Arc<Mutex<Instant>>, heartbeat: Heartbeat) {
+    loop {
+        task::sleep(heartbeat.interval).await;
+        let silent_for = last_seen.lock().map(|x| x.elapsed()).unwrap_or_default();
+        if silent_for > heartbeat.timeout {
+            warn!("evicting {}, no answer for {:?}", addr, silent_for);
+            return;
+        }
+        if let Err(e) = state::ping(&addr) {
+            warn!("could not ping {}: {}", addr, e);
+            return;
+        }
+    }
+}
+
+async fn handle_connection<S>(
+    raw_stream: S,
+    addr: SocketAddr,
+    heartbeat: Heartbeat,
+    ws_config: WebSocketConfig,
+) where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
     info!("Incoming TCP connection from: {}", addr);
 
-    let ws_stream = tokio_tungstenite::accept_async(raw_stream)
-        .await
-        .expect("Error during the websocket handshake occurred");
+    let ws_stream =
+        match tokio_tungstenite::accept_async_with_config(raw_stream, Some(ws_config)).await {
+            Ok(ws_stream) => ws_stream,
+            Err(e) => {
+                warn!("websocket handshake with {} failed: {}", addr, e);
+                return;
+            }
+        };
     info!("WebSocket connection established: {}", addr);
 
     let (outgoing, incoming) = ws_stream.split();
     // peer map
     let (tx, rx) = unbounded();
-    state::insert_peer(addr, tx.clone());
+    state::insert_peer(addr, tx);
     let _ = state::send(&addr, SignalEnum::Start);
 
     let id_mutex = Arc::new(Mutex::new(Option::<ProtoId>::None));
+    let last_seen = Arc::new(Mutex::new(Instant::now()));
 
     let broadcast_incoming = incoming
         .try_filter(|msg| {
@@ -87,36 +200,28 @@ async fn handle_connection(raw_stream: TcpStream, addr: SocketAddr) {
             future::ready(!msg.is_close())
         })
         .try_for_each(|msg| {
-            warn!(
-                "Received a message from {}: {}",
-                addr,
-                msg.to_text().unwrap()
-            );
-            let message = msg.to_text().unwrap().to_string();
+            if let Ok(mut x) = last_seen.lock() {
+                *x = Instant::now();
+            }
+            if msg.is_ping() || msg.is_pong() {
+                return future::ok(());
+            }
+            let Message::Text(message) = msg else {
+                warn!("closing {}, it sent a frame that isn't text", addr);
+                return future::err(Error::ConnectionClosed);
+            };
             if let Ok(signal) = serde_json::from_str::<SignalEnum>(&message) {
-                if signal.is_login() && id_mutex.lock().map(|x| x.is_none()).unwrap_or(false) {
-                    if signal.is_tank() {
-                        let tank_id = TankId::new("123".to_string());
-                        state::insert_tank(addr, tank_id.clone());
-
-                        let msg = SignalEnum::TankMessage(protocol::TankMessage::LoginResponse(
-                            tank_id.clone(),
-                        ));
-                        state::send_message_to_tank(&tank_id, msg);
-                        if let Ok(mut x) = id_mutex.lock() {
-                            *x = Some(ProtoId::Tank(tank_id));
+                debug!("Received from {}: {}", addr, describe(&signal));
+                let logged_in = id_mutex.lock().map(|x| x.is_some()).unwrap_or(false);
+                if !logged_in {
+                    // the first message has to be a login that checks out
+                    return match login(addr, signal, &id_mutex) {
+                        Ok(()) => future::ok(()),
+                        Err(e) => {
+                            warn!("closing unauthenticated connection {}: {}", addr, e);
+                            future::err(Error::ConnectionClosed)
                         }
-                    } else if signal.is_operator() {
-                        let user_id = UserId::new(generate_id(10));
-                        state::insert_user(addr, user_id.clone());
-
-                        let msg =
-                            SignalEnum::UserResponse(UserMessage::LoginResponse(user_id.clone()));
-                        state::send_message_to_operator(&user_id, msg);
-                        if let Ok(mut x) = id_mutex.lock() {
-                            *x = Some(ProtoId::User(user_id));
-                        }
-                    }
+                    };
                 } else {
                     let result: anyhow::Result<()> = match signal {
                         SignalEnum::TankCommand(cmd) => {
@@ -151,9 +256,17 @@ async fn handle_connection(raw_stream: TcpStream, addr: SocketAddr) {
         });
 
     let receive_from_others = rx.map(Ok).forward(outgoing);
+    let keepalive = keepalive(addr, last_seen.clone(), heartbeat);
 
-    pin_mut!(broadcast_incoming, receive_from_others);
-    future::select(broadcast_incoming, receive_from_others).await;
+    pin_mut!(broadcast_incoming, receive_from_others, keepalive);
+    let connection = future::select(broadcast_incoming, receive_from_others);
+    if let Either::Left((Either::Left((_, flush)), _)) = future::select(connection, keepalive).await
+    {
+        // Dropping the last sender ends the stream, so replies that are already
+        // queued (like a login error) still reach the client before it is closed.
+        state::remove_peer(&addr);
+        let _ = async_std::future::timeout(Duration::from_secs(1), flush).await;
+    }
 
     info!("{} disconnected", &addr);
 
@@ -163,24 +276,68 @@ async fn handle_connection(raw_stream: TcpStream, addr: SocketAddr) {
             ProtoId::Tank(tank_id) => state::remove_tank(&tank_id),
             ProtoId::User(user_id) => state::remove_user(&user_id),
         },
-        None => todo!(),
+        None => {}
     }
 }
 
-async fn run() -> Result<(), IoError> {
-    let addr = "127.0.0.1:9002";
-    let listener = TcpListener::bind(&addr).await.expect("Can't listen");
-    info!("Listening on: {}", addr);
+async fn run(
+    listener: TcpListener,
+    tls: Option<tls::TlsAcceptor>,
+    config: ServerConfig,
+) -> Result<(), IoError> {
+    let heartbeat = Heartbeat {
+        interval: config.heartbeat_interval(),
+        timeout: config.heartbeat_timeout(),
+    };
+    let ws_config = WebSocketConfig {
+        max_message_size: Some(config.limits.max_message_bytes),
+        max_frame_size: Some(config.limits.max_message_bytes),
+        ..Default::default()
+    };
 
     while let Ok((stream, addr)) = listener.accept().await {
-        task::spawn(handle_connection(stream, addr));
+        let Some(connection) = state::open_connection(config.limits.max_connections) else {
+            warn!(
+                "refusing {}, already at {} connections",
+                addr,
+                state::connection_count()
+            );
+            continue;
+        };
+        let tls = tls.clone();
+        task::spawn(async move {
+            let _connection = connection;
+            match tls {
+                Some(tls) => match tls.accept(stream).await {
+                    Ok(stream) => handle_connection(stream, addr, heartbeat, ws_config).await,
+                    Err(e) => warn!("TLS handshake with {} failed: {}", addr, e),
+                },
+                None => handle_connection(stream, addr, heartbeat, ws_config).await,
+            }
+        });
     }
     Ok(())
 }
 
 #[tokio::main]
 async fn main() {
-    match setup_logging() {
+    let cli = Cli::parse();
+    let config = match ServerConfig::load(&cli) {
+        Ok(config) => config,
+        Err(e) => {
+            println!("{:#}\n...exiting", e);
+            std::process::exit(1);
+        }
+    };
+    if cli.print_config {
+        match config.to_toml() {
+            Ok(text) => print!("{}", text),
+            Err(e) => println!("{:#}", e),
+        }
+        return;
+    }
+
+    match setup_logging(&config) {
         Ok(_) => (),
         Err(e) => {
             println!("Could not start logger,{}\n...exiting", e);
@@ -188,5 +345,108 @@ async fn main() {
         }
     }
 
-    task::block_on(run());
+    if let Err(e) = auth::init(&config.auth.credentials_file) {
+        error!("{}", e);
+        std::process::exit(1);
+    }
+    state::set_limits(config.limits);
+    state::set_ice(config.ice.clone());
+    info!("{:?}", config.heartbeat);
+
+    let tls = match &config.tls {
+        Some(tls) => match tls::acceptor(&tls.cert_file, &tls.key_file) {
+            Ok(acceptor) => Some(acceptor),
+            Err(e) => {
+                error!("{:#}", e);
+                std::process::exit(1);
+            }
+        },
+        None => None,
+    };
+
+    let mut servers = vec![];
+    for addr in &config.server.listen {
+        let listener = match TcpListener::bind(addr).await {
+            Ok(listener) => listener,
+            Err(e) => {
+                error!("Can't listen on {}: {}", addr, e);
+                std::process::exit(1);
+            }
+        };
+        let scheme = if tls.is_some() { "wss" } else { "ws" };
+        info!("Listening on: {}://{}", scheme, addr);
+        servers.push(task::spawn(run(listener, tls.clone(), config.clone())));
+    }
+    for server in servers {
+        if let Err(e) = server.await {
+            error!("{}", e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use protocol::{OperatorCredentials, TankId, TankInfo};
+
+    use super::*;
+
+    const HEARTBEAT: Heartbeat = Heartbeat {
+        interval: Duration::from_millis(10),
+        timeout: Duration::from_millis(50),
+    };
+
+    #[tokio::test]
+    async fn silent_peers_are_evicted() {
+        let addr: SocketAddr = "127.0.0.1:65001".parse().unwrap();
+        let (tx, mut rx) = unbounded();
+        state::insert_peer(addr, tx);
+        let started = Instant::now();
+        let last_seen = Arc::new(Mutex::new(started));
+        tokio::time::timeout(
+            Duration::from_secs(5),
+            keepalive(addr, last_seen, HEARTBEAT),
+        )
+        .await
+        .expect("a silent peer should be evicted");
+        assert!(started.elapsed() > HEARTBEAT.timeout);
+        // it was pinged while it had the chance to answer
+        assert!(matches!(rx.try_recv(), Ok(Message::Ping(_))));
+        state::remove_peer(&addr);
+    }
+
+    #[tokio::test]
+    async fn answering_peers_stay() {
+        let addr: SocketAddr = "127.0.0.1:65002".parse().unwrap();
+        let (tx, _rx) = unbounded();
+        state::insert_peer(addr, tx);
+        let last_seen = Arc::new(Mutex::new(Instant::now()));
+        let answering = async {
+            loop {
+                tokio::time::sleep(Duration::from_millis(5)).await;
+                *last_seen.lock().unwrap() = Instant::now();
+            }
+        };
+        tokio::select! {
+            _ = keepalive(addr, last_seen.clone(), HEARTBEAT) => {
+                panic!("evicted a peer that answers")
+            }
+            _ = tokio::time::timeout(HEARTBEAT.timeout * 4, answering) => {}
+        }
+        state::remove_peer(&addr);
+    }
+
+    #[test]
+    fn logins_are_logged_without_credentials() {
+        let login = SignalEnum::UserCommand(UserCommand::Login(OperatorCredentials::ApiKey(
+            "secret-key".to_owned(),
+        )));
+        assert_eq!(describe(&login), "operator login");
+        let info = TankInfo {
+            id: TankId::new("tank-1".to_owned()),
+            name: "Tank".to_owned(),
+            hardware: Default::default(),
+        };
+        let login = SignalEnum::TankCommand(TankCommand::Login(info, "secret-token".to_owned()));
+        assert_eq!(describe(&login), "login of tank tank-1");
+    }
 }
@@ -1,23 +1,44 @@
 use std::net::SocketAddr;
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::{Arc, OnceLock};
 
 use futures_channel::mpsc::UnboundedSender;
 use log::*;
-use protocol::{SignalEnum, TankId, UserId};
+use protocol::{
+    IceServer, SessionInfo, SignalEnum, TankId, TankInfo, TankListing, TankMessage, TankPresence,
+    UserId, UserMessage,
+};
 use scc::HashMap;
 use tokio_tungstenite::tungstenite::Message;
 
+use crate::auth::Role;
+use crate::config::{IceConfig, LimitsConfig};
+use crate::turn;
+
 type Tx = UnboundedSender<Message>;
 pub type PeerMap = Arc<HashMap<SocketAddr, Tx>>;
-pub type UserList = Arc<HashMap<UserId, SocketAddr>>;
-pub type TankList = Arc<HashMap<TankId, SocketAddr>>;
+pub type UserList = Arc<HashMap<UserId, UserEntry>>;
+pub type TankList = Arc<HashMap<TankId, TankEntry>>;
+
+pub struct TankEntry {
+    pub addr: SocketAddr,
+    pub info: TankInfo,
+}
 
-pub type SessionList = Arc<HashMap<TankId, Option<UserId>>>;
+pub struct UserEntry {
+    pub addr: SocketAddr,
+    pub role: Role,
+}
+
+pub type SessionList = Arc<HashMap<TankId, SessionInfo>>;
 
 static PEERS: OnceLock<PeerMap> = OnceLock::new();
 static USERS: OnceLock<UserList> = OnceLock::new();
 static TANKS: OnceLock<TankList> = OnceLock::new();
 static SESSIONS: OnceLock<SessionList> = OnceLock::new();
+static LIMITS: OnceLock<LimitsConfig> = OnceLock::new();
+static ICE: OnceLock<IceConfig> = OnceLock::new();
+static CONNECTIONS: AtomicUsize = AtomicUsize::new(0);
 
 fn peers<'a>() -> &'a PeerMap {
     PEERS.get_or_init(|| PeerMap::default())
@@ -35,6 +56,48 @@ fn sessions<'a>() -> &'a SessionList {
     SESSIONS.get_or_init(|| SessionList::default())
 }
 
+pub fn set_limits(limits: LimitsConfig) {
+    let _ = LIMITS.set(limits);
+}
+
+fn limits<'a>() -> &'a LimitsConfig {
+    LIMITS.get_or_init(LimitsConfig::default)
+}
+
+pub fn set_ice(ice: IceConfig) {
+    let _ = ICE.set(ice);
+}
+
+/// ICE servers for `user`, with freshly minted TURN credentials if TURN is configured.
+pub fn ice_servers(user: &str) -> Vec<IceServer> {
+    turn::ice_servers(ICE.get_or_init(IceConfig::default), user)
+}
+
+/// An accepted socket, counted against `limits.max_connections` until it is dropped.
+pub struct Connection(());
+
+impl Drop for Connection {
+    fn drop(&mut self) {
+        CONNECTIONS.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Counts a newly accepted socket, unless there are `max` open already.
+/// Sockets count from the moment they are accepted, so slow or stuck handshakes
+/// can't get around the limit.
+pub fn open_connection(max: usize) -> Option<Connection> {
+    CONNECTIONS
+        .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |open| {
+            (open < max).then_some(open + 1)
+        })
+        .ok()
+        .map(|_| Connection(()))
+}
+
+pub fn connection_count() -> usize {
+    CONNECTIONS.load(Ordering::Relaxed)
+}
+
 pub fn insert_peer(addr: SocketAddr, tx: Tx) {
     peers().insert(addr, tx.clone());
 }
@@ -43,47 +106,225 @@ pub fn remove_peer(addr: &SocketAddr) {
     peers().remove(addr);
 }
 
-pub fn insert_user(addr: SocketAddr, user_id: UserId) {
-    users().insert(user_id, addr);
+pub fn insert_user(addr: SocketAddr, user_id: UserId, role: Role) -> anyhow::Result<()> {
+    users()
+        .insert(user_id, UserEntry { addr, role })
+        .map_err(|(user_id, _)| anyhow::anyhow!("user id {} is taken", user_id.as_str()))
 }
 
 pub fn remove_user(addr: &UserId) {
     users().remove(addr);
+    leave_sessions(addr);
 }
 
-pub fn insert_tank(addr: SocketAddr, tank_id: TankId) {
-    tanks().insert(tank_id, addr);
+pub fn get_user_role(user_id: &UserId) -> Option<Role> {
+    users().read(user_id, |_, entry| entry.role)
+}
+
+/// Registers a tank under the id it chose, rejecting ids that are empty or already online.
+pub fn insert_tank(addr: SocketAddr, info: TankInfo) -> anyhow::Result<()> {
+    if info.id.as_str().trim().is_empty() {
+        anyhow::bail!("tank id must not be empty");
+    }
+    let listing = TankListing {
+        info: info.clone(),
+        busy: false,
+    };
+    tanks()
+        .insert(info.id.clone(), TankEntry { addr, info })
+        .map_err(|(tank_id, _)| {
+            anyhow::anyhow!("tank id {} is already online", tank_id.as_str())
+        })?;
+    broadcast_to_operators(UserMessage::TankPresence(TankPresence::Online(listing)));
+    Ok(())
 }
 
 pub fn remove_tank(addr: &TankId) {
-    tanks().remove(addr);
+    if tanks().remove(addr).is_some() {
+        broadcast_to_operators(UserMessage::TankPresence(TankPresence::Offline(
+            addr.clone(),
+        )));
+    }
+    if let Some((tank_id, session)) = sessions().remove(addr) {
+        for user_id in session.controller.iter().chain(&session.spectators) {
+            let msg = SignalEnum::UserResponse(UserMessage::SessionClosed(
+                tank_id.clone(),
+                "tank disconnected".to_owned(),
+            ));
+            let _ = send_message_to_operator(user_id, msg);
+        }
+    }
 }
 
-pub fn get_tank_list() -> Vec<TankId> {
+pub fn get_tank_list() -> Vec<TankListing> {
     let mut result = vec![];
-    tanks().scan(|k, _| {
-        result.push(k.to_owned());
+    tanks().scan(|k, entry| {
+        let busy = sessions()
+            .read(k, |_, session| session.controller.is_some())
+            .unwrap_or(false);
+        result.push(TankListing {
+            info: entry.info.clone(),
+            busy,
+        });
     });
 
     result
 }
 
+/// Adds the operator to the tank's session as a spectator, unless they are in it already.
+pub fn join_session(tank_id: &TankId, user_id: &UserId) -> anyhow::Result<()> {
+    if !tanks().contains(tank_id) {
+        anyhow::bail!("tank {} is not online", tank_id.as_str());
+    }
+    let session = {
+        let mut session = sessions().entry(tank_id.clone()).or_default();
+        if session.controller.as_ref() == Some(user_id) || session.spectators.contains(user_id) {
+            return Ok(());
+        }
+        let size = session.spectators.len() + usize::from(session.controller.is_some());
+        if size >= limits().max_session_size {
+            anyhow::bail!("{} already has {} operators", tank_id.as_str(), size);
+        }
+        session.spectators.push(user_id.clone());
+        session.clone()
+    };
+    broadcast_session(tank_id, &session, false);
+    Ok(())
+}
+
+/// Hands the control lease to the operator if nobody holds it, or regardless with `force`.
+/// Whoever held it before stays in the session as a spectator.
+pub fn take_control(tank_id: &TankId, user_id: &UserId, force: bool) -> anyhow::Result<()> {
+    let session = sessions()
+        .update(tank_id, |_, session| {
+            if session.controller.as_ref() == Some(user_id) {
+                return Ok(None);
+            }
+            if !session.spectators.contains(user_id) {
+                anyhow::bail!("join the session of {} first", tank_id.as_str());
+            }
+            match session.controller.take() {
+                Some(controller) if !force => {
+                    let reason = format!("{} is in control", controller.as_str());
+                    session.controller = Some(controller);
+                    anyhow::bail!(reason);
+                }
+                Some(controller) => session.spectators.push(controller),
+                None => {}
+            }
+            session.spectators.retain(|id| id != user_id);
+            session.controller = Some(user_id.clone());
+            Ok(Some(session.clone()))
+        })
+        .ok_or_else(|| anyhow::anyhow!("join the session of {} first", tank_id.as_str()))??;
+    if let Some(session) = session {
+        broadcast_session(tank_id, &session, true);
+    }
+    Ok(())
+}
+
+/// Gives up the control lease, the operator keeps watching as a spectator.
+pub fn release_control(tank_id: &TankId, user_id: &UserId) -> anyhow::Result<()> {
+    let session = sessions()
+        .update(tank_id, |_, session| {
+            if session.controller.as_ref() != Some(user_id) {
+                return None;
+            }
+            session.controller = None;
+            session.spectators.push(user_id.clone());
+            Some(session.clone())
+        })
+        .flatten()
+        .ok_or_else(|| anyhow::anyhow!("not in control of {}", tank_id.as_str()))?;
+    broadcast_session(tank_id, &session, true);
+    Ok(())
+}
+
+/// Drops a disconnected operator from every session, releasing any lease they held,
+/// and tells those tanks to close the operator's peer connection.
+fn leave_sessions(user_id: &UserId) {
+    let mut changed = vec![];
+    sessions().scan(|tank_id, _| changed.push(tank_id.clone()));
+    for tank_id in changed {
+        let update = sessions().update(&tank_id, |_, session| {
+            let was_controller = session.controller.as_ref() == Some(user_id);
+            if was_controller {
+                session.controller = None;
+            }
+            let spectators = session.spectators.len();
+            session.spectators.retain(|id| id != user_id);
+            (was_controller || spectators != session.spectators.len())
+                .then(|| (session.clone(), was_controller))
+        });
+        if let Some(Some((session, controller_changed))) = update {
+            let msg = SignalEnum::TankMessage(TankMessage::OperatorDisconnected(user_id.clone()));
+            let _ = send_message_to_tank(&tank_id, msg);
+            broadcast_session(&tank_id, &session, controller_changed);
+        }
+    }
+}
+
+/// Tells everyone in the session about its new state, and the tank about a new controller.
+fn broadcast_session(tank_id: &TankId, session: &SessionInfo, controller_changed: bool) {
+    debug!("session of {} is now {:?}", tank_id.as_str(), session);
+    for user_id in session.controller.iter().chain(&session.spectators) {
+        let msg =
+            SignalEnum::UserResponse(UserMessage::SessionUpdate(tank_id.clone(), session.clone()));
+        if let Err(e) = send_message_to_operator(user_id, msg) {
+            warn!(
+                "could not send session update to {}: {}",
+                user_id.as_str(),
+                e
+            );
+        }
+    }
+    if controller_changed {
+        let msg =
+            SignalEnum::TankMessage(TankMessage::ControllerChanged(session.controller.clone()));
+        if let Err(e) = send_message_to_tank(tank_id, msg) {
+            warn!("could not send controller to {}: {}", tank_id.as_str(), e);
+        }
+        broadcast_to_operators(UserMessage::TankPresence(TankPresence::Busy(
+            tank_id.clone(),
+            session.controller.is_some(),
+        )));
+    }
+}
+
+/// Sends the message to every logged-in operator.
+fn broadcast_to_operators(message: UserMessage) {
+    let mut addrs = vec![];
+    users().scan(|_, entry| addrs.push(entry.addr));
+    for addr in addrs {
+        if let Err(e) = send(&addr, SignalEnum::UserResponse(message.clone())) {
+            warn!("could not send {:?} to {}: {}", message, addr, e);
+        }
+    }
+}
+
 pub fn send_message_to_tank(tank_id: &TankId, message: SignalEnum) -> anyhow::Result<()> {
     if let Some(entry) = tanks().get(tank_id) {
-        let addr: &SocketAddr = &entry;
-        send(addr, message)?;
+        send(&entry.addr, message)?;
     };
     Ok(())
 }
 
 pub fn send_message_to_operator(operator: &UserId, message: SignalEnum) -> anyhow::Result<()> {
-    if let Some(entry) = users().get(operator) {
-        let addr: &SocketAddr = &entry;
-        send(addr, message)?;
+    if let Some(addr) = users().read(operator, |_, entry| entry.addr) {
+        send(&addr, message)?;
     }
     Ok(())
 }
 
+/// Queues a websocket ping, the answer shows up as a pong on the peer's stream.
+pub fn ping(addr: &SocketAddr) -> anyhow::Result<()> {
+    let sender = peers()
+        .read(addr, |_, tx| tx.clone())
+        .ok_or_else(|| anyhow::anyhow!("{} is not connected", addr))?;
+    sender.unbounded_send(Message::Ping(vec![]))?;
+    Ok(())
+}
+
 pub fn send(addr: &SocketAddr, message: SignalEnum) -> Result<(), anyhow::Error> {
     let sender = match peers().get(addr) {
         Some(x) => x,
@@ -100,3 +341,263 @@ pub fn send(addr: &SocketAddr, message: SignalEnum) -> Result<(), anyhow::Error>
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+    use std::net::{Ipv4Addr, SocketAddrV4};
+    use std::sync::atomic::{AtomicU16, Ordering};
+
+    use futures_channel::mpsc::{unbounded, UnboundedReceiver};
+
+    use super::*;
+
+    /// Every test gets its own tank and operators, the state is shared between them.
+    fn next_addr() -> SocketAddr {
+        static PORT: AtomicU16 = AtomicU16::new(1);
+        SocketAddrV4::new(Ipv4Addr::LOCALHOST, PORT.fetch_add(1, Ordering::Relaxed)).into()
+    }
+
+    fn connect() -> (SocketAddr, UnboundedReceiver<Message>) {
+        let addr = next_addr();
+        let (tx, rx) = unbounded();
+        insert_peer(addr, tx);
+        (addr, rx)
+    }
+
+    fn tank(name: &str) -> (TankId, UnboundedReceiver<Message>) {
+        let (addr, rx) = connect();
+        let id = TankId::new(name.to_owned());
+        let info = TankInfo {
+            id: id.clone(),
+            name: name.to_owned(),
+            hardware: BTreeMap::new(),
+        };
+        insert_tank(addr, info).unwrap();
+        (id, rx)
+    }
+
+    fn operator(tank_id: &TankId, name: &str) -> (UserId, UnboundedReceiver<Message>) {
+        let (addr, rx) = connect();
+        let user_id = UserId::new(format!("{}-{}", tank_id.as_str(), name));
+        insert_user(addr, user_id.clone(), Role::Operator).unwrap();
+        join_session(tank_id, &user_id).unwrap();
+        (user_id, rx)
+    }
+
+    fn received(rx: &mut UnboundedReceiver<Message>) -> Vec<SignalEnum> {
+        std::iter::from_fn(|| rx.try_recv().ok())
+            .map(|message| serde_json::from_str(message.to_text().unwrap()).unwrap())
+            .collect()
+    }
+
+    /// The controllers the tank was told about, in order.
+    fn controllers(rx: &mut UnboundedReceiver<Message>) -> Vec<Option<UserId>> {
+        received(rx)
+            .into_iter()
+            .filter_map(|signal| match signal {
+                SignalEnum::TankMessage(TankMessage::ControllerChanged(controller)) => {
+                    Some(controller)
+                }
+                _ => None,
+            })
+            .collect()
+    }
+
+    /// What the operator heard about the tank coming, going and being taken.
+    fn presence(rx: &mut UnboundedReceiver<Message>, tank_id: &TankId) -> Vec<TankPresence> {
+        received(rx)
+            .into_iter()
+            .filter_map(|signal| match signal {
+                SignalEnum::UserResponse(UserMessage::TankPresence(presence)) => Some(presence),
+                _ => None,
+            })
+            .filter(|presence| match presence {
+                TankPresence::Online(listing) => listing.info.id == *tank_id,
+                TankPresence::Offline(id) | TankPresence::Busy(id, _) => id == tank_id,
+            })
+            .collect()
+    }
+
+    fn session(tank_id: &TankId) -> SessionInfo {
+        sessions().read(tank_id, |_, s| s.clone()).unwrap()
+    }
+
+    #[test]
+    fn presence_reaches_every_operator() {
+        let (addr, mut watcher_rx) = connect();
+        let watcher = UserId::new("presence-watcher".to_owned());
+        insert_user(addr, watcher, Role::Operator).unwrap();
+
+        let (tank_id, _tank_rx) = tank("presence");
+        let info = |id: &str| TankInfo {
+            id: TankId::new(id.to_owned()),
+            name: "impostor".to_owned(),
+            hardware: BTreeMap::new(),
+        };
+        let (addr, _rx) = connect();
+        let err = insert_tank(addr, info("presence")).unwrap_err();
+        assert_eq!(err.to_string(), "tank id presence is already online");
+        let err = insert_tank(addr, info(" ")).unwrap_err();
+        assert_eq!(err.to_string(), "tank id must not be empty");
+        // the tank that was there first keeps its id
+        let listing = tanks()
+            .read(&tank_id, |_, entry| entry.info.clone())
+            .unwrap();
+        assert_eq!(listing.name, "presence");
+
+        let (alice, _alice_rx) = operator(&tank_id, "alice");
+        take_control(&tank_id, &alice, false).unwrap();
+        release_control(&tank_id, &alice).unwrap();
+        remove_tank(&tank_id);
+        assert_eq!(
+            presence(&mut watcher_rx, &tank_id),
+            vec![
+                TankPresence::Online(TankListing {
+                    info: listing,
+                    busy: false
+                }),
+                TankPresence::Busy(tank_id.clone(), true),
+                TankPresence::Busy(tank_id.clone(), false),
+                TankPresence::Offline(tank_id),
+            ]
+        );
+    }
+
+    #[test]
+    fn gone_tanks_close_their_session() {
+        let (tank_id, _tank_rx) = tank("gone");
+        let (alice, mut alice_rx) = operator(&tank_id, "alice");
+        let (_bob, mut bob_rx) = operator(&tank_id, "bob");
+        take_control(&tank_id, &alice, false).unwrap();
+
+        remove_tank(&tank_id);
+        for rx in [&mut alice_rx, &mut bob_rx] {
+            assert!(received(rx).iter().any(|signal| matches!(
+                signal,
+                SignalEnum::UserResponse(UserMessage::SessionClosed(id, reason))
+                    if *id == tank_id && reason == "tank disconnected"
+            )));
+        }
+        assert!(!sessions().contains(&tank_id));
+        assert!(join_session(&tank_id, &alice).is_err());
+    }
+
+    #[test]
+    fn connections_count_until_dropped() {
+        let first = open_connection(2).unwrap();
+        let second = open_connection(2).unwrap();
+        assert!(open_connection(2).is_none());
+        assert_eq!(connection_count(), 2);
+        drop(first);
+        let third = open_connection(2).unwrap();
+        assert!(open_connection(2).is_none());
+        drop((second, third));
+        assert_eq!(connection_count(), 0);
+    }
+
+    #[test]
+    fn lease_handover() {
+        let (tank_id, mut tank_rx) = tank("handover");
+        let (alice, _alice_rx) = operator(&tank_id, "alice");
+        let (bob, _bob_rx) = operator(&tank_id, "bob");
+
+        take_control(&tank_id, &alice, false).unwrap();
+        assert_eq!(session(&tank_id).controller, Some(alice.clone()));
+        assert_eq!(session(&tank_id).spectators, vec![bob.clone()]);
+        // asking again changes nothing
+        take_control(&tank_id, &alice, false).unwrap();
+
+        let err = take_control(&tank_id, &bob, false).unwrap_err();
+        assert_eq!(err.to_string(), format!("{} is in control", alice.as_str()));
+        assert_eq!(session(&tank_id).controller, Some(alice.clone()));
+        assert!(release_control(&tank_id, &bob).is_err());
+
+        release_control(&tank_id, &alice).unwrap();
+        assert_eq!(session(&tank_id).controller, None);
+        assert!(release_control(&tank_id, &alice).is_err());
+
+        take_control(&tank_id, &bob, false).unwrap();
+        let session = session(&tank_id);
+        assert_eq!(session.controller, Some(bob.clone()));
+        assert_eq!(session.spectators, vec![alice.clone()]);
+        assert_eq!(
+            controllers(&mut tank_rx),
+            vec![Some(alice), None, Some(bob)]
+        );
+    }
+
+    #[test]
+    fn forced_takeover() {
+        let (tank_id, mut tank_rx) = tank("takeover");
+        let (alice, _alice_rx) = operator(&tank_id, "alice");
+        let (bob, mut bob_rx) = operator(&tank_id, "bob");
+        take_control(&tank_id, &alice, false).unwrap();
+        received(&mut bob_rx);
+
+        take_control(&tank_id, &bob, true).unwrap();
+        let session = session(&tank_id);
+        assert_eq!(session.controller, Some(bob.clone()));
+        // whoever held it keeps watching
+        assert_eq!(session.spectators, vec![alice.clone()]);
+        assert_eq!(controllers(&mut tank_rx), vec![Some(alice), Some(bob)]);
+        assert!(received(&mut bob_rx).iter().any(|signal| matches!(
+            signal,
+            SignalEnum::UserResponse(UserMessage::SessionUpdate(_, update)) if *update == session
+        )));
+    }
+
+    #[test]
+    fn control_needs_the_session() {
+        let (tank_id, _tank_rx) = tank("outsider");
+        let (addr, _rx) = connect();
+        let outsider = UserId::new("outsider-carol".to_owned());
+        insert_user(addr, outsider.clone(), Role::Admin).unwrap();
+        assert!(take_control(&tank_id, &outsider, true).is_err());
+
+        let (alice, _alice_rx) = operator(&tank_id, "alice");
+        take_control(&tank_id, &alice, false).unwrap();
+        assert!(take_control(&tank_id, &outsider, true).is_err());
+        assert_eq!(session(&tank_id).controller, Some(alice));
+
+        let unknown = TankId::new("outsider-unknown".to_owned());
+        assert!(take_control(&unknown, &outsider, false).is_err());
+        assert!(release_control(&unknown, &outsider).is_err());
+    }
+
+    #[test]
+    fn leaving_releases_the_lease() {
+        let (tank_id, mut tank_rx) = tank("leaving");
+        let (alice, _alice_rx) = operator(&tank_id, "alice");
+        let (bob, _bob_rx) = operator(&tank_id, "bob");
+        let (carol, _carol_rx) = operator(&tank_id, "carol");
+        take_control(&tank_id, &alice, false).unwrap();
+        received(&mut tank_rx);
+
+        // a spectator leaving doesn't touch the lease
+        remove_user(&carol);
+        let signals = received(&mut tank_rx);
+        assert!(matches!(
+            signals.as_slice(),
+            [SignalEnum::TankMessage(TankMessage::OperatorDisconnected(id))] if *id == carol
+        ));
+        assert_eq!(session(&tank_id).controller, Some(alice.clone()));
+
+        remove_user(&alice);
+        let session = session(&tank_id);
+        assert_eq!(session.controller, None);
+        assert_eq!(session.spectators, vec![bob.clone()]);
+        let signals = received(&mut tank_rx);
+        assert!(signals.iter().any(|signal| matches!(
+            signal,
+            SignalEnum::TankMessage(TankMessage::OperatorDisconnected(id)) if *id == alice
+        )));
+        assert!(signals.iter().any(|signal| matches!(
+            signal,
+            SignalEnum::TankMessage(TankMessage::ControllerChanged(None))
+        )));
+
+        // the lease is free for the next one
+        take_control(&tank_id, &bob, false).unwrap();
+    }
+}
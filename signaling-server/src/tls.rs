@@ -0,0 +1,33 @@
+use std::fs::File;
+use std::io::BufReader;
+use std::path::Path;
+use std::sync::Arc;
+
+use anyhow::Context;
+use tokio_rustls::rustls::ServerConfig;
+pub use tokio_rustls::TlsAcceptor;
+
+/// Builds the acceptor that terminates `wss://` with the PEM certificate chain and private key.
+pub fn acceptor(cert_file: &Path, key_file: &Path) -> anyhow::Result<TlsAcceptor> {
+    let certs = rustls_pemfile::certs(&mut BufReader::new(
+        File::open(cert_file)
+            .with_context(|| format!("can't open certificate {}", cert_file.display()))?,
+    ))
+    .collect::<Result<Vec<_>, _>>()
+    .with_context(|| format!("invalid certificate {}", cert_file.display()))?;
+    if certs.is_empty() {
+        anyhow::bail!("no certificate in {}", cert_file.display());
+    }
+    let key = rustls_pemfile::private_key(&mut BufReader::new(
+        File::open(key_file)
+            .with_context(|| format!("can't open private key {}", key_file.display()))?,
+    ))
+    .with_context(|| format!("invalid private key {}", key_file.display()))?
+    .ok_or_else(|| anyhow::anyhow!("no private key in {}", key_file.display()))?;
+
+    let config = ServerConfig::builder()
+        .with_no_client_auth()
+        .with_single_cert(certs, key)
+        .context("certificate and private key don't match")?;
+    Ok(TlsAcceptor::from(Arc::new(config)))
+}
@@ -0,0 +1,107 @@
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use base64::{engine::general_purpose::STANDARD, Engine};
+use hmac::{Hmac, Mac};
+use protocol::IceServer;
+use sha1::Sha1;
+
+use crate::config::{IceConfig, TurnConfig};
+
+type HmacSha1 = Hmac<Sha1>;
+
+/// The configured servers plus, with TURN set up, a TURN entry with fresh credentials for `user`.
+pub fn ice_servers(ice: &IceConfig, user: &str) -> Vec<IceServer> {
+    let mut servers = ice.servers.clone();
+    if let Some(turn) = &ice.turn {
+        servers.push(credentials(turn, user, SystemTime::now()));
+    }
+    servers
+}
+
+/// Mints credentials the way coturn's `use-auth-secret` checks them: the username is
+/// `<expiry unix seconds>:<user>` and the credential the base64 HMAC-SHA1 of the username
+/// keyed with the shared secret, so the TURN server needs no list of users.
+pub fn credentials(turn: &TurnConfig, user: &str, now: SystemTime) -> IceServer {
+    let expiry = (now + Duration::from_secs(turn.ttl_secs))
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0);
+    let username = format!("{}:{}", expiry, user);
+    let mut mac =
+        HmacSha1::new_from_slice(turn.secret.as_bytes()).expect("hmac takes keys of any size");
+    mac.update(username.as_bytes());
+    IceServer {
+        urls: turn.urls.clone(),
+        username: Some(username),
+        credential: Some(STANDARD.encode(mac.finalize().into_bytes())),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const NOW: u64 = 1_700_000_000;
+
+    fn turn(ttl_secs: u64) -> TurnConfig {
+        TurnConfig {
+            urls: vec!["turn:turn.example.com:3478".to_owned()],
+            secret: "coturn secret".to_owned(),
+            ttl_secs,
+        }
+    }
+
+    fn at(secs: u64) -> SystemTime {
+        UNIX_EPOCH + Duration::from_secs(secs)
+    }
+
+    #[test]
+    fn coturn_credentials() {
+        // worked out independently, base64 of HMAC-SHA1("coturn secret", username)
+        let turn_for_a_day = TurnConfig {
+            ttl_secs: TurnConfig::default().ttl_secs,
+            ..turn(0)
+        };
+        let server = credentials(&turn_for_a_day, "alice", at(NOW));
+        assert_eq!(server.username.as_deref(), Some("1700086400:alice"));
+        assert_eq!(
+            server.credential.as_deref(),
+            Some("5fOwWyH83hIGgyAzpfZTVvireSI=")
+        );
+        let server = credentials(&turn(60), "tank-1", at(NOW));
+        assert_eq!(
+            server,
+            IceServer {
+                urls: vec!["turn:turn.example.com:3478".to_owned()],
+                username: Some("1700000060:tank-1".to_owned()),
+                credential: Some("wLlpjTasi1xPNyG8YHXreaYWz6g=".to_owned()),
+            }
+        );
+    }
+
+    #[test]
+    fn credentials_expire_after_the_ttl() {
+        let expiry = |ttl_secs, now| {
+            let server = credentials(&turn(ttl_secs), "alice", at(now));
+            let username = server.username.unwrap();
+            username.split_once(':').unwrap().0.parse::<u64>().unwrap()
+        };
+        assert_eq!(expiry(60, NOW), NOW + 60);
+        assert_eq!(expiry(3600, NOW), NOW + 3600);
+        assert_eq!(expiry(60, NOW + 1), NOW + 61);
+    }
+
+    #[test]
+    fn turn_only_when_configured() {
+        let mut ice = IceConfig::default();
+        assert_eq!(ice_servers(&ice, "alice"), ice.servers);
+
+        ice.turn = Some(turn(60));
+        let servers = ice_servers(&ice, "alice");
+        assert_eq!(servers.len(), 2);
+        assert_eq!(servers[0], ice.servers[0]);
+        assert_eq!(servers[1].urls, vec!["turn:turn.example.com:3478"]);
+        assert!(servers[1].username.as_deref().unwrap().ends_with(":alice"));
+        assert!(servers[1].credential.is_some());
+    }
+}
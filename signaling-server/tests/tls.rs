@@ -0,0 +1,114 @@
+//! A tank connecting to a `wss://` signaling server with a self-signed certificate.
+
+use std::path::{Path, PathBuf};
+
+use futures_util::StreamExt;
+use tokio::net::TcpListener;
+use tokio_tungstenite::{connect_async_tls_with_config, Connector};
+
+#[path = "../src/tls.rs"]
+mod server_tls;
+
+#[path = "../../camera-service/src/tls.rs"]
+mod camera_tls;
+
+/// What camera-service's `tls` module expects from its crate.
+mod prelude {
+    pub use anyhow::Result;
+    pub use std::sync::Arc;
+}
+
+struct Pem {
+    dir: PathBuf,
+}
+
+impl Pem {
+    /// A self-signed certificate for `localhost` and its key, written to a fresh directory.
+    fn self_signed(name: &str) -> Self {
+        let dir = std::env::temp_dir().join(format!("tls-test-{}-{name}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let certified = rcgen::generate_simple_self_signed(vec!["localhost".to_owned()]).unwrap();
+        std::fs::write(dir.join("cert.pem"), certified.cert.pem()).unwrap();
+        std::fs::write(dir.join("key.pem"), certified.key_pair.serialize_pem()).unwrap();
+        Self { dir }
+    }
+
+    fn cert(&self) -> PathBuf {
+        self.dir.join("cert.pem")
+    }
+
+    fn key(&self) -> PathBuf {
+        self.dir.join("key.pem")
+    }
+}
+
+impl Drop for Pem {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.dir);
+    }
+}
+
+/// Serves one websocket over TLS and reports whether a client got through the handshake.
+async fn connect(cert: &Path, key: &Path, connector: Option<Connector>) -> anyhow::Result<()> {
+    let acceptor = server_tls::acceptor(cert, key)?;
+    let listener = TcpListener::bind("127.0.0.1:0").await?;
+    let port = listener.local_addr()?.port();
+    let server = tokio::spawn(async move {
+        let (stream, _) = listener.accept().await?;
+        let stream = acceptor.accept(stream).await?;
+        let mut ws = tokio_tungstenite::accept_async(stream).await?;
+        // wait for the client to go away
+        while ws.next().await.is_some() {}
+        anyhow::Ok(())
+    });
+
+    let url = format!("wss://localhost:{port}");
+    let client = connect_async_tls_with_config(url, None, false, connector).await;
+    let (mut ws, _) = match client {
+        Ok(connected) => connected,
+        Err(e) => {
+            server.abort();
+            return Err(e.into());
+        }
+    };
+    ws.close(None).await?;
+    server.await?
+}
+
+#[tokio::test]
+async fn connector_with_the_ca_bundle() {
+    let server = Pem::self_signed("trusted");
+    let connector = camera_tls::connector(Some(&server.cert())).unwrap();
+    assert!(connector.is_some());
+    connect(&server.cert(), &server.key(), connector)
+        .await
+        .unwrap();
+}
+
+#[tokio::test]
+async fn webpki_roots_reject_a_self_signed_server() {
+    let server = Pem::self_signed("untrusted");
+    let connector = camera_tls::connector(None).unwrap();
+    assert!(connector.is_none());
+    assert!(connect(&server.cert(), &server.key(), connector)
+        .await
+        .is_err());
+}
+
+#[tokio::test]
+async fn connector_with_another_ca_bundle() {
+    let server = Pem::self_signed("server");
+    let other = Pem::self_signed("other");
+    let connector = camera_tls::connector(Some(&other.cert())).unwrap();
+    assert!(connect(&server.cert(), &server.key(), connector)
+        .await
+        .is_err());
+}
+
+#[test]
+fn bundle_without_certificates() {
+    let pem = Pem::self_signed("empty");
+    // a key is no certificate
+    assert!(camera_tls::connector(Some(&pem.key())).is_err());
+    assert!(camera_tls::connector(Some(&pem.dir.join("missing.pem"))).is_err());
+}